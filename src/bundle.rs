@@ -0,0 +1,57 @@
+/* ===============================================================================
+Restaurant menu bot.
+Combo sets of several items with choice. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use crate::node::Node;
+
+// Part of the set, the customer chooses one of the children of the source node
+pub struct Slot {
+   pub source: Node, // with enabled children
+   pub chosen: Option<Node>,
+}
+
+pub struct Bundle {
+   pub percent: usize, // discount from the sum of the chosen items, zero for the own price of the bundle node
+   pub slots: Vec<Slot>,
+}
+
+impl Bundle {
+   pub fn is_complete(&self) -> bool {
+      self.slots.iter().all(|slot| slot.chosen.is_some())
+   }
+
+   // Price for the customer with the chosen items
+   pub fn price(&self, node: &Node) -> usize {
+      if self.percent == 0 {
+         return node.actual_price();
+      }
+
+      let sum: usize = self.slots.iter()
+      .filter_map(|slot| slot.chosen.as_ref())
+      .map(|item| item.actual_price())
+      .sum();
+      sum - sum * self.percent / 100
+   }
+
+   // Like "Soups: Borscht, Drinks: Tea", the same is kept with the ticket
+   pub fn components_text(&self) -> String {
+      self.slots.iter()
+      .filter_map(|slot| slot.chosen.as_ref().map(|item| format!("{}: {}", slot.source.title, item.title)))
+      .collect::<Vec<_>>()
+      .join(", ")
+   }
+
+   // Titles of the slots without choice
+   pub fn missing_text(&self) -> String {
+      self.slots.iter()
+      .filter(|slot| slot.chosen.is_none())
+      .map(|slot| slot.source.title.as_str())
+      .collect::<Vec<_>>()
+      .join(", ")
+   }
+}
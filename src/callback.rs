@@ -17,6 +17,11 @@ use crate::states::*;
 use crate::database as db;
 use crate::navigation;
 use crate::registration;
+use crate::chat;
+use crate::queue;
+use crate::review;
+use crate::tip;
+use crate::payment;
 use crate::loc::*;
 
 #[derive(AsRefStr, EnumString, )]
@@ -35,12 +40,66 @@ pub enum Command {
    DecAmountNow(i32), // remove 1pcs of node from cart and return to PassNow mode
    #[strum(to_string = "tic")]
    TicketMake(i32), // start ordering through the bot
+   #[strum(to_string = "tsl")]
+   TicketSlots(i32), // show the time to choose for the order for later
+   #[strum(to_string = "tma")]
+   TicketMakeAt(i32, i32), // ordering for later (node id, minutes since the epoch)
    #[strum(to_string = "tca")]
    TicketCancel(i32), // cancel ticket
    #[strum(to_string = "tne")]
    TicketNext(i32), // next stage for ticket
    #[strum(to_string = "tco")]
    TicketConfirm(i32), // finish ticket
+   #[strum(to_string = "ted")]
+   TicketEdit(i32), // owner starts changing the items of ticket
+   #[strum(to_string = "tid")]
+   TicketItemDec(i32, i32), // propose 1pcs less of the item (ticket id, line id)
+   #[strum(to_string = "tii")]
+   TicketItemInc(i32, i32), // propose 1pcs more of the item
+   #[strum(to_string = "tir")]
+   TicketItemRemove(i32, i32), // propose to remove the item
+   #[strum(to_string = "tis")]
+   TicketItemSubstitute(i32, i32), // select a replacement for the item
+   #[strum(to_string = "tsw")]
+   TicketSubstituteWith(i32, i32, i32), // propose a replacement (ticket id, old line id, new node id)
+   #[strum(to_string = "tdi")]
+   TicketDiscard(i32), // owner forgets the changes
+   #[strum(to_string = "tpr")]
+   TicketPropose(i32), // owner sends the changes to customer
+   #[strum(to_string = "tac")]
+   TicketAccept(i32), // customer agrees with the changes
+   #[strum(to_string = "tet")]
+   TicketEta(i32, i32), // owner sets the estimated time (ticket id, minutes from now)
+   #[strum(to_string = "tch")]
+   TicketChat(i32), // start writing to the other side of the ticket
+   #[strum(to_string = "tta")]
+   TicketTake(i32), // courier takes the delivery
+   #[strum(to_string = "qne")]
+   QueueNext(i32), // next stage for ticket from the owner's queue
+   #[strum(to_string = "qca")]
+   QueueCancel(i32), // cancel ticket from the owner's queue
+   #[strum(to_string = "qre")]
+   QueueRefresh, // redraw the owner's queue
+   #[strum(to_string = "tra")]
+   TicketRate(i32, i32), // customer rates the finished ticket (ticket id, stars)
+   #[strum(to_string = "trv")]
+   TicketReview(i32), // customer starts writing a review
+   #[strum(to_string = "tti")]
+   TicketTip(i32, i32), // customer leaves a tip (ticket id, amount)
+   #[strum(to_string = "tte")]
+   TicketTipEnter(i32), // customer starts entering the tip amount
+   #[strum(to_string = "nad")]
+   NextAddress(i32), // next address from the book for the order to the node
+   #[strum(to_string = "tpa")]
+   TicketPay(i32), // customer confirms the test payment
+   #[strum(to_string = "trf")]
+   TicketRefunded(i32), // owner or admin returned the money
+   #[strum(to_string = "qrf")]
+   QueueRefunded(i32), // the same from the owner's queue
+   #[strum(to_string = "bch")]
+   BundleChoose(i32, i32), // customer chooses the component of the bundle and return to Pass mode (bundle id, node id)
+   #[strum(to_string = "bcn")]
+   BundleChooseNow(i32, i32), // the same and return to PassNow mode
    Unknown,
 }
 
@@ -52,6 +111,14 @@ impl Command {
       .unwrap_or_default()
       .parse().unwrap_or_default();
 
+      // Several arguments are separated by underscores
+      let args: Vec<i32> = s.get(3..)
+      .unwrap_or_default()
+      .split('_')
+      .map(|a| a.parse().unwrap_or_default())
+      .collect();
+      let arg_n = |n: usize| args.get(n).copied().unwrap_or_default();
+
       if cmd == Self::Pass(0).as_ref() {
          Command::Pass(arg)
       } else if cmd == Self::PassNow(0).as_ref() {
@@ -66,12 +133,66 @@ impl Command {
          Command::DecAmountNow(arg)
       } else if cmd == Self::TicketMake(0).as_ref() {
          Command::TicketMake(arg)
+      } else if cmd == Self::TicketSlots(0).as_ref() {
+         Command::TicketSlots(arg)
+      } else if cmd == Self::TicketMakeAt(0, 0).as_ref() {
+         Command::TicketMakeAt(arg_n(0), arg_n(1))
       } else if cmd == Self::TicketCancel(0).as_ref() {
          Command::TicketCancel(arg)
       } else if cmd == Self::TicketNext(0).as_ref() {
          Command::TicketNext(arg)
       } else if cmd == Self::TicketConfirm(0).as_ref() {
          Command::TicketConfirm(arg)
+      } else if cmd == Self::TicketEdit(0).as_ref() {
+         Command::TicketEdit(arg)
+      } else if cmd == Self::TicketItemDec(0, 0).as_ref() {
+         Command::TicketItemDec(arg_n(0), arg_n(1))
+      } else if cmd == Self::TicketItemInc(0, 0).as_ref() {
+         Command::TicketItemInc(arg_n(0), arg_n(1))
+      } else if cmd == Self::TicketItemRemove(0, 0).as_ref() {
+         Command::TicketItemRemove(arg_n(0), arg_n(1))
+      } else if cmd == Self::TicketItemSubstitute(0, 0).as_ref() {
+         Command::TicketItemSubstitute(arg_n(0), arg_n(1))
+      } else if cmd == Self::TicketSubstituteWith(0, 0, 0).as_ref() {
+         Command::TicketSubstituteWith(arg_n(0), arg_n(1), arg_n(2))
+      } else if cmd == Self::TicketDiscard(0).as_ref() {
+         Command::TicketDiscard(arg)
+      } else if cmd == Self::TicketPropose(0).as_ref() {
+         Command::TicketPropose(arg)
+      } else if cmd == Self::TicketAccept(0).as_ref() {
+         Command::TicketAccept(arg)
+      } else if cmd == Self::TicketEta(0, 0).as_ref() {
+         Command::TicketEta(arg_n(0), arg_n(1))
+      } else if cmd == Self::TicketChat(0).as_ref() {
+         Command::TicketChat(arg)
+      } else if cmd == Self::TicketTake(0).as_ref() {
+         Command::TicketTake(arg)
+      } else if cmd == Self::QueueNext(0).as_ref() {
+         Command::QueueNext(arg)
+      } else if cmd == Self::QueueCancel(0).as_ref() {
+         Command::QueueCancel(arg)
+      } else if cmd == Self::QueueRefresh.as_ref() {
+         Command::QueueRefresh
+      } else if cmd == Self::TicketRate(0, 0).as_ref() {
+         Command::TicketRate(arg_n(0), arg_n(1))
+      } else if cmd == Self::TicketReview(0).as_ref() {
+         Command::TicketReview(arg)
+      } else if cmd == Self::TicketTip(0, 0).as_ref() {
+         Command::TicketTip(arg_n(0), arg_n(1))
+      } else if cmd == Self::TicketTipEnter(0).as_ref() {
+         Command::TicketTipEnter(arg)
+      } else if cmd == Self::NextAddress(0).as_ref() {
+         Command::NextAddress(arg)
+      } else if cmd == Self::TicketPay(0).as_ref() {
+         Command::TicketPay(arg)
+      } else if cmd == Self::TicketRefunded(0).as_ref() {
+         Command::TicketRefunded(arg)
+      } else if cmd == Self::QueueRefunded(0).as_ref() {
+         Command::QueueRefunded(arg)
+      } else if cmd == Self::BundleChoose(0, 0).as_ref() {
+         Command::BundleChoose(arg_n(0), arg_n(1))
+      } else if cmd == Self::BundleChooseNow(0, 0).as_ref() {
+         Command::BundleChooseNow(arg_n(0), arg_n(1))
       } else {
          Command::Unknown
       }
@@ -82,16 +203,37 @@ impl Command {
          Self::TicketCancel(_) => loc(Key::CallbackCancel, tag, &[]),
          Self::TicketNext(_) => loc(Key::CallbackNext, tag, &[]),
          Self::TicketConfirm(_) => loc(Key::CallbackConfirm, tag, &[]),
+         Self::TicketEdit(_) => loc(Key::CallbackEdit, tag, &[]),
+         Self::TicketDiscard(_) => loc(Key::CallbackDiscard, tag, &[]),
+         Self::TicketPropose(_) => loc(Key::CallbackPropose, tag, &[]),
+         Self::TicketAccept(_) => loc(Key::CallbackAccept, tag, &[]),
+         Self::TicketChat(_) => loc(Key::CallbackChat3, tag, &[]),
+         Self::TicketTake(_) => loc(Key::CallbackTake, tag, &[]),
          _ => String::from("callback::button_caption unsupported command"),
       }
    }
 }
 
-pub async fn update(bot: Bot, q: CallbackQuery, tag: LocaleTag) -> HandlerResult {
+pub async fn update(bot: Bot, q: CallbackQuery, dialogue: MyDialogue, tag: LocaleTag) -> HandlerResult {
    async fn do_inc(bot: &Bot, q: CallbackQuery, node_id: i32, mode: WorkTime, tag: LocaleTag) -> Result<String, String> {
-      // Increment amount in database and reload node
+      // Increment amount in database with the current price and reload node
       let user_id = q.from.id.0;
-      db::orders_amount_inc(user_id, node_id).await?;
+      let node = db::node(db::LoadNode::EnabledIdNoChildren(node_id)).await?
+      .ok_or(format!("callback::do_inc node_id={} not found", node_id))?;
+
+      // The bundle needs the choice of all components
+      let price = match db::bundle(node_id, user_id).await? {
+         Some(bundle) if !bundle.is_complete() => {
+            // "Please choose first: {}"
+            return Ok(loc(Key::CallbackChoose, tag, &[&bundle.missing_text()]));
+         }
+         Some(bundle) => bundle.price(&node),
+         None => node.actual_price(),
+      };
+      if !db::orders_amount_inc(user_id, node_id, price).await? {
+         // "Sorry, no more left"
+         return Ok(loc(Key::CallbackSoldOut, tag, &[]));
+      }
       navigation::view(bot, q, node_id, mode, tag).await?;
       Ok(loc(Key::CallbackAdded, tag, &[]))
    }
@@ -104,6 +246,25 @@ pub async fn update(bot: Bot, q: CallbackQuery, tag: LocaleTag) -> HandlerResult
       Ok(loc(Key::CallbackRemoved, tag, &[]))
    }
 
+   async fn do_choose(bot: &Bot, q: CallbackQuery, bundle_id: i32, component_id: i32, mode: WorkTime, tag: LocaleTag) -> Result<String, String> {
+      let user_id = q.from.id.0;
+      if !db::bundle_choose(user_id, bundle_id, component_id).await? {
+         return Ok(format!("callback::do_choose bundle_id={} has no component_id={}", bundle_id, component_id));
+      }
+
+      // The bundle in the cart gets the new price
+      if db::orders_amount(user_id as i64, bundle_id).await? > 0 {
+         if let Some(node) = db::node(db::LoadNode::EnabledIdNoChildren(bundle_id)).await? {
+            if let Some((price, _)) = db::bundle_price(&node, user_id).await? {
+               db::order_update_price(user_id, bundle_id, price).await?;
+            }
+         }
+      }
+
+      navigation::view(bot, q, bundle_id, mode, tag).await?;
+      Ok(loc(Key::CallbackChosen, tag, &[]))
+   }
+
    let query_id = q.id.to_owned();
 
    // Parse and process commands by receiving a message to send back
@@ -122,10 +283,37 @@ pub async fn update(bot: Bot, q: CallbackQuery, tag: LocaleTag) -> HandlerResult
       Command::IncAmountNow(node_id) => do_inc(&bot, q, node_id, WorkTime::Now, tag).await?,
       Command::DecAmount(node_id) => do_dec(&bot, q, node_id, WorkTime::All, tag).await?,
       Command::DecAmountNow(node_id) => do_dec(&bot, q, node_id, WorkTime::All, tag).await?,
-      Command::TicketMake(node_id) => registration::make_ticket(&bot, q, node_id, tag).await?,
+      Command::TicketMake(node_id) => registration::make_ticket(&bot, q, node_id, None, tag).await?,
+      Command::TicketSlots(node_id) => registration::select_time(&bot, q, node_id, tag).await?,
+      Command::TicketMakeAt(node_id, minutes) => registration::make_ticket_at(&bot, q, node_id, minutes, tag).await?,
       Command::TicketCancel(node_id) => registration::cancel_ticket(&bot, q, node_id, tag).await?,
       Command::TicketNext(node_id) => registration::next_ticket(&bot, node_id, tag).await?,
       Command::TicketConfirm(node_id) => registration::confirm_ticket(&bot, node_id, tag).await?,
+      Command::TicketEdit(ticket_id) => registration::edit_ticket(&bot, q, ticket_id, tag).await?,
+      Command::TicketItemDec(ticket_id, item_id) => registration::propose_item(&bot, q, ticket_id, item_id, db::ItemProposal::Dec, tag).await?,
+      Command::TicketItemInc(ticket_id, item_id) => registration::propose_item(&bot, q, ticket_id, item_id, db::ItemProposal::Inc, tag).await?,
+      Command::TicketItemRemove(ticket_id, item_id) => registration::propose_item(&bot, q, ticket_id, item_id, db::ItemProposal::Remove, tag).await?,
+      Command::TicketItemSubstitute(ticket_id, item_id) => registration::select_substitute(&bot, q, ticket_id, item_id, tag).await?,
+      Command::TicketSubstituteWith(ticket_id, old_id, new_id) => registration::substitute_item(&bot, q, ticket_id, old_id, new_id, tag).await?,
+      Command::TicketDiscard(ticket_id) => registration::discard_changes(&bot, q, ticket_id, tag).await?,
+      Command::TicketPropose(ticket_id) => registration::propose_changes(&bot, q, ticket_id, tag).await?,
+      Command::TicketAccept(ticket_id) => registration::accept_changes(&bot, q, ticket_id, tag).await?,
+      Command::TicketEta(ticket_id, minutes) => registration::set_eta(&bot, q, ticket_id, minutes, tag).await?,
+      Command::TicketChat(ticket_id) => chat::enter(&bot, q, dialogue, ticket_id, tag).await?,
+      Command::TicketTake(ticket_id) => registration::take_delivery(&bot, q, ticket_id, tag).await?,
+      Command::QueueNext(ticket_id) => queue::next(&bot, q, ticket_id, tag).await?,
+      Command::QueueCancel(ticket_id) => queue::cancel(&bot, q, ticket_id, tag).await?,
+      Command::QueueRefresh => queue::refresh(&bot, &q, tag).await?,
+      Command::TicketRate(ticket_id, stars) => review::rate(&bot, q, ticket_id, stars, tag).await?,
+      Command::TicketReview(ticket_id) => review::enter(&bot, q, dialogue, ticket_id, tag).await?,
+      Command::TicketTip(ticket_id, amount) => tip::choose(&bot, q, ticket_id, amount, tag).await?,
+      Command::TicketTipEnter(ticket_id) => tip::enter(&bot, q, dialogue, ticket_id, tag).await?,
+      Command::NextAddress(node_id) => registration::next_address(&bot, q, node_id, tag).await?,
+      Command::TicketPay(ticket_id) => payment::fake_pay(&bot, q, ticket_id, tag).await?,
+      Command::TicketRefunded(ticket_id) => payment::refunded(&bot, q, ticket_id, tag).await?,
+      Command::QueueRefunded(ticket_id) => queue::refunded(&bot, q, ticket_id, tag).await?,
+      Command::BundleChoose(bundle_id, node_id) => do_choose(&bot, q, bundle_id, node_id, WorkTime::All, tag).await?,
+      Command::BundleChooseNow(bundle_id, node_id) => do_choose(&bot, q, bundle_id, node_id, WorkTime::Now, tag).await?,
       Command::Unknown => format!("callback::update unknowm command {}", input),
    };
 
@@ -7,7 +7,7 @@ http://www.gnu.org/licenses/gpl-3.0.html
 Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
 =============================================================================== */
 
-use teloxide::{prelude::*,
+use teloxide::{prelude::*, utils::html,
    types::{ReplyMarkup, KeyboardButton, KeyboardMarkup, 
       ParseMode, ButtonRequest, InlineKeyboardButton, InlineKeyboardMarkup,
    }
@@ -16,10 +16,12 @@ use teloxide::{prelude::*,
 use crate::states::*;
 use crate::database as db;
 use crate::customer::*;
+use crate::geo::Geo;
 use crate::environment as env;
 use crate::callback as cb;
 use crate::node;
 use crate::orders;
+use crate::promo::{Check, Promo};
 use crate::registration;
 use crate::general;
 use crate::loc::*;
@@ -36,11 +38,17 @@ enum Command {
    Exit, // return to start menu
    Edit(EditCmd),
    Delete(i32),
+   Points(i32), // switch spending of the loyalty points by owner node id
    Reload,
    Unknown,
 }
 
 const DEL: &str = "/del";
+const ADEL: &str = "/adel"; // remove from the address book
+const NOTE: &str = "/note";
+const COMMENT: &str = "/comm";
+const PROMO: &str = "/promo";
+const POINTS: &str = "/points";
 
 // Main commands
 #[derive(Copy, Clone)]
@@ -49,6 +57,9 @@ enum EditCmd {
    Contact,
    Address,
    Delivery,
+   Note(i32), // wish to the cart line by node id
+   Comment(i32), // comment to the whole order by owner node id
+   Promo(i32), // promo code for the order by owner node id
 }
 
 impl Command {
@@ -66,6 +77,14 @@ impl Command {
          if s.get(..4).unwrap_or_default() == DEL {
             let r_part = s.get(4..).unwrap_or_default();
             Self::Delete(r_part.parse().unwrap_or_default())
+         } else if let Some(r_part) = s.strip_prefix(NOTE) {
+            Self::Edit(EditCmd::Note(r_part.parse().unwrap_or_default()))
+         } else if let Some(r_part) = s.strip_prefix(COMMENT) {
+            Self::Edit(EditCmd::Comment(r_part.parse().unwrap_or_default()))
+         } else if let Some(r_part) = s.strip_prefix(PROMO) {
+            Self::Edit(EditCmd::Promo(r_part.parse().unwrap_or_default()))
+         } else if let Some(r_part) = s.strip_prefix(POINTS) {
+            Self::Points(r_part.parse().unwrap_or_default())
          } else {
             Self::Unknown
          }
@@ -91,20 +110,25 @@ pub async fn enter(bot: Bot, msg: Message, dialogue: MyDialogue, state: MainStat
 }
 
 
-async fn view(bot: Bot, msg: Message, state: CartState) -> HandlerResult {
+pub async fn view(bot: Bot, msg: Message, state: CartState) -> HandlerResult {
    let tag = state.prev_state.tag;
 
    // Start with info about user
    // "Your data, {}:\nContact for communication: {}\nDelivery method: {}"
-   let args: Args = &[&state.customer.name, &state.customer.contact, &state.customer.delivery_desc(tag)];
+   let args: Args = &[&state.customer.name, &state.customer.contact_desc(tag), &state.customer.delivery_desc(tag)];
    let info = loc(Key::CartView1, tag, args);
 
    // Load info about orders
    let user_id = state.prev_state.user_id;
    let orders = db::orders(user_id.0 as i64).await?;
 
+   // "\n⭐ Loyalty points: {}"
+   let info = if orders.points_balance > 0 {
+      info + loc(Key::CartView4, tag, &[&orders.points_balance]).as_str()
+   } else { info };
+
    // Announce
-   let cart_info = orders.cart_info();
+   let cart_info = orders.cart_info(&state.customer);
    let announce = if cart_info.orders_num == 0 {
       // "Cart is empty"
       loc(Key::CartView2, tag, &[])
@@ -121,13 +145,14 @@ async fn view(bot: Bot, msg: Message, state: CartState) -> HandlerResult {
    .await?;
 
    // Messages by owners
-   for owner in orders.data {
+   let address = checkout_address(user_id.0, &state.customer).await?;
+   for owner in &orders.data {
       let owner_id = owner.0.id;
-      let text = make_owner_text(&owner.0, &owner.1, tag);
+      let text = make_owner_text(owner.0, owner.1, &orders, &state.customer, tag);
 
       
       bot.send_message(msg.chat.id, text)
-      .reply_markup(order_markup(owner_id, tag))
+      .reply_markup(order_markup(owner_id, address.as_deref(), tag))
       .parse_mode(ParseMode::Html)
       .await?;
    }
@@ -167,6 +192,11 @@ pub async fn update(bot: Bot, msg: Message, dialogue: MyDialogue, state: CartSta
          view(bot, msg, state).await
       }
 
+      Command::Points(owner_id) => {
+         db::order_toggle_points(user_id, owner_id).await?;
+         view(bot, msg, state).await
+      }
+
       Command::Reload => view(bot, msg, state).await,
 
       Command::Unknown => {
@@ -181,7 +211,7 @@ pub async fn update(bot: Bot, msg: Message, dialogue: MyDialogue, state: CartSta
    }
 }
 
-pub fn make_owner_text(node: &node::Node, order: &orders::Order, tag: LocaleTag) -> String {
+pub fn make_owner_text(node: &node::Node, order: &orders::Order, orders: &orders::Orders, customer: &Customer, tag: LocaleTag) -> String {
 
    // Prepare info about owner
    let descr = if node.descr.len() <= 1 { String::default() } 
@@ -200,7 +230,7 @@ pub fn make_owner_text(node: &node::Node, order: &orders::Order, tag: LocaleTag)
    // Info about items
    let items = order.iter()
    .fold(String::from("\n"), |acc, item| {
-      let price = item.node.price;
+      let price = item.price;
       let amount = item.amount;
 
       // "{}\n{}: {} x {} pcs. = {}"
@@ -212,11 +242,84 @@ pub fn make_owner_text(node: &node::Node, order: &orders::Order, tag: LocaleTag)
       ];
       let text = loc(Key::CartMakeOwnerText4, tag, args);
 
-      // Add del command
-      format!("{} /del{}", text, item.node.id)
+      // Add del and note commands
+      let text = format!("{} {}{} ✏ {}{}", text, DEL, item.node.id, NOTE, item.node.id);
+      let text = match &item.components {
+         Some(components) => format!("{}\n({})", text, html::escape(components)),
+         None => text,
+      };
+      match &item.note {
+         Some(note) => format!("{}\n<i>{}</i>", text, html::escape(note)),
+         None => text,
+      }
    });
 
-   node.title.clone() + descr.as_str() + time.as_str() + items.as_str()
+   // Comment, promo code and loyalty points to the whole order, the commands go with the title
+   let title = format!("{} 💬 {}{} 🏷 {}{}", node.title, COMMENT, node.id, PROMO, node.id);
+   let title = if orders.points_balance > 0 { format!("{} ⭐ {}{}", title, POINTS, node.id) } else { title };
+   let comment = orders.comments.get(&node.id)
+   .map(|comment| loc(Key::CartMakeOwnerText11, tag, &[&html::escape(comment)])) // "\n💬 {}"
+   .unwrap_or_default();
+
+   let cost: usize = order.iter().map(|item| item.cost()).sum();
+   let promo = orders.promos.get(&node.id);
+   let discount = promo.map(|p| p.discount(cost)).unwrap_or_default();
+   let promo = promo
+   .map(|p| loc(Key::CartMakeOwnerText12, tag, &[&p.code, &env::price_with_unit(discount)])) // "\n🏷 Promo code {}: −{}"
+   .unwrap_or_default();
+
+   let points = orders.points(node.id);
+   let points_text = if points > 0 {
+      loc(Key::CartMakeOwnerText13, tag, &[&points]) // "\n⭐ Points: −{}"
+   } else { String::default() };
+
+   title + descr.as_str() + time.as_str() + items.as_str() + comment.as_str() + promo.as_str() + points_text.as_str() + delivery_text(node, cost, discount + points, customer, tag).as_str()
+}
+
+// Delivery conditions and the total cost with the discount
+fn delivery_text(node: &node::Node, cost: usize, discount: usize, customer: &Customer, tag: LocaleTag) -> String {
+   let terms = &node.delivery;
+   let geo = customer.geo.as_ref();
+   let total = cost - discount;
+   if !customer.is_courier() {
+      // "\nTotal: {}"
+      return if discount > 0 { loc(Key::CartMakeOwnerText9, tag, &[&env::price_with_unit(total)]) } else { String::default() };
+   }
+
+   if !terms.is_in_zone(geo) {
+      // "\nThe place does not deliver to your location, please choose pickup"
+      return loc(Key::CartMakeOwnerText10, tag, &[]);
+   }
+
+   let fee = terms.fee_for(total, geo);
+   let mut res = String::default();
+
+   if terms.fee > 0 || terms.km_fee > 0 {
+      if fee > 0 {
+         // "\nDelivery: {}"
+         res += &loc(Key::CartMakeOwnerText5, tag, &[&env::price_with_unit(fee)]);
+         if terms.free_from > 0 {
+            // "\nFree delivery for orders from {}"
+            res += &loc(Key::CartMakeOwnerText7, tag, &[&env::price_with_unit(terms.free_from)]);
+         }
+      } else if terms.free_from > 0 && total >= terms.free_from {
+         // "\nDelivery: free"
+         res += &loc(Key::CartMakeOwnerText6, tag, &[]);
+      }
+   }
+
+   if terms.fee > 0 || terms.km_fee > 0 || discount > 0 {
+      // "\nTotal: {}"
+      res += &loc(Key::CartMakeOwnerText9, tag, &[&env::price_with_unit(total + fee)]);
+   }
+
+   if !terms.is_enough(cost) {
+      // "\nMinimum order for delivery is {}, add {} more or choose pickup"
+      let args: Args = &[&env::price_with_unit(terms.min_order), &env::price_with_unit(terms.min_order - cost)];
+      res += &loc(Key::CartMakeOwnerText8, tag, args);
+   }
+
+   res
 }
 
 fn markup(tag: LocaleTag) -> ReplyMarkup {
@@ -236,21 +339,56 @@ fn markup(tag: LocaleTag) -> ReplyMarkup {
    kb_markup(keyboard)
 }
 
-fn order_markup(node_id: i32, tag: LocaleTag) -> InlineKeyboardMarkup {
+// Label of the current address to choose another one at checkout
+pub async fn checkout_address(user_id: u64, customer: &Customer) -> Result<Option<String>, String> {
+   if !customer.is_courier() {
+      return Ok(None);
+   }
+
+   let book = db::addresses(user_id).await?;
+   if book.len() < 2 {
+      return Ok(None);
+   }
+
+   let label = book.iter()
+   .find(|a| Some(a.id) == customer.address_id)
+   .map(|a| a.label.clone())
+   .unwrap_or(String::from("?"));
+   Ok(Some(label))
+}
+
+pub fn order_markup(node_id: i32, address: Option<&str>, tag: LocaleTag) -> InlineKeyboardMarkup {
    let button = InlineKeyboardButton::callback(
       // "Checkout via bot"
       loc(Key::CartOrderMarkup, tag, &[]), 
       format!("{}{}", cb::Command::TicketMake(0).as_ref(), node_id)
    );
-   InlineKeyboardMarkup::default()
-   .append_row(vec![button])
+   let later = InlineKeyboardButton::callback(
+      // "🕒 For later"
+      loc(Key::CartOrderMarkupLater, tag, &[]),
+      format!("{}{}", cb::Command::TicketSlots(0).as_ref(), node_id)
+   );
+   let markup = InlineKeyboardMarkup::default()
+   .append_row(vec![button, later]);
+
+   match address {
+      Some(label) => {
+         let button = InlineKeyboardButton::callback(
+            // "🏠 {}"
+            loc(Key::CartOrderMarkupAddress, tag, &[&label]),
+            format!("{}{}", cb::Command::NextAddress(0).as_ref(), node_id)
+         );
+         markup.append_row(vec![button])
+      }
+      None => markup,
+   }
 }
 // ============================================================================
 // [Fields editing mode]
 // ============================================================================
 #[derive(Clone)]
 pub struct CartStateEditing {
-   prev_state: CartState,
+   pub prev_state: CartState,
    cmd: EditCmd,
 }
 
@@ -265,26 +403,25 @@ async fn enter_edit(bot: Bot, msg: Message, state: CartStateEditing) -> HandlerR
       }
       EditCmd::Contact => 
       {
-         // "If you want to allow staff to contact you directly, enter contacts (current value is '{}') or press / to cancel"
-         (loc(Key::CartEnterEdit2, tag, &[&state.prev_state.customer.contact]), cancel_markup(tag))
+         // "If you want to allow staff to contact you directly, enter contacts (current value is '{}'), share your phone number with the button below or press / to cancel"
+         (loc(Key::CartEnterEdit2, tag, &[&state.prev_state.customer.contact_desc(tag)]), contact_markup(tag))
       }
       EditCmd::Address => {
          let customer = &state.prev_state.customer;
+         let book = db::addresses(state.prev_state.prev_state.user_id.0).await?;
 
          // Form a description of the address with a possible display of the geolocation
-         let addr_desc = match customer.location_id() {
-            Ok(message_id) => {
-               // Try to forward geolocation message from history
-               let from = msg.chat.id; // from bot
-               let to = state.prev_state.prev_state.user_id; // to user
-               let res = bot.forward_message(to, from, message_id).await;
+         let addr_desc = match customer.geo {
+            Some(geo) => {
+               // Show the saved point from its coordinates
+               let res = bot.send_location(msg.chat.id, geo.latitude, geo.longitude).await;
                match res {
                   Ok(_) => loc(Key::CartEnterEdit3, tag, &[]), // "previous location in the post above"
                   Err(_) => loc(Key::CartEnterEdit4, tag, &[]), // "saved location is no longer available"
                }
             }
-            Err(()) => {
-               if customer.is_location() {
+            None => {
+               if customer.is_lost_location() {
                   loc(Key::CartEnterEdit4, tag, &[]) // "saved location is no longer available"
                } else {
                   loc(Key::CartEnterEdit5, tag, &[&customer.address]) // "current address '{}'"
@@ -293,7 +430,28 @@ async fn enter_edit(bot: Bot, msg: Message, state: CartStateEditing) -> HandlerR
          };
 
          // "Enter the delivery address or point on the map (/ to cancel), {}. You can also send an arbitrary point or even broadcast its change, to do this, press the paperclip 📎 and select a geolocation."
-         (loc(Key::CartEnterEdit6, tag, &[&addr_desc]), address_markup(tag))
+         let mut text = loc(Key::CartEnterEdit6, tag, &[&addr_desc]);
+         if !book.is_empty() {
+            // "\n\nSaved addresses, choose one with the buttons below or remove it:"
+            let init = text + &loc(Key::CartEnterEdit8, tag, &[]);
+            text = book.iter()
+            .fold(init, |acc, a| format!("{}\n{} {}{}", acc, a.label, ADEL, a.id));
+         }
+         // "\nTo save an address under a name, enter it like 'Home: 1 Main St.'"
+         text += &loc(Key::CartEnterEdit9, tag, &[]);
+         (text, address_markup(&book, tag))
+      }
+      EditCmd::Note(_) => {
+         // "Enter a note to the item like 'no onions', - to remove or / to cancel"
+         (loc(Key::CartEnterEdit10, tag, &[]), cancel_markup(tag))
+      }
+      EditCmd::Comment(_) => {
+         // "Enter a comment to the order like 'call on arrival', - to remove or / to cancel"
+         (loc(Key::CartEnterEdit11, tag, &[]), cancel_markup(tag))
+      }
+      EditCmd::Promo(_) => {
+         // "Enter the promo code, - to remove or / to cancel"
+         (loc(Key::CartEnterEdit12, tag, &[]), cancel_markup(tag))
       }
       EditCmd::Delivery => {
          // "Current value is '{}', select delivery method"
@@ -309,7 +467,7 @@ async fn enter_edit(bot: Bot, msg: Message, state: CartStateEditing) -> HandlerR
 }
 
 pub async fn update_edit(bot: Bot, msg: Message, dialogue: MyDialogue, state: CartStateEditing) -> HandlerResult {
-   async fn do_update(cmd: EditCmd, user_id: u64, ans: String, tag: LocaleTag) -> Result<String, String> {
+   async fn do_update(cmd: EditCmd, user_id: u64, ans: String, geo: Option<(Geo, bool)>, phone: Option<String>, tag: LocaleTag) -> Result<String, String> {
       let cancel_command = loc(Key::CommonCancel, tag, &[]); // "/"
       if ans == cancel_command {
          // "Cancel, value not changed",
@@ -319,8 +477,20 @@ pub async fn update_edit(bot: Bot, msg: Message, dialogue: MyDialogue, state: Ca
       // Store new value
       match cmd {
          EditCmd::Name => db::user_update_name(user_id, &ans).await?,
-         EditCmd::Contact => db::user_update_contact(user_id, &ans).await?,
-         EditCmd::Address => db::user_update_address(user_id, &ans).await?,
+         EditCmd::Contact => match phone {
+            Some(phone) => db::user_update_phone(user_id, &phone).await?,
+            None => db::user_update_contact(user_id, &ans).await?,
+         }
+         EditCmd::Address => return update_address(user_id, ans, geo, tag).await,
+         EditCmd::Note(node_id) => {
+            let note = (ans != "-").then_some(ans.as_str());
+            db::order_update_note(user_id, node_id, note).await?
+         }
+         EditCmd::Comment(owner_id) => {
+            let comment = (ans != "-").then_some(ans.as_str());
+            db::order_update_comment(user_id, owner_id, comment).await?
+         }
+         EditCmd::Promo(owner_id) => return update_promo(user_id, owner_id, ans, tag).await,
          EditCmd::Delivery => {
             // Parse answer
             let delivery = Delivery::from_str(ans.as_str(), tag);
@@ -341,19 +511,32 @@ pub async fn update_edit(bot: Bot, msg: Message, dialogue: MyDialogue, state: Ca
    let tag = state.prev_state.prev_state.tag;
 
    // Input may be text or geolocation
+   let geo = msg.location().map(|l| (Geo::new(l.latitude, l.longitude), l.live_period.is_some()));
    let input = if let Some(input) = msg.text() {
       input.to_string()
    } else {
       if let Some(_) = msg.location() {
-         Customer::make_location(msg.id)
+         Customer::make_location()
       } else {
          String::default()
       }
    };
 
+   // Phone number is verified only when the customer shares own contact
+   let user_id = state.prev_state.prev_state.user_id;
+   if let Some(contact) = msg.contact() {
+      if contact.user_id != Some(user_id) {
+         // "Only your own phone number can be saved, please use the button"
+         let text = loc(Key::CartUpdateEditPhone, tag, &[]);
+         bot.send_message(msg.chat.id, text).await?;
+         return enter(bot, msg, dialogue, state.prev_state.prev_state).await
+      }
+   }
+   let phone = msg.contact().map(|c| c.phone_number.chars().take(20).collect());
+
    // Report result
-   let user_id = state.prev_state.prev_state.user_id.0 as u64;
-   let text = do_update(state.cmd, user_id, input, tag).await?;
+   let user_id = user_id.0;
+   let text = do_update(state.cmd, user_id, input, geo, phone, tag).await?;
 
    bot.send_message(msg.chat.id, text).await?;
 
@@ -361,6 +544,91 @@ pub async fn update_edit(bot: Bot, msg: Message, dialogue: MyDialogue, state: Ca
    enter(bot, msg, dialogue, state.prev_state.prev_state).await
 }
 
+// Choose, remove or add an entry of the address book
+async fn update_address(user_id: u64, ans: String, geo: Option<(Geo, bool)>, tag: LocaleTag) -> Result<String, String> {
+   if let Some(id) = ans.strip_prefix(ADEL) {
+      db::address_delete(user_id, id.parse().unwrap_or_default()).await?;
+      // "Address removed"
+      return Ok(loc(Key::CartUpdateEditAddress, tag, &[]));
+   }
+
+   // Label from the button
+   let book = db::addresses(user_id).await?;
+   if let Some(a) = book.iter().find(|a| a.label == ans) {
+      db::address_select(user_id, a.id).await?;
+      // "New value saved"
+      return Ok(loc(Key::CommonEditConfirm, tag, &[]));
+   }
+
+   let (label, address) = match geo {
+      // "📍 Live location"
+      Some((_, true)) => (loc(Key::CartAddressLabel2, tag, &[]), ans),
+      // "📍 Location"
+      Some((_, false)) => (loc(Key::CartAddressLabel1, tag, &[]), ans),
+      None => match ans.split_once(':') {
+         Some((label, address)) if !label.trim().is_empty() && label.chars().count() <= 20 => {
+            (label.trim().to_string(), address.trim().to_string())
+         }
+         _ => (ans.clone(), ans),
+      }
+   };
+
+   let label: String = label.chars().take(100).collect();
+   let address: String = address.chars().take(100).collect();
+   let live = geo.map(|g| g.1).unwrap_or_default();
+   db::address_save(user_id, &label, &address, geo.map(|g| g.0), live).await?;
+
+   // "New value saved"
+   Ok(loc(Key::CommonEditConfirm, tag, &[]))
+}
+
+// Check the code for the order to the owner before saving
+async fn update_promo(user_id: u64, owner_id: i32, ans: String, tag: LocaleTag) -> Result<String, String> {
+   if ans == "-" {
+      db::order_update_promo(user_id, owner_id, None).await?;
+      // "New value saved"
+      return Ok(loc(Key::CommonEditConfirm, tag, &[]));
+   }
+
+   let code = Promo::normalize(&ans);
+   let res = match db::promo_check(&code, owner_id, user_id).await? {
+      Check::Valid(promo) => {
+         db::order_update_promo(user_id, owner_id, Some(promo.id)).await?;
+         // "New value saved"
+         loc(Key::CommonEditConfirm, tag, &[])
+      }
+      // "Promo code {} not found for this place"
+      Check::NotFound => loc(Key::CartUpdateEditPromo1, tag, &[&code]),
+      // "Promo code {} is not valid now"
+      Check::Expired => loc(Key::CartUpdateEditPromo2, tag, &[&code]),
+      // "Promo code {} has already been used up"
+      Check::Exhausted => loc(Key::CartUpdateEditPromo3, tag, &[&code]),
+   };
+   Ok(res)
+}
+
+// The customer's live location keeps the delivery point up to date
+pub async fn update_live_location(msg: Message) -> HandlerResult {
+   let location = msg.location().filter(|l| l.live_period.is_some());
+   if let (Some(location), Some(user)) = (location, msg.from.as_ref()) {
+      let geo = Geo::new(location.latitude, location.longitude);
+      db::user_update_live_geo(user.id.0, geo).await?;
+   }
+   Ok(())
+}
+
+fn contact_markup(tag: LocaleTag) -> ReplyMarkup {
+   let kb = vec![
+      KeyboardButton::new(loc(Key::CartContactMarkup, tag, &[])).request(ButtonRequest::Contact), // "📞 Share phone"
+      KeyboardButton::new(loc(Key::CommonCancel, tag, &[])), // "/"
+   ];
+
+   let markup = KeyboardMarkup::new(vec![kb])
+   .resize_keyboard();
+
+   ReplyMarkup::Keyboard(markup)
+}
+
 fn delivery_markup(tag: LocaleTag) -> ReplyMarkup {
    kb_markup(vec![vec![
       String::from(Delivery::Courier.to_string(tag)),
@@ -368,13 +636,19 @@ fn delivery_markup(tag: LocaleTag) -> ReplyMarkup {
    ]])
 }
 
-fn address_markup(tag: LocaleTag) -> ReplyMarkup {
+fn address_markup(book: &[Address], tag: LocaleTag) -> ReplyMarkup {
    let kb = vec![
       KeyboardButton::new(loc(Key::CartAddressMarkup, tag, &[])).request(ButtonRequest::Location), // "Geolocation"
       KeyboardButton::new(loc(Key::CommonCancel, tag, &[])), // "/"
    ];
 
-   let markup = KeyboardMarkup::new(vec![kb])
+   // Saved addresses above
+   let mut rows: Vec<Vec<KeyboardButton>> = book.chunks(2)
+   .map(|row| row.iter().map(|a| KeyboardButton::new(a.label.clone())).collect())
+   .collect();
+   rows.push(kb);
+
+   let markup = KeyboardMarkup::new(rows)
    .resize_keyboard();
 
    ReplyMarkup::Keyboard(markup)
@@ -0,0 +1,115 @@
+/* ===============================================================================
+Restaurant menu bot.
+Anonymous chat tied to a ticket. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use teloxide::{prelude::*, types::{CallbackQuery, ReplyParameters, }};
+
+use crate::states::*;
+use crate::database as db;
+use crate::node;
+use crate::loc::*;
+
+#[derive(Clone)]
+pub struct ChatState {
+   pub prev_state: Box<State>,
+   pub user_id: UserId,
+   pub tag: LocaleTag,
+   pub ticket_id: i32,
+}
+
+pub async fn enter(bot: &Bot, q: CallbackQuery, dialogue: MyDialogue, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let user_id = q.from.id;
+   let t = db::ticket_with_owners(ticket_id).await?;
+
+   // Only the customer and the owners can talk
+   if user_id != t.ticket.customer_id && !t.owners.contains(user_id) {
+      // "You are not a participant of this order"
+      return Ok(loc(Key::ChatEnter1, tag, &[]));
+   }
+
+   if !t.ticket.stage.is_active() {
+      // "The order is completed, the chat is closed"
+      return Ok(loc(Key::ChatEnter2, tag, &[]));
+   }
+
+   // Return to the same place after chat
+   let prev_state = resume_state(&dialogue, user_id, tag).await?;
+   let new_state = ChatState { prev_state, user_id, tag, ticket_id };
+   dialogue.update(new_state)
+   .await
+   .map_err(|err| format!("chat::enter dialogue user_id={} {}", user_id, err))?;
+
+   // "Order {}: write your messages, they will be delivered without revealing your contacts. Press / to finish"
+   let text = loc(Key::ChatEnter3, tag, &[&t.ticket.code()]);
+   bot.send_message(user_id, text)
+   .reply_markup(cancel_markup(tag))
+   .await
+   .map_err(|err| format!("chat::enter user_id={} {}", user_id, err))?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn update(bot: Bot, msg: Message, dialogue: MyDialogue, state: ChatState) -> HandlerResult {
+   let tag = state.tag;
+   let chat_id = msg.chat.id;
+
+   if msg.text() == Some(loc(Key::CommonCancel, tag, &[]).as_str()) {
+      // "Chat finished"
+      return leave(bot, msg, dialogue, state, loc(Key::ChatUpdate1, tag, &[])).await;
+   }
+
+   // The ticket may be finished while chatting
+   let t = db::ticket_with_owners(state.ticket_id).await?;
+   if !t.ticket.stage.is_active() {
+      // "The order is completed, the chat is closed"
+      return leave(bot, msg, dialogue, state, loc(Key::ChatEnter2, tag, &[])).await;
+   }
+
+   // Recipients on the other side
+   let from_customer = state.user_id == t.ticket.customer_id;
+   let (recipients, header) = if from_customer {
+      let owners = [t.owners.0, t.owners.1, t.owners.2];
+      let recipients: Vec<UserId> = owners.into_iter()
+      .filter(|id| id.0 > node::Owners::VALID_USER_ID)
+      .collect();
+      // "⬆️ Order {}: message from the customer"
+      (recipients, loc(Key::ChatUpdate2, tag, &[&t.ticket.code()]))
+   } else {
+      // "⬆️ Order {}: message from the place"
+      (vec![t.ticket.customer_id], loc(Key::ChatUpdate3, tag, &[&t.ticket.code()]))
+   };
+
+   // Copy the message without the sender and add the ticket info
+   let mut delivered = false;
+   for recipient in recipients {
+      let res = bot.copy_message(recipient, chat_id, msg.id).await;
+      if let Ok(copy_id) = res {
+         delivered = bot.send_message(recipient, &header)
+         .reply_parameters(ReplyParameters::new(copy_id))
+         .reply_markup(t.ticket.markup_chat_reply(tag))
+         .await
+         .is_ok() || delivered;
+      }
+   }
+
+   let text = if delivered {
+      // "Message sent"
+      loc(Key::GeneralUpdateInput3, tag, &[])
+   } else {
+      // "Unable to deliver the message"
+      loc(Key::ChatUpdate4, tag, &[])
+   };
+   bot.send_message(chat_id, text).await?;
+
+   Ok(())
+}
+
+async fn leave(bot: Bot, msg: Message, dialogue: MyDialogue, state: ChatState, text: String) -> HandlerResult {
+   resume(bot, msg, dialogue, *state.prev_state, text).await
+}
@@ -0,0 +1,69 @@
+/* ===============================================================================
+Restaurant menu bot.
+Courier's live location for the customer. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use teloxide::{prelude::*, types::ReplyParameters};
+
+use crate::states::*;
+use crate::database as db;
+use crate::ticket::*;
+
+// Ticket in delivery if the message is the live location of its courier
+pub async fn delivery_of(msg: Message) -> Option<TicketWithOwners> {
+   let is_live = msg.location()
+   .map(|l| l.live_period.is_some())
+   .unwrap_or_default();
+   if !is_live {
+      return None;
+   }
+
+   let courier_id = msg.from.as_ref()?.id;
+   db::ticket_delivering_by(courier_id)
+   .await
+   .ok()
+   .flatten()
+}
+
+// Repeat the courier's location at customer side, new or edited message
+pub async fn relay_location(bot: Bot, msg: Message, t: TicketWithOwners) -> HandlerResult {
+   let location = msg.location().unwrap(); // checked in delivery_of()
+   let customer_id = t.ticket.customer_id;
+
+   match t.ticket.courier_location_id {
+      Some(msg_id) => {
+         // Telegram refuses to edit when the live period is over, nothing to do
+         let _ = bot.edit_message_live_location(customer_id, msg_id, location.latitude, location.longitude)
+         .await;
+      }
+      None => {
+         // Show it under the order message
+         let period = location.live_period.map(|p| p.seconds()).unwrap_or_default();
+         let res = bot.send_location(customer_id, location.latitude, location.longitude)
+         .live_period(period)
+         .reply_parameters(ReplyParameters::new(t.ticket.cust_msg_id))
+         .await
+         .map_err(|err| format!("courier::relay_location customer_id={}: {}", customer_id, err))?;
+
+         db::ticket_update_courier_location(t.ticket.id, Some(res.id)).await?;
+      }
+   }
+
+   Ok(())
+}
+
+// Finish the location relay when the delivery is over
+pub async fn stop_relay(bot: &Bot, t: &TicketWithOwners) -> Result<(), String> {
+   if let Some(msg_id) = t.ticket.courier_location_id {
+      // The live period may already be over
+      let _ = bot.stop_message_live_location(t.ticket.customer_id, msg_id)
+      .await;
+
+      db::ticket_update_courier_location(t.ticket.id, None).await?;
+   }
+   Ok(())
+}
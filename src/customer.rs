@@ -7,9 +7,12 @@ http://www.gnu.org/licenses/gpl-3.0.html
 Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
 =============================================================================== */
 
-use teloxide::types::{MessageId,};
+use crate::geo::Geo;
 use crate::loc::*;
 
+// Address text when the customer sent a point on the map
+const LOCATION: &str = "Location";
+
 #[derive(Clone)]
 pub enum Delivery {
    Courier, // delivery by courier
@@ -35,20 +38,46 @@ impl Delivery {
 pub struct Customer {
    pub name: String,
    pub contact: String,
+   pub phone: Option<String>, // verified number from the contact button
    pub address: String,
    pub delivery: Delivery,
+   pub geo: Option<Geo>, // coordinates if the address is a point on the map
+   pub live: bool, // the point follows the customer's live location
+   pub address_id: Option<i32>, // current entry of the address book
+}
+
+// Entry of the customer's address book
+#[derive(Clone)]
+pub struct Address {
+   pub id: i32,
+   pub label: String,
 }
 
 impl Customer {
+   pub fn contact_desc(&self, tag: LocaleTag) -> String {
+      match &self.phone {
+         // "{}, phone {}"
+         Some(phone) => loc(Key::CustomerContactDesc, tag, &[&self.contact, phone]),
+         None => self.contact.clone(),
+      }
+   }
+
+   pub fn is_courier(&self) -> bool {
+      matches!(self.delivery, Delivery::Courier)
+   }
+
    pub fn delivery_desc(&self, tag: LocaleTag) -> String {
       match self.delivery {
          Delivery::Courier => {
-            if self.address.len() <= 1 {
-               // "for delivery by courier, enter the address or choose pickup"
-               loc(Key::CustomerDeliveryDesk1, tag, &[])
+            if self.is_location() && self.live {
+               // "courier for live geolocation"
+               loc(Key::CustomerDeliveryDesk5, tag, &[])
             } else if self.is_location() {
                // "courier for geolocation"
                loc(Key::CustomerDeliveryDesk2, tag, &[])
+            } else if !self.has_address() {
+               // "for delivery by courier, enter the address or choose pickup"
+               loc(Key::CustomerDeliveryDesk1, tag, &[])
             } else {
                // "courier to the address: {}"
                loc(Key::CustomerDeliveryDesk3, tag, &[&self.address])
@@ -59,25 +88,21 @@ impl Customer {
       }
    }
 
-   pub fn make_location(location_id: MessageId) -> String {
-      format!("Location{}", location_id.0)
+   // Address text for a point on the map, the coordinates are stored separately
+   pub fn make_location() -> String {
+      String::from(LOCATION)
    }
 
    pub fn is_location(&self) -> bool {
-      self.address.starts_with("Location")
+      self.geo.is_some()
    }
 
-   pub fn location_id(&self) -> Result<MessageId, ()> {
-      if self.is_location() {
-         self.address.get(8..)
-         .and_then(
-            |s| s.parse::<i32>()
-            .ok()
-            .map(|id| MessageId(id))
-         )
-         .ok_or(())
-      } else {
-         Err(())
-      }
+   // Point on the map was saved earlier only as a message reference
+   pub fn is_lost_location(&self) -> bool {
+      self.geo.is_none() && self.address.starts_with(LOCATION)
+   }
+
+   pub fn has_address(&self) -> bool {
+      self.is_location() || (self.address.len() > 1 && !self.is_lost_location())
    }
 }
\ No newline at end of file
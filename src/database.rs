@@ -14,13 +14,21 @@ use tokio_postgres::{types::ToSql, Row, };
 use teloxide::types::{MessageId, UserId,};
 use async_recursion::async_recursion;
 use std::str::FromStr;
+use chrono::{NaiveDate, NaiveDateTime};
 
 use crate::environment as env;
+use crate::geo::Geo;
 use crate::node::*;
 use crate::customer::*;
 use crate::orders::*;
 use crate::ticket;
 use crate::search;
+use crate::review::Rating;
+use crate::payment::Reconciliation;
+use crate::referral::Referral;
+use crate::promo::{Check, Discount, Promo};
+use crate::pricing::PriceRule;
+use crate::bundle::{Bundle, Slot};
 
 
 // Access to database
@@ -57,6 +65,8 @@ pub async fn node(mode: LoadNode) -> Result<Option<Node>, String> {
             start_node.picture = node_lookup_picture(start_node.parent).await?;
          }
 
+         node_apply_price_rules(std::slice::from_mut(&mut start_node)).await?;
+
          Ok(Some(start_node))
       }
    }
@@ -95,13 +105,27 @@ pub async fn node(mode: LoadNode) -> Result<Option<Node>, String> {
          owners: Owners::from_int(row.get(7), row.get(8), row.get(9)),
          time: (row.get(10), row.get(11)),
          price: row.get::<usize, i32>(12) as usize,
+         couriers: Owners::from_int(row.get(13), row.get(14), row.get(15)),
+         delivery: DeliveryTerms {
+            min_order: row.get::<usize, i32>(16) as usize,
+            fee: row.get::<usize, i32>(17) as usize,
+            free_from: row.get::<usize, i32>(18) as usize,
+            zone: Geo::from_opt(row.get(19), row.get(20)),
+            radius: row.get::<usize, i32>(21) as usize,
+            km_fee: row.get::<usize, i32>(22) as usize,
+         },
+         phone_required: row.get(23),
+         discount: 0,
+         stock: row.get::<usize, Option<i32>>(24).map(|s| s as usize),
+         daily_stock: row.get::<usize, i32>(25) as usize,
+         points_rate: row.get::<usize, i32>(26) as usize,
       }
    }
 
    // === main body
 
    // Construct statement from parts
-   let part_select = "SELECT id, parent, title, descr, picture, enabled, banned, owner1, owner2, owner3, open, close, price FROM nodes WHERE";
+   let part_select = "SELECT id, parent, title, descr, picture, enabled, banned, owner1, owner2, owner3, open, close, price, courier1, courier2, courier3, min_order, delivery_fee, free_delivery, zone_lat, zone_lon, zone_radius, km_fee, phone_required, stock, daily_stock, points_rate FROM nodes WHERE";
    let part_owner = "owner1 = $1::BIGINT OR owner2 = $1::BIGINT OR owner3 = $1::BIGINT";
    let part_id = "id = $1::BIGINT";
    let part_enabled = "AND enabled AND NOT banned";
@@ -150,6 +174,7 @@ pub async fn node(mode: LoadNode) -> Result<Option<Node>, String> {
             node.children.push(child);
          }
 
+         node_apply_price_rules(&mut node.children).await?;
          Ok(Some(node))
       }
 
@@ -174,6 +199,39 @@ pub async fn node(mode: LoadNode) -> Result<Option<Node>, String> {
    }
 }
 
+// Discount by the rules of the node or its parents active now, the biggest one if several
+async fn node_apply_price_rules(nodes: &mut [Node]) -> Result<(), String> {
+   let ids: Vec<i32> = nodes.iter()
+   .filter(|node| node.price > 0)
+   .map(|node| node.id)
+   .collect();
+   if ids.is_empty() {
+      return Ok(());
+   }
+
+   // Rules of the item and all its parents, the choice is in PriceRule::discount_at()
+   let sql_text = "WITH RECURSIVE cte AS (
+         SELECT id AS item_id, id, parent FROM nodes WHERE id = ANY($1::INTEGER[])
+         UNION SELECT cte.item_id, n.id, n.parent FROM nodes n
+         INNER JOIN cte ON cte.parent = n.id
+      ) SELECT r.id, r.node_id, r.weekdays, r.start, r.finish, r.percent, cte.item_id
+      FROM cte INNER JOIN price_rules r ON r.node_id = cte.id";
+
+   let rows = query_prepared(sql_text, &[&ids]).await?;
+   let mut rules: HashMap<i32, Vec<PriceRule>> = HashMap::new();
+   for row in &rows {
+      rules.entry(row.get(6)).or_default().push(price_rule_from_db(row));
+   }
+
+   let now = env::current_date_time();
+   for node in nodes.iter_mut() {
+      node.discount = rules.get(&node.id)
+      .map(|rules| PriceRule::discount_at(rules, now))
+      .unwrap_or_default();
+   }
+   Ok(())
+}
+
 async fn node_lookup_picture(node_id: i32) -> Result<Origin, String> {
    let sql_text = "WITH RECURSIVE cte AS (
          SELECT id, parent, picture FROM nodes WHERE id = $1::INTEGER
@@ -192,6 +250,35 @@ async fn node_lookup_picture(node_id: i32) -> Result<Origin, String> {
    else { Ok(Origin::None) }
 }
 
+// Delivery conditions, each one from the nearest node where it is set
+pub async fn node_delivery_terms(node_id: i32) -> Result<DeliveryTerms, String> {
+   // The zone center goes together with its radius
+   let sql_text = "WITH RECURSIVE cte AS (
+         SELECT id, parent, min_order, delivery_fee, free_delivery, zone_lat, zone_lon, zone_radius, km_fee, 0 AS depth
+         FROM nodes WHERE id = $1::INTEGER
+         UNION SELECT n.id, n.parent, n.min_order, n.delivery_fee, n.free_delivery, n.zone_lat, n.zone_lon, n.zone_radius, n.km_fee, cte.depth + 1
+         FROM nodes n
+         INNER JOIN cte ON cte.parent = n.id
+      ) SELECT
+         COALESCE((SELECT min_order FROM cte WHERE min_order > 0 ORDER BY depth LIMIT 1), 0),
+         COALESCE((SELECT delivery_fee FROM cte WHERE delivery_fee > 0 ORDER BY depth LIMIT 1), 0),
+         COALESCE((SELECT free_delivery FROM cte WHERE free_delivery > 0 ORDER BY depth LIMIT 1), 0),
+         (SELECT zone_lat FROM cte WHERE zone_lat IS NOT NULL ORDER BY depth LIMIT 1),
+         (SELECT zone_lon FROM cte WHERE zone_lat IS NOT NULL ORDER BY depth LIMIT 1),
+         COALESCE((SELECT zone_radius FROM cte WHERE zone_lat IS NOT NULL ORDER BY depth LIMIT 1), 0),
+         COALESCE((SELECT km_fee FROM cte WHERE km_fee > 0 ORDER BY depth LIMIT 1), 0)";
+
+   let row = query_prepared_one(sql_text, &[&node_id]).await?;
+   Ok(DeliveryTerms {
+      min_order: row[0].get::<usize, i32>(0) as usize,
+      fee: row[0].get::<usize, i32>(1) as usize,
+      free_from: row[0].get::<usize, i32>(2) as usize,
+      zone: Geo::from_opt(row[0].get(3), row[0].get(4)),
+      radius: row[0].get::<usize, i32>(5) as usize,
+      km_fee: row[0].get::<usize, i32>(6) as usize,
+   })
+}
+
 pub async fn node_insert(node: &mut Node) -> Result<(), String> {
    // Information for query
    let sql_text = "INSERT INTO nodes (parent, title, descr, picture, enabled, banned, owner1, owner2, owner3, open, close, price) \
@@ -279,9 +366,51 @@ pub async fn node_update(id: i32, update: &UpdateNode) -> Result<(), String> {
          let i32_new_val = *new_val as i32;
          execute_one(text.as_str(), &[&i32_new_val, &id]).await
       }
+      UpdateKind::Location(new_val) => {
+         let text = "UPDATE nodes SET zone_lat = $1::DOUBLE PRECISION, zone_lon = $2::DOUBLE PRECISION WHERE id=$3::INTEGER";
+         let (lat, lon) = (new_val.map(|g| g.latitude), new_val.map(|g| g.longitude));
+         execute_one(text, &[&lat, &lon, &id]).await
+      }
+      UpdateKind::Stock(new_val) => {
+         let text = format!("UPDATE nodes SET {} = $1::INTEGER WHERE id=$2::INTEGER", update.field);
+         let new_val = new_val.map(|v| v as i32);
+         execute_one(text.as_str(), &[&new_val, &id]).await
+      }
    }
 }
 
+// Refill the stock of the nodes with the daily amount once a day, returns the number of nodes
+pub async fn nodes_restock(today: NaiveDate) -> Result<u64, String> {
+   let text = "UPDATE nodes SET stock = daily_stock, stock_date = $1::DATE
+      WHERE daily_stock > 0 AND stock_date IS DISTINCT FROM $1::DATE";
+   execute_prepared(text, &[&today]).await
+}
+
+// Return the items of the canceled ticket to the stock, only as much as taken and if not refilled since
+pub async fn nodes_restore_stock(ticket_id: i32) -> Result<(), String> {
+   let mut client = db_client().await?;
+   let trans = client.transaction()
+   .await
+   .map_err(|err| format!("nodes_restore_stock transaction ticket_id={}: {}", ticket_id, err))?;
+
+   let text = "UPDATE nodes n SET stock = n.stock + i.taken
+      FROM (SELECT node_id, stock_date, SUM(stock_taken) AS taken FROM ticket_items
+         WHERE ticket_id = $1::INTEGER AND stock_taken > 0 GROUP BY node_id, stock_date) i
+      WHERE n.id = i.node_id AND n.stock IS NOT NULL AND n.stock_date IS NOT DISTINCT FROM i.stock_date";
+   trans.execute(text, &[&ticket_id])
+   .await
+   .map_err(|err| format!("nodes_restore_stock update ticket_id={}: {}", ticket_id, err))?;
+
+   let text = "UPDATE ticket_items SET stock_taken = 0 WHERE ticket_id = $1::INTEGER";
+   trans.execute(text, &[&ticket_id])
+   .await
+   .map_err(|err| format!("nodes_restore_stock items ticket_id={}: {}", ticket_id, err))?;
+
+   trans.commit()
+   .await
+   .map_err(|err| format!("nodes_restore_stock commit ticket_id={}: {}", ticket_id, err))
+}
+
 
 pub async fn node_search(pattern: &str) -> Result<search::Search, String> {
    
@@ -325,13 +454,391 @@ pub async fn node_search(pattern: &str) -> Result<search::Search, String> {
    Ok(search::Search::new(res))
 }
 
+// ============================================================================
+// [Reviews]
+// ============================================================================
+// Customer's rating of the ticket, a new rating replaces the previous one
+pub async fn review_rate(ticket_id: i32, node_id: i32, rating: i32) -> Result<(), String> {
+   let sql_text = "INSERT INTO reviews (ticket_id, node_id, rating, created) VALUES ($1::INTEGER, $2::INTEGER, $3::INTEGER, $4::TIMESTAMP)
+      ON CONFLICT (ticket_id) DO UPDATE SET rating = EXCLUDED.rating";
+   let created = env::current_date_time();
+   execute_prepared_one(sql_text, &[&ticket_id, &node_id, &rating, &created]).await
+}
+
+// Text of the review, returns the rating or zero if it was not rated
+pub async fn review_update_text(ticket_id: i32, review: &str) -> Result<i32, String> {
+   let sql_text = "UPDATE reviews SET review = $2::VARCHAR WHERE ticket_id = $1::INTEGER RETURNING rating";
+   let rows = query_prepared(sql_text, &[&ticket_id, &review]).await?;
+   Ok(rows.first().map(|row| row.get(0)).unwrap_or_default())
+}
+
+pub async fn node_rating(node_id: i32) -> Result<Option<Rating>, String> {
+   let sql_text = "SELECT AVG(rating)::DOUBLE PRECISION, COUNT(*) FROM reviews WHERE node_id = $1::INTEGER";
+   let rows = query_prepared_one(sql_text, &[&node_id]).await?;
+   let average: Option<f64> = rows[0].get(0);
+   let count: i64 = rows[0].get(1);
+   Ok(average.map(|average| Rating { average, count }))
+}
+
+// ============================================================================
+// [Promo codes]
+// ============================================================================
+const PROMO_SELECT: &str = "SELECT p.id, p.node_id, p.code, p.percent, p.amount, p.valid_from, p.valid_to, p.per_user, p.total FROM promo_codes p";
+
+fn promo_from_db(row: &Row, offset: usize) -> Promo {
+   let percent: i32 = row.get(offset + 3);
+   let amount: i32 = row.get(offset + 4);
+   Promo {
+      id: row.get(offset),
+      node_id: row.get(offset + 1),
+      code: row.get(offset + 2),
+      discount: if percent > 0 { Discount::Percent(percent as usize) } else { Discount::Fixed(amount as usize) },
+      valid_from: row.get(offset + 5),
+      valid_to: row.get(offset + 6),
+      per_user: row.get::<usize, i32>(offset + 7) as usize,
+      total: row.get::<usize, i32>(offset + 8) as usize,
+   }
+}
+
+// Codes set at the node itself
+// The promo code applied to the ticket if it still exists
+pub async fn ticket_promo(ticket_id: i32) -> Result<Option<Promo>, String> {
+   let sql_text = format!("{} WHERE p.id = (SELECT promo_id FROM tickets WHERE ticket_id = $1::INTEGER)", PROMO_SELECT);
+   let rows = query_prepared(&sql_text, &[&ticket_id]).await?;
+   Ok(rows.first().map(|row| promo_from_db(row, 0)))
+}
+
+pub async fn promos(node_id: i32) -> Result<Vec<Promo>, String> {
+   let sql_text = format!("{} WHERE p.node_id = $1::INTEGER ORDER BY p.code", PROMO_SELECT);
+   let rows = query_prepared(&sql_text, &[&node_id]).await?;
+   Ok(rows.iter().map(|row| promo_from_db(row, 0)).collect())
+}
+
+// Create or replace the code, false if it belongs to another node
+pub async fn promo_save(promo: &Promo) -> Result<bool, String> {
+   let (percent, amount) = match promo.discount {
+      Discount::Percent(percent) => (percent as i32, 0),
+      Discount::Fixed(amount) => (0, amount as i32),
+   };
+   let sql_text = "INSERT INTO promo_codes (node_id, code, percent, amount, valid_from, valid_to, per_user, total)
+      VALUES ($1::INTEGER, $2::VARCHAR, $3::INTEGER, $4::INTEGER, $5::DATE, $6::DATE, $7::INTEGER, $8::INTEGER)
+      ON CONFLICT (code) DO UPDATE SET percent = EXCLUDED.percent, amount = EXCLUDED.amount, valid_from = EXCLUDED.valid_from,
+         valid_to = EXCLUDED.valid_to, per_user = EXCLUDED.per_user, total = EXCLUDED.total
+      WHERE promo_codes.node_id = EXCLUDED.node_id";
+   let updated = execute_prepared(sql_text, &[&promo.node_id, &promo.code, &percent, &amount,
+      &promo.valid_from, &promo.valid_to, &(promo.per_user as i32), &(promo.total as i32)
+   ]).await?;
+   Ok(updated == 1)
+}
+
+pub async fn promo_delete(node_id: i32, code: &str) -> Result<bool, String> {
+   let sql_text = "DELETE FROM promo_codes WHERE node_id = $1::INTEGER AND code = $2::VARCHAR";
+   let deleted = execute_prepared(sql_text, &[&node_id, &code]).await?;
+   Ok(deleted == 1)
+}
+
+// Look for the code at the place or its parents and check it for the customer
+pub async fn promo_check(code: &str, owner_node_id: i32, user_id: u64) -> Result<Check, String> {
+   let sql_text = format!("WITH RECURSIVE cte AS (
+         SELECT id, parent FROM nodes WHERE id = $1::INTEGER
+         UNION SELECT n.id, n.parent FROM nodes n
+         INNER JOIN cte ON cte.parent = n.id
+      ) {} WHERE p.code = $2::VARCHAR AND p.node_id IN (SELECT id FROM cte)", PROMO_SELECT);
+   let rows = query_prepared(&sql_text, &[&owner_node_id, &code]).await?;
+   let promo = match rows.first() {
+      Some(row) => promo_from_db(row, 0),
+      None => return Ok(Check::NotFound),
+   };
+
+   if !promo.is_valid_now() {
+      return Ok(Check::Expired);
+   }
+
+   // Canceled orders do not use up the code
+   let sql_text = "SELECT COUNT(*) FILTER (WHERE customer = $2::BIGINT), COUNT(*) FROM tickets
+      WHERE promo_id = $1::INTEGER AND stage NOT IN ('Y', 'Z')";
+   let rows = query_prepared_one(sql_text, &[&promo.id, &(user_id as i64)]).await?;
+   let by_user: i64 = rows[0].get(0);
+   let total: i64 = rows[0].get(1);
+
+   if promo.is_available(by_user as usize, total as usize) {
+      Ok(Check::Valid(promo))
+   } else {
+      Ok(Check::Exhausted)
+   }
+}
+
+// ============================================================================
+// [Price rules]
+// ============================================================================
+// Rules set at the node itself
+pub async fn price_rules(node_id: i32) -> Result<Vec<PriceRule>, String> {
+   let sql_text = "SELECT id, node_id, weekdays, start, finish, percent FROM price_rules WHERE node_id = $1::INTEGER ORDER BY id";
+   let rows = query_prepared(sql_text, &[&node_id]).await?;
+   let res = rows.iter()
+   .map(price_rule_from_db)
+   .collect();
+   Ok(res)
+}
+
+fn price_rule_from_db(row: &Row) -> PriceRule {
+   PriceRule {
+      id: row.get(0),
+      node_id: row.get(1),
+      weekdays: row.get(2),
+      start: row.get(3),
+      finish: row.get(4),
+      percent: row.get::<usize, i32>(5) as usize,
+   }
+}
+
+pub async fn price_rule_insert(rule: &PriceRule) -> Result<(), String> {
+   let sql_text = "INSERT INTO price_rules (node_id, weekdays, start, finish, percent)
+      VALUES ($1::INTEGER, $2::INTEGER, $3::TIME, $4::TIME, $5::INTEGER)";
+   execute_prepared_one(sql_text, &[&rule.node_id, &rule.weekdays, &rule.start, &rule.finish, &(rule.percent as i32)]).await
+}
+
+pub async fn price_rule_delete(node_id: i32, id: i32) -> Result<bool, String> {
+   let sql_text = "DELETE FROM price_rules WHERE node_id = $1::INTEGER AND id = $2::INTEGER";
+   let deleted = execute_prepared(sql_text, &[&node_id, &id]).await?;
+   Ok(deleted == 1)
+}
+
+// ============================================================================
+// [Bundles]
+// ============================================================================
+// Slots of the bundle with the last choice of the customer, None if the node is not a bundle
+pub async fn bundle(node_id: i32, user_id: u64) -> Result<Option<Bundle>, String> {
+   let sql_text = "SELECT s.source_id, c.component_id FROM bundle_slots s
+      LEFT JOIN order_components c ON c.slot_id = s.id AND c.node_id = s.bundle_id AND c.user_id = $2::BIGINT
+      WHERE s.bundle_id = $1::INTEGER ORDER BY s.id";
+   let rows = query_prepared(sql_text, &[&node_id, &(user_id as i64)]).await?;
+   if rows.is_empty() {
+      return Ok(None);
+   }
+
+   let sql_text = "SELECT COALESCE((SELECT percent FROM bundles WHERE node_id = $1::INTEGER), 0)";
+   let percent: i32 = query_prepared_one(sql_text, &[&node_id]).await?[0].get(0);
+
+   // Choices are the enabled children of the source, a disabled source excludes the slot
+   let mut slots = Vec::new();
+   for row in rows {
+      let component_id: Option<i32> = row.get(1);
+      if let Some(source) = node(LoadNode::EnabledId(row.get(0))).await? {
+         let chosen = source.children.iter()
+         .find(|child| Some(child.id) == component_id)
+         .cloned();
+         slots.push(Slot { source, chosen });
+      }
+   }
+
+   Ok(Some(Bundle { percent: percent as usize, slots }))
+}
+
+// Price of the item for the customer with the chosen components, None for a bundle without complete choice
+pub async fn bundle_price(node: &Node, user_id: u64) -> Result<Option<(usize, Option<String>)>, String> {
+   match bundle(node.id, user_id).await? {
+      Some(bundle) if !bundle.is_complete() => Ok(None),
+      Some(bundle) => Ok(Some((bundle.price(node), Some(bundle.components_text())))),
+      None => Ok(Some((node.actual_price(), None))),
+   }
+}
+
+// The customer chooses one of the children of the slot source, false if it is not a choice of the bundle
+pub async fn bundle_choose(user_id: u64, bundle_id: i32, component_id: i32) -> Result<bool, String> {
+   let sql_text = "INSERT INTO order_components (user_id, node_id, slot_id, component_id)
+      SELECT $1::BIGINT, s.bundle_id, s.id, n.id FROM bundle_slots s INNER JOIN nodes n ON n.parent = s.source_id
+      WHERE s.bundle_id = $2::INTEGER AND n.id = $3::INTEGER
+      ON CONFLICT (user_id, node_id, slot_id) DO UPDATE SET component_id = EXCLUDED.component_id";
+   let updated = execute_prepared(sql_text, &[&(user_id as i64), &bundle_id, &component_id]).await?;
+   Ok(updated > 0)
+}
+
+// New slot with choices from the children of the source, false if there is no such node in the place of the bundle or slot already exists
+pub async fn bundle_slot_insert(bundle_id: i32, source_id: i32) -> Result<bool, String> {
+   // The place is the nearest node with the owner up from the bundle, the source is below it but not the bundle or its parents
+   let sql_text = "WITH RECURSIVE up AS (
+         SELECT id, parent, owner1, 0 AS depth FROM nodes WHERE id = $1::INTEGER
+         UNION SELECT n.id, n.parent, n.owner1, up.depth + 1 FROM nodes n
+         INNER JOIN up ON up.parent = n.id
+      ), down AS (
+         SELECT id FROM nodes WHERE id = (SELECT id FROM up WHERE owner1 > 0 ORDER BY depth LIMIT 1)
+         UNION SELECT n.id FROM nodes n
+         INNER JOIN down ON n.parent = down.id
+      ) INSERT INTO bundle_slots (bundle_id, source_id)
+      SELECT $1::INTEGER, id FROM down WHERE id = $2::INTEGER AND id NOT IN (SELECT id FROM up)
+      ON CONFLICT (bundle_id, source_id) DO NOTHING";
+   let inserted = execute_prepared(sql_text, &[&bundle_id, &source_id]).await?;
+   Ok(inserted == 1)
+}
+
+pub async fn bundle_slot_delete(bundle_id: i32, source_id: i32) -> Result<bool, String> {
+   let sql_text = "DELETE FROM bundle_slots WHERE bundle_id = $1::INTEGER AND source_id = $2::INTEGER";
+   let deleted = execute_prepared(sql_text, &[&bundle_id, &source_id]).await?;
+   Ok(deleted == 1)
+}
+
+pub async fn bundle_update_percent(bundle_id: i32, percent: usize) -> Result<(), String> {
+   let sql_text = "INSERT INTO bundles (node_id, percent) VALUES ($1::INTEGER, $2::INTEGER)
+      ON CONFLICT (node_id) DO UPDATE SET percent = EXCLUDED.percent";
+   execute_prepared_one(sql_text, &[&bundle_id, &(percent as i32)]).await
+}
+
+// ============================================================================
+// [Loyalty points]
+// ============================================================================
+// Kinds of the records in the ledger, one of each kind per ticket
+const POINTS_EARNED: &str = "E";
+const POINTS_SPENT: &str = "S";
+const POINTS_RETURNED: &str = "R";
+const POINTS_REFERRAL: &str = "F"; // to the referrer by the ticket of the invited user
+
+pub async fn points_balance(user_id: u64) -> Result<usize, String> {
+   let sql_text = "SELECT COALESCE(SUM(amount), 0) FROM points WHERE user_id = $1::BIGINT";
+   let rows = query_prepared_one(sql_text, &[&(user_id as i64)]).await?;
+   let balance: i64 = rows[0].get(0);
+   Ok(balance.max(0) as usize)
+}
+
+// Whether the user owns or delivers for any place
+pub async fn is_staff(user_id: UserId) -> Result<bool, String> {
+   let sql_text = "SELECT EXISTS (SELECT 1 FROM nodes WHERE $1::BIGINT IN (owner1, owner2, owner3, courier1, courier2, courier3))";
+   let rows = query_prepared_one(sql_text, &[&(user_id.0 as i64)]).await?;
+   Ok(rows[0].get(0))
+}
+
+// Switch spending of the points on the order to the owner
+pub async fn order_toggle_points(user_id: u64, owner_node_id: i32) -> Result<(), String> {
+   let user_id = user_id as i64;
+   let text = "DELETE FROM order_points WHERE user_id = $1::BIGINT AND owner_node_id = $2::INTEGER";
+   if execute_prepared(text, &[&user_id, &owner_node_id]).await? == 0 {
+      let text = "INSERT INTO order_points (user_id, owner_node_id) VALUES ($1::BIGINT, $2::INTEGER)";
+      execute_prepared_one(text, &[&user_id, &owner_node_id]).await?;
+   }
+   Ok(())
+}
+
+// Reward for the finished ticket by the rate of the place, returns the earned points
+pub async fn points_award(ticket_id: i32, user_id: u64, cost: usize) -> Result<usize, String> {
+   let text = "INSERT INTO points (user_id, ticket_id, kind, amount, created)
+      SELECT $1::BIGINT, t.ticket_id, $3::CHAR, $4::INTEGER * n.points_rate / 100, $5::TIMESTAMP
+      FROM tickets t INNER JOIN nodes n ON n.id = t.node_id
+      WHERE t.ticket_id = $2::INTEGER AND $4::INTEGER * n.points_rate / 100 > 0
+      ON CONFLICT (ticket_id, kind) DO NOTHING RETURNING amount";
+   let rows = query_prepared(text, &[&(user_id as i64), &ticket_id, &POINTS_EARNED, &(cost as i32), &env::current_date_time()]).await?;
+   Ok(rows.first().map(|row| row.get::<usize, i32>(0) as usize).unwrap_or_default())
+}
+
+// Give back the points spent on the canceled ticket
+pub async fn points_return(ticket_id: i32) -> Result<(), String> {
+   let text = "INSERT INTO points (user_id, ticket_id, kind, amount, created)
+      SELECT user_id, ticket_id, $2::CHAR, -amount, $4::TIMESTAMP FROM points WHERE ticket_id = $1::INTEGER AND kind = $3::CHAR
+      ON CONFLICT (ticket_id, kind) DO NOTHING";
+   execute_prepared(text, &[&ticket_id, &POINTS_RETURNED, &POINTS_SPENT, &env::current_date_time()]).await?;
+   Ok(())
+}
+
+// Reward for the first finished ticket of the invited user, returns the referrer
+pub async fn points_referral(ticket_id: i32, points: usize) -> Result<Option<u64>, String> {
+   let text = "INSERT INTO points (user_id, ticket_id, kind, amount, created)
+      SELECT u.referrer, t.ticket_id, $2::CHAR, $3::INTEGER, $4::TIMESTAMP
+      FROM tickets t INNER JOIN users u ON u.user_id = t.customer
+      WHERE t.ticket_id = $1::INTEGER AND u.referrer IS NOT NULL
+         AND NOT EXISTS (SELECT 1 FROM points p INNER JOIN tickets o ON o.ticket_id = p.ticket_id
+            WHERE p.kind = $2::CHAR AND o.customer = t.customer)
+      ON CONFLICT (ticket_id, kind) DO NOTHING RETURNING user_id";
+   let rows = query_prepared(text, &[&ticket_id, &POINTS_REFERRAL, &(points as i32), &env::current_date_time()]).await?;
+   Ok(rows.first().map(|row| row.get::<usize, i64>(0) as u64))
+}
+
+// ============================================================================
+// [Referrals]
+// ============================================================================
+// Invited users with the sum of their finished tickets by referrers, all or only one
+pub async fn referrals_report(referrer: Option<u64>) -> Result<Vec<Referral>, String> {
+   let text = "SELECT r.user_id, r.user_name,
+         COUNT(DISTINCT u.user_id), COUNT(DISTINCT t.customer), COUNT(t.ticket_id), COALESCE(SUM(t.cost), 0)::BIGINT
+      FROM users u INNER JOIN users r ON r.user_id = u.referrer
+      LEFT JOIN (SELECT t.ticket_id, t.customer,
+            (SELECT COALESCE(SUM(i.price * i.amount), 0) FROM ticket_items i WHERE i.ticket_id = t.ticket_id) - t.discount - t.points + t.delivery_fee AS cost
+         FROM tickets t WHERE t.stage = $2::CHAR) t ON t.customer = u.user_id
+      WHERE $1::BIGINT IS NULL OR r.user_id = $1::BIGINT
+      GROUP BY r.user_id, r.user_name ORDER BY COUNT(DISTINCT u.user_id) DESC, r.user_name";
+   let referrer = referrer.map(|id| id as i64);
+   let rows = query_prepared(text, &[&referrer, &ticket::Stage::Finished.as_ref()]).await?;
+
+   let res = rows.iter()
+   .map(|row| Referral {
+      referrer_id: row.get::<usize, i64>(0) as u64,
+      name: row.get(1),
+      users: row.get(2),
+      customers: row.get(3),
+      orders: row.get(4),
+      amount: row.get(5),
+   })
+   .collect();
+   Ok(res)
+}
+
+// ============================================================================
+// [Addresses]
+// ============================================================================
+pub async fn addresses(user_id: u64) -> Result<Vec<Address>, String> {
+   let user_id = user_id as i64;
+   let sql_text = "SELECT id, label FROM addresses WHERE user_id = $1::BIGINT ORDER BY id";
+   let rows = query_prepared(sql_text, &[&user_id]).await?;
+
+   let res = rows.iter()
+   .map(|row| Address {
+      id: row.get(0),
+      label: row.get(1),
+   })
+   .collect();
+   Ok(res)
+}
+
+// Store the address in the book and make it current, the same label replaces the old entry
+pub async fn address_save(user_id: u64, label: &str, address: &str, geo: Option<Geo>, live: bool) -> Result<(), String> {
+   let user_id = user_id as i64;
+   let (lat, lon) = (geo.map(|g| g.latitude), geo.map(|g| g.longitude));
+   let sql_text = "WITH a AS (
+         INSERT INTO addresses (user_id, label, address, latitude, longitude, live)
+         VALUES ($1::BIGINT, $2::VARCHAR, $3::VARCHAR, $4::DOUBLE PRECISION, $5::DOUBLE PRECISION, $6::BOOLEAN)
+         ON CONFLICT (user_id, label) DO UPDATE SET address = EXCLUDED.address,
+         latitude = EXCLUDED.latitude, longitude = EXCLUDED.longitude, live = EXCLUDED.live
+         RETURNING id, address, latitude, longitude, live
+      ) UPDATE users u SET address = a.address, latitude = a.latitude, longitude = a.longitude, live = a.live, address_id = a.id
+      FROM a WHERE u.user_id = $1::BIGINT";
+   execute_prepared_one(sql_text, &[&user_id, &label, &address, &lat, &lon, &live]).await
+}
+
+// Make the address from the book current, false if there is no such one
+pub async fn address_select(user_id: u64, address_id: i32) -> Result<bool, String> {
+   let user_id = user_id as i64;
+   let sql_text = "UPDATE users u SET address = a.address, latitude = a.latitude, longitude = a.longitude, live = a.live, address_id = a.id
+      FROM addresses a WHERE a.id = $2::INTEGER AND a.user_id = $1::BIGINT AND u.user_id = $1::BIGINT";
+   let query = execute_prepared(sql_text, &[&user_id, &address_id]).await?;
+   Ok(query == 1)
+}
+
+// Remove the address from the book, the current address of the customer stays
+pub async fn address_delete(user_id: u64, address_id: i32) -> Result<bool, String> {
+   let user_id = user_id as i64;
+   let sql_text = "DELETE FROM addresses WHERE id = $2::INTEGER AND user_id = $1::BIGINT";
+   let query = execute_prepared(sql_text, &[&user_id, &address_id]).await?;
+
+   let sql_text = "UPDATE users SET address_id = NULL WHERE user_id = $1::BIGINT AND address_id = $2::INTEGER";
+   execute_prepared(sql_text, &[&user_id, &address_id]).await?;
+   Ok(query == 1)
+}
+
 // ============================================================================
 // [Users]
 // ============================================================================
 pub async fn user(user_id: u64) -> Result<Customer, String> {
    // Make query
    let user_id = user_id as i64;
-   let sql_text = "SELECT user_name, contact, address, pickup FROM users WHERE user_id=$1::BIGINT";
+   let sql_text = "SELECT user_name, contact, address, pickup, latitude, longitude, live, address_id, phone FROM users WHERE user_id=$1::BIGINT";
    let rows = query_prepared_one(sql_text, &[&user_id]).await?;
    let row = &rows[0];
 
@@ -339,7 +846,11 @@ pub async fn user(user_id: u64) -> Result<Customer, String> {
       name: row.get(0),
       contact: row.get(1),
       address: row.get(2),
-      delivery: if row.get(3) { Delivery::Pickup } else { Delivery::Courier }
+      delivery: if row.get(3) { Delivery::Pickup } else { Delivery::Courier },
+      geo: Geo::from_opt(row.get(4), row.get(5)),
+      live: row.get(6),
+      address_id: row.get(7),
+      phone: row.get(8),
    };
 
    Ok(res)
@@ -355,20 +866,22 @@ pub async fn user_update_last_seen(user_id: u64) -> Result<bool, String> {
    Ok(query == 1)
 }
 
-// Store new user
-pub async fn user_insert(user_id: u64, name: String, contact: String) -> Result<(), String> {
+// Store new user, the referrer is kept only for the first time and if known
+pub async fn user_insert(user_id: u64, name: String, contact: String, referrer: Option<u64>) -> Result<(), String> {
    let client = db_client().await?;
 
    let user_id = user_id as i64;
-   let sql_text = "INSERT INTO users (user_id, user_name, contact, address, last_seen, pickup) \
-      VALUES ($1::BIGINT, $2::VARCHAR, $3::VARCHAR, '-', NOW(), FALSE) \
+   let referrer = referrer.map(|id| id as i64);
+   let sql_text = "INSERT INTO users (user_id, user_name, contact, address, last_seen, pickup, referrer) \
+      VALUES ($1::BIGINT, $2::VARCHAR, $3::VARCHAR, '-', NOW(), FALSE, \
+         (SELECT user_id FROM users WHERE user_id = $4::BIGINT AND user_id <> $1::BIGINT)) \
       ON CONFLICT (user_id) DO UPDATE SET user_name = $2::VARCHAR, contact = $3::VARCHAR, last_seen = NOW() \
-      RETURNING (xmax = 0) AS inserted";
+      RETURNING (xmax = 0) AS inserted, referrer";
    // execute_one(sql_text, &[&user_id, &name, &contact]).await?;
 
    // Run query
    let query = client
-   .query(sql_text, &[&user_id, &name, &contact])
+   .query(sql_text, &[&user_id, &name, &contact, &referrer])
    .await
    .map_err(|err| format!("user_insert {} query: {}", sql_text, err))?;
 
@@ -377,7 +890,10 @@ pub async fn user_insert(user_id: u64, name: String, contact: String) -> Result<
       // Notify about a new user
       let inserted: bool = query[0].get(0);
       if inserted {
-         env::log(&format!("New user id={}, {}, {}", user_id, name, contact)).await;
+         let referrer = query[0].get::<usize, Option<i64>>(1)
+         .map(|id| format!(", invited by /msg{}", id))
+         .unwrap_or_default();
+         env::log(&format!("New user id={}, {}, {}{}", user_id, name, contact, referrer)).await;
       }
 
       Ok(())
@@ -400,8 +916,24 @@ pub async fn user_update_contact(user_id: u64, contact: &String) -> Result<(), S
    user_update_str(user_id, contact, "contact").await
 }
 
-pub async fn user_update_address(user_id: u64, address: &String) -> Result<(), String> {
-   user_update_str(user_id, address, "address").await
+// New point from the customer's live location, false if the customer does not share it
+pub async fn user_update_live_geo(user_id: u64, geo: Geo) -> Result<bool, String> {
+   let user_id = user_id as i64;
+   let text = "UPDATE users SET latitude = $1::DOUBLE PRECISION, longitude = $2::DOUBLE PRECISION WHERE user_id=$3::BIGINT AND live";
+   let query = execute_prepared(text, &[&geo.latitude, &geo.longitude, &user_id]).await?;
+   if query != 1 {
+      return Ok(false);
+   }
+
+   // Keep the address book entry in sync
+   let text = "UPDATE addresses a SET latitude = $1::DOUBLE PRECISION, longitude = $2::DOUBLE PRECISION
+      FROM users u WHERE u.user_id = $3::BIGINT AND a.id = u.address_id AND a.live";
+   execute_prepared(text, &[&geo.latitude, &geo.longitude, &user_id]).await?;
+   Ok(true)
+}
+
+pub async fn user_update_phone(user_id: u64, phone: &String) -> Result<(), String> {
+   user_update_str(user_id, phone, "phone").await
 }
 
 pub async fn user_update_delivery(user_id: u64, delivery: &Delivery) -> Result<(), String> {
@@ -419,10 +951,11 @@ pub async fn orders(user_id: i64) -> Result<Orders, String> {
       node_id: i32,
       owner_id: i32,
       amount: i32,
+      note: Option<String>,
    }
    async fn do_load_orders(user_id: i64) -> Result<Vec<Order>, String> {
       // Run query
-      let sql_text = "SELECT node_id, owner_node_id, amount FROM orders WHERE user_id = $1::BIGINT AND amount > 0";
+      let sql_text = "SELECT node_id, owner_node_id, amount, note FROM orders WHERE user_id = $1::BIGINT AND amount > 0";
       let query = query_prepared(sql_text, &[&user_id]).await?;
 
       // Return result
@@ -431,6 +964,7 @@ pub async fn orders(user_id: i64) -> Result<Orders, String> {
          node_id: row.get(0),
          owner_id: row.get(1),
          amount: row.get(2),
+         note: row.get(3),
       }).collect();
       Ok(res)
    }
@@ -443,11 +977,20 @@ pub async fn orders(user_id: i64) -> Result<Orders, String> {
    for order in orders {
       let node = node(LoadNode::EnabledIdNoChildren(order.node_id)).await?;
 
-      if let Some(node) = node {
+      // Bundles without complete choice are unavailable like disabled nodes
+      let node = match node {
+         Some(node) => bundle_price(&node, user_id as u64).await?.map(|price| (node, price)),
+         None => None,
+      };
+
+      if let Some((node, (price, components))) = node {
 
          let node = NodeWithAmount{
             amount: order.amount as usize,
             node,
+            note: order.note,
+            price,
+            components,
          };
 
          // Add to existing owner or to the new
@@ -464,11 +1007,34 @@ pub async fn orders(user_id: i64) -> Result<Orders, String> {
    let mut res = Orders::new();
    for hash_item in hash {
       let owner = node(LoadNode::EnabledIdNoChildren(hash_item.0)).await?;
-      if let Some(owner) = owner {
+      if let Some(mut owner) = owner {
+         owner.delivery = node_delivery_terms(owner.id).await?;
          res.data.insert(owner, hash_item.1);
       }
    }
 
+   // Comments to the whole orders
+   let sql_text = "SELECT owner_node_id, comment FROM order_comments WHERE user_id = $1::BIGINT";
+   let query = query_prepared(sql_text, &[&user_id]).await?;
+   res.comments = query.iter()
+   .map(|row| (row.get(0), row.get(1)))
+   .collect();
+
+   // Promo codes entered for the orders
+   let sql_text = format!("SELECT o.owner_node_id, p.* FROM order_promos o INNER JOIN ({}) p ON p.id = o.promo_id WHERE o.user_id = $1::BIGINT", PROMO_SELECT);
+   let query = query_prepared(&sql_text, &[&user_id]).await?;
+   res.promos = query.iter()
+   .map(|row| (row.get(0), promo_from_db(row, 1)))
+   .collect();
+
+   // Loyalty points to spend
+   let sql_text = "SELECT owner_node_id FROM order_points WHERE user_id = $1::BIGINT";
+   let query = query_prepared(sql_text, &[&user_id]).await?;
+   res.points = query.iter()
+   .map(|row| row.get(0))
+   .collect();
+   res.points_balance = points_balance(user_id as u64).await?;
+
    Ok(res)
 }
 
@@ -497,16 +1063,19 @@ pub async fn orders_amount(user_id: i64, node_id: i32) -> Result<usize, String>
    Ok(res)
 }
 
-pub async fn orders_amount_inc(user_id: u64, node_id: i32) -> Result<(), String> {
-   let query = "INSERT INTO orders as o (user_id, node_id, owner_node_id, amount) VALUES ($1::BIGINT, $2::INTEGER,
+// With the price for the customer at the moment, false if there is no more in stock
+pub async fn orders_amount_inc(user_id: u64, node_id: i32, price: usize) -> Result<bool, String> {
+   let query = "INSERT INTO orders as o (user_id, node_id, owner_node_id, amount, price) SELECT $1::BIGINT, $2::INTEGER,
       (WITH RECURSIVE cte AS (
             SELECT id, parent, owner1 FROM nodes WHERE id = $2::INTEGER
             UNION SELECT n.id, n.parent, n.owner1 FROM nodes n
             INNER JOIN cte ON cte.parent = n.id
          ) SELECT id FROM cte WHERE owner1 > 0 LIMIT 1
-      ), 1)
+      ), 1, $3::INTEGER
+      FROM nodes WHERE id = $2::INTEGER AND COALESCE(stock, 1) > 0
       ON CONFLICT ON CONSTRAINT orders_pkey DO
-      UPDATE SET amount = o.amount + 1 WHERE o.user_id = $1::BIGINT AND o.node_id = $2::INTEGER";
+      UPDATE SET amount = o.amount + 1, price = EXCLUDED.price WHERE o.user_id = $1::BIGINT AND o.node_id = $2::INTEGER
+      AND o.amount < (SELECT COALESCE(stock, o.amount + 1) FROM nodes WHERE id = $2::INTEGER)";
 
    // Prepare query
    let client = db_client().await?;
@@ -517,15 +1086,14 @@ pub async fn orders_amount_inc(user_id: u64, node_id: i32) -> Result<(), String>
 
    // Run query
    let user_id = user_id as i64;
+   let price = price as i32;
    let query = client
-   .execute(&statement, &[&user_id, &node_id])
+   .execute(&statement, &[&user_id, &node_id, &price])
    .await
    .map_err(|err| format!("amount_inc execute: {}", err))?;
 
-   // Return result
-   if query != 1 {
-      Err(format!("amount_inc execute user_id={}, node_id={} return {} recs instead one", user_id, node_id, query))
-   } else { Ok(()) }
+   // Nothing is changed when the stock is over
+   Ok(query == 1)
 }
 
 pub async fn orders_amount_dec(user_id: u64, node_id: i32) -> Result<(), String> {
@@ -551,6 +1119,37 @@ pub async fn orders_amount_dec(user_id: u64, node_id: i32) -> Result<(), String>
    } else { Ok(()) }
 }
 
+// Cart lines for the place with the prices seen by customer
+pub async fn orders_of_owner(user_id: u64, owner_node_id: i32) -> Result<Vec<OrderLine>, String> {
+   let text = "SELECT o.node_id, COALESCE(n.title, ''), o.price, o.amount FROM orders o LEFT JOIN nodes n ON n.id = o.node_id
+      WHERE o.user_id = $1::BIGINT AND o.owner_node_id = $2::INTEGER AND o.amount > 0 ORDER BY o.node_id";
+   let user_id = user_id as i64;
+   let rows = query_prepared(text, &[&user_id, &owner_node_id]).await?;
+
+   let res = rows.iter()
+   .map(|row| OrderLine {
+      node_id: row.get(0),
+      title: row.get(1),
+      price: row.get::<usize, Option<i32>>(2).map(|p| p as usize),
+      amount: row.get::<usize, i32>(3) as usize,
+   }).collect();
+   Ok(res)
+}
+
+pub async fn order_update_amount(user_id: u64, node_id: i32, amount: usize) -> Result<(), String> {
+   let user_id = user_id as i64;
+   let amount = amount as i32;
+   let text = "UPDATE orders SET amount = $1::INTEGER WHERE user_id = $2::BIGINT AND node_id = $3::INTEGER";
+   execute_prepared_one(text, &[&amount, &user_id, &node_id]).await
+}
+
+pub async fn order_update_price(user_id: u64, node_id: i32, price: usize) -> Result<(), String> {
+   let user_id = user_id as i64;
+   let price = price as i32;
+   let text = "UPDATE orders SET price = $1::INTEGER WHERE user_id = $2::BIGINT AND node_id = $3::INTEGER";
+   execute_prepared_one(text, &[&price, &user_id, &node_id]).await
+}
+
 pub async fn order_delete_node(user_id: u64, node_id: i32) -> Result<(), String> {
    let user_id = user_id as i64;
    let text = "DELETE FROM orders WHERE user_id = $1::BIGINT AND node_id = $2::INTEGER";
@@ -562,28 +1161,120 @@ pub async fn orders_delete(user_id: u64) -> Result<(), String> {
    let user_id = user_id as i64;
    let text = "DELETE FROM orders WHERE user_id = $1::BIGINT OR amount < 1";
    execute_prepared(text, &[&user_id]).await?;
+   let text = "DELETE FROM order_comments WHERE user_id = $1::BIGINT";
+   execute_prepared(text, &[&user_id]).await?;
+   let text = "DELETE FROM order_promos WHERE user_id = $1::BIGINT";
+   execute_prepared(text, &[&user_id]).await?;
+   Ok(())
+}
+
+// Note to the cart line, None to remove
+pub async fn order_update_note(user_id: u64, node_id: i32, note: Option<&str>) -> Result<(), String> {
+   let user_id = user_id as i64;
+   let text = "UPDATE orders SET note = $3::VARCHAR WHERE user_id = $1::BIGINT AND node_id = $2::INTEGER";
+   execute_prepared(text, &[&user_id, &node_id, &note]).await?;
    Ok(())
 }
 
+// Comment to the whole order for the owner, None to remove
+pub async fn order_update_comment(user_id: u64, owner_node_id: i32, comment: Option<&str>) -> Result<(), String> {
+   let user_id = user_id as i64;
+   match comment {
+      Some(comment) => {
+         let text = "INSERT INTO order_comments (user_id, owner_node_id, comment) VALUES ($1::BIGINT, $2::INTEGER, $3::VARCHAR)
+            ON CONFLICT (user_id, owner_node_id) DO UPDATE SET comment = EXCLUDED.comment";
+         execute_prepared_one(text, &[&user_id, &owner_node_id, &comment]).await
+      }
+      None => {
+         let text = "DELETE FROM order_comments WHERE user_id = $1::BIGINT AND owner_node_id = $2::INTEGER";
+         execute_prepared(text, &[&user_id, &owner_node_id]).await?;
+         Ok(())
+      }
+   }
+}
+
+// Promo code for the order to the owner, None to remove
+pub async fn order_update_promo(user_id: u64, owner_node_id: i32, promo_id: Option<i32>) -> Result<(), String> {
+   let user_id = user_id as i64;
+   match promo_id {
+      Some(promo_id) => {
+         let text = "INSERT INTO order_promos (user_id, owner_node_id, promo_id) VALUES ($1::BIGINT, $2::INTEGER, $3::INTEGER)
+            ON CONFLICT (user_id, owner_node_id) DO UPDATE SET promo_id = EXCLUDED.promo_id";
+         execute_prepared_one(text, &[&user_id, &owner_node_id, &promo_id]).await
+      }
+      None => {
+         let text = "DELETE FROM order_promos WHERE user_id = $1::BIGINT AND owner_node_id = $2::INTEGER";
+         execute_prepared(text, &[&user_id, &owner_node_id]).await?;
+         Ok(())
+      }
+   }
+}
+
 // ============================================================================
 // [Tickets]
 // ============================================================================
+// Fields for ticket_from_db()
+const TICKET_SELECT: &str = "SELECT t.ticket_id, t.node_id, t.customer, t.cust_msg_id, t.owner1_msg_id, t.owner2_msg_id, t.owner3_msg_id, t.stage, t.cust_status_msg_id, t.owner1_status_msg_id, t.owner2_status_msg_id, t.owner3_status_msg_id, service_msg_id,
+   n.owner1, n.owner2, n.owner3, t.eta, t.pickup, t.courier, n.courier1, n.courier2, n.courier3, t.courier_loc_msg_id, t.created, t.number, t.scheduled, t.payment_id, t.payment_state, t.payment_amount,
+   t.discount, (SELECT code FROM promo_codes WHERE id = t.promo_id), t.points, t.tip, t.delivery_fee FROM tickets t INNER JOIN nodes n ON n.id = t.node_id";
+
 pub async fn tickets(user_id: i64) -> Result<Vec<ticket::TicketWithOwners>, String> {
    // Load all unfinished tickets, where the user is a client or owner
-   let text = "SELECT t.ticket_id, t.node_id, t.customer, t.cust_msg_id, t.owner1_msg_id, t.owner2_msg_id, t.owner3_msg_id, t.stage, t.cust_status_msg_id, t.owner1_status_msg_id, t.owner2_status_msg_id, t.owner3_status_msg_id, service_msg_id,
-      n.owner1, n.owner2, n.owner3 FROM tickets t INNER JOIN nodes n ON n.id = t.node_id
-      WHERE t.stage < 'X' AND t.customer = $1::BIGINT OR n.owner1 = $1::BIGINT OR n.owner2 = $1::BIGINT OR n.owner3 = $1::BIGINT";
+   let text = format!("{} WHERE t.stage < 'X' AND t.customer = $1::BIGINT OR n.owner1 = $1::BIGINT OR n.owner2 = $1::BIGINT OR n.owner3 = $1::BIGINT", TICKET_SELECT);
+
+   let rows = query_prepared(&text, &[&user_id]).await?;
+   tickets_from_rows(rows).await
+}
 
-   let rows = query_prepared(text, &[&user_id]).await?;
+// Active tickets of the places where the user is an owner and the ones waiting for refund
+pub async fn tickets_of_owner(user_id: UserId) -> Result<Vec<ticket::TicketWithOwners>, String> {
+   let text = format!("{} WHERE (t.stage IN ('A', 'E', 'S', 'B', 'C', 'D') OR t.payment_state = 'R') AND (n.owner1 = $1::BIGINT OR n.owner2 = $1::BIGINT OR n.owner3 = $1::BIGINT) ORDER BY t.ticket_id", TICKET_SELECT);
+   let user_id = user_id.0 as i64;
 
-   let res = rows.iter()
-   .map(|row| ticket_from_db(row))
-   .collect();
+   let rows = query_prepared(&text, &[&user_id]).await?;
+   tickets_from_rows(rows).await
+}
+
+// Tickets of the places where the user is an owner by the daily number, the most recent first
+pub async fn tickets_by_number(user_id: UserId, number: i32) -> Result<Vec<ticket::TicketWithOwners>, String> {
+   let text = format!("{} WHERE t.number = $2::INTEGER AND (n.owner1 = $1::BIGINT OR n.owner2 = $1::BIGINT OR n.owner3 = $1::BIGINT) ORDER BY t.ticket_id DESC LIMIT 30", TICKET_SELECT);
+   let user_id = user_id.0 as i64;
+
+   let rows = query_prepared(&text, &[&user_id, &number]).await?;
+   tickets_from_rows(rows).await
+}
+
+// Orders for later that should go to work by the given time
+pub async fn tickets_scheduled_due(time: NaiveDateTime) -> Result<Vec<ticket::TicketWithOwners>, String> {
+   let text = format!("{} WHERE t.stage = 'S' AND t.scheduled <= $1::TIMESTAMP", TICKET_SELECT);
+
+   let rows = query_prepared(&text, &[&time]).await?;
+   tickets_from_rows(rows).await
+}
+
+// Tickets in progress whose estimated time has passed without a reminder
+pub async fn tickets_eta_expired() -> Result<Vec<ticket::TicketWithOwners>, String> {
+   let text = format!("{} WHERE t.stage IN ('B', 'C') AND NOT t.eta_reminded AND t.eta < $1::TIMESTAMP", TICKET_SELECT);
+   let now = env::current_date_time();
+
+   let rows = query_prepared(&text, &[&now]).await?;
+   tickets_from_rows(rows).await
+}
+
+async fn tickets_from_rows(rows: Vec<Row>) -> Result<Vec<ticket::TicketWithOwners>, String> {
+   let mut res = Vec::with_capacity(rows.len());
+   for row in rows {
+      let mut t = ticket_from_db(&row);
+      t.items = ticket_items(t.ticket.id).await?;
+      res.push(t);
+   }
 
    Ok(res)
 }
 
-pub async fn ticket_form_orders(node_id: i32, user_id: UserId, owners_msg_id: ticket::ThreeMsgId, cust_msg_id: MessageId, service_msg_id: Option<MessageId>) -> Result<ticket::Ticket, String> {
+// The ticket from the cart before anyone is notified, None if there are not enough items in stock,
+// the ids of the messages to owners and service chat are stored later with ticket_update_msg_ids()
+pub async fn ticket_form_orders(node_id: i32, user_id: UserId, checkout: ticket::Checkout, cust_msg_id: MessageId) -> Result<Option<ticket::Ticket>, String> {
 
    // Prepare query
 
@@ -593,63 +1284,212 @@ pub async fn ticket_form_orders(node_id: i32, user_id: UserId, owners_msg_id: ti
    .await
    .map_err(|err| format!("order_to_ticket transaction customer_id={}, node_id={}: {}", user_id, node_id, err))?;
 
-   // Delete orders, like fn delete_orders()
-   let query = "DELETE FROM orders WHERE (user_id = $1::BIGINT AND owner_node_id = $2::INTEGER) OR amount < 1";
-
-   let statement = trans
-   .prepare_cached(&query)
-   .await
-   .map_err(|err| format!("order_to_ticket delete prepare customer_id={}, owner_node_id={}: {}", user_id, node_id, err))?;
-
-   trans
-   .execute(&statement, &[&(user_id.0 as i64), &node_id])
+   // Next daily number of the place, the row of the counter is locked until the end of transaction
+   // so that simultaneous orders get different numbers, the first one of the day continues the existing tickets
+   let created = env::current_date_time();
+   let query = "INSERT INTO ticket_numbers (node_id, date, number)
+      VALUES ($1::INTEGER, $2::TIMESTAMP::DATE,
+         (SELECT COALESCE(MAX(number), 0) + 1 FROM tickets WHERE node_id = $1::INTEGER AND created >= $2::TIMESTAMP::DATE))
+      ON CONFLICT (node_id, date) DO UPDATE SET number = ticket_numbers.number + 1
+      RETURNING number";
+   let query = trans
+   .query(query, &[&node_id, &created])
    .await
-   .map_err(|err| format!("order_to_ticket delete execute customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+   .map_err(|err| format!("order_to_ticket number customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+   let number: i32 = query.first()
+   .ok_or(format!("order_to_ticket customer_id={}, node_id={}: no daily number", user_id, node_id))?
+   .get(0);
 
    // Create ticket
-   let query = "INSERT INTO tickets (node_id, customer, cust_msg_id, owner1_msg_id, owner2_msg_id, owner3_msg_id, stage, cust_status_msg_id, owner1_status_msg_id, owner2_status_msg_id, owner3_status_msg_id, service_msg_id)
-      VALUES ($1::INTEGER, $2::BIGINT, $3::INTEGER, $4::INTEGER, $5::INTEGER, $6::INTEGER, 'A', NULL, NULL, NULL, NULL, $7::INTEGER)
+   let query = "INSERT INTO tickets (node_id, customer, cust_msg_id, owner1_msg_id, owner2_msg_id, owner3_msg_id, stage, cust_status_msg_id, owner1_status_msg_id, owner2_status_msg_id, owner3_status_msg_id, service_msg_id, pickup, created, number, scheduled, delivery_fee)
+      VALUES ($1::INTEGER, $2::BIGINT, $3::INTEGER, NULL, NULL, NULL, 'A', NULL, NULL, NULL, NULL, NULL, $4::BOOLEAN, $5::TIMESTAMP, $6::INTEGER, $7::TIMESTAMP, $8::INTEGER)
       RETURNING ticket_id";
 
    let statement = trans
-   .prepare_cached(&query)
+   .prepare_cached(query)
    .await
    .map_err(|err| format!("order_to_ticket insert prepare customer_id={}, node_id={}: {}", user_id, node_id, err))?;
 
-   // Convert from MessageId to i32
-   let owners_id = ticket::three_msg_id_to_int(&owners_msg_id);
-   let service_id = service_msg_id.map(|id| id.0);
-
    let query = trans
-   .query(&statement, &[&node_id, &(user_id.0 as i64), &cust_msg_id.0, &owners_id.0, &owners_id.1, &owners_id.2, &service_id])
+   .query(&statement, &[&node_id, &(user_id.0 as i64), &cust_msg_id.0, &checkout.pickup, &created, &number, &checkout.scheduled, &(checkout.delivery_fee as i32)])
    .await
    .map_err(|err| format!("order_to_ticket insert query customer_id={}, node_id={}: {}", user_id, node_id, err))?;
 
-   // Commit transaction
-   trans.commit()
-   .await
-   .map_err(|err| format!("order_to_ticket transaction commit customer_id={}, node_id={}: {}", user_id, node_id, err))?;
-
    // Check returning
    let len = query.len();
    if len != 1 {
       return Err(format!("order_to_ticket customer_id={}, node_id={}: query returns {} records instead one", user_id, node_id, len));
    }
-   let ticket_id = query[0].get(0);
+   let ticket_id: i32 = query[0].get(0);
+
+   // Keep the ordered items with the ticket, prices from the cart at the moment of ordering with the price rules applied,
+   // the chosen components of the bundles like Bundle::components_text() and how much is taken from the stock below
+   let query = "INSERT INTO ticket_items (ticket_id, node_id, title, price, amount, proposed, note, stock_taken, stock_date, components)
+      SELECT $1::INTEGER, o.node_id, n.title, COALESCE(o.price, n.price), o.amount, NULL, o.note,
+         CASE WHEN n.stock IS NULL THEN 0 ELSE o.amount END, n.stock_date,
+         (SELECT string_agg(sn.title || ': ' || cn.title, ', ' ORDER BY s.id) FROM order_components c
+         INNER JOIN bundle_slots s ON s.id = c.slot_id
+         INNER JOIN nodes sn ON sn.id = s.source_id
+         INNER JOIN nodes cn ON cn.id = c.component_id
+         WHERE c.user_id = o.user_id AND c.node_id = o.node_id)
+      FROM orders o INNER JOIN nodes n ON n.id = o.node_id
+      WHERE o.user_id = $2::BIGINT AND o.owner_node_id = $3::INTEGER AND o.amount > 0";
+
+   let statement = trans
+   .prepare_cached(query)
+   .await
+   .map_err(|err| format!("order_to_ticket items prepare customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+
+   trans
+   .execute(&statement, &[&ticket_id, &(user_id.0 as i64), &node_id])
+   .await
+   .map_err(|err| format!("order_to_ticket items execute customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+
+   // Move the comment to the whole order
+   let query = "WITH c AS (DELETE FROM order_comments WHERE user_id = $2::BIGINT AND owner_node_id = $3::INTEGER RETURNING comment)
+      UPDATE tickets SET comment = (SELECT comment FROM c) WHERE ticket_id = $1::INTEGER";
+
+   let statement = trans
+   .prepare_cached(query)
+   .await
+   .map_err(|err| format!("order_to_ticket comment prepare customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+
+   trans
+   .execute(&statement, &[&ticket_id, &(user_id.0 as i64), &node_id])
+   .await
+   .map_err(|err| format!("order_to_ticket comment execute customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+
+   // Move the promo code with the discount as the customer saw it in the order
+   let query = "WITH p AS (DELETE FROM order_promos WHERE user_id = $2::BIGINT AND owner_node_id = $3::INTEGER RETURNING promo_id)
+      UPDATE tickets SET promo_id = (SELECT promo_id FROM p), discount = CASE WHEN EXISTS (SELECT 1 FROM p) THEN $4::INTEGER ELSE 0 END
+      WHERE ticket_id = $1::INTEGER
+      RETURNING (SELECT code FROM promo_codes WHERE id = tickets.promo_id), discount";
+
+   let statement = trans
+   .prepare_cached(query)
+   .await
+   .map_err(|err| format!("order_to_ticket promo prepare customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+
+   let row = trans
+   .query_one(&statement, &[&ticket_id, &(user_id.0 as i64), &node_id, &(checkout.discount as i32)])
+   .await
+   .map_err(|err| format!("order_to_ticket promo query customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+   let promo: Option<String> = row.get(0);
+   let discount = row.get::<usize, i32>(1) as usize;
+
+   // Loyalty points are written off with the ledger, no more than left on the balance
+   let query = "DELETE FROM order_points WHERE user_id = $1::BIGINT AND owner_node_id = $2::INTEGER";
+   trans
+   .execute(query, &[&(user_id.0 as i64), &node_id])
+   .await
+   .map_err(|err| format!("order_to_ticket points delete customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+
+   let mut points = 0;
+   if checkout.points > 0 {
+      // The row of the user is locked so that simultaneous orders do not spend the same balance
+      let query = "SELECT 1 FROM users WHERE user_id = $1::BIGINT FOR UPDATE";
+      trans
+      .execute(query, &[&(user_id.0 as i64)])
+      .await
+      .map_err(|err| format!("order_to_ticket points lock customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+
+      let query = "WITH b AS (SELECT COALESCE(SUM(amount), 0) AS balance FROM points WHERE user_id = $1::BIGINT)
+         INSERT INTO points (user_id, ticket_id, kind, amount, created)
+         SELECT $1::BIGINT, $2::INTEGER, $3::CHAR, -LEAST($4::BIGINT, b.balance), $5::TIMESTAMP FROM b WHERE b.balance > 0
+         RETURNING -amount";
+      let rows = trans
+      .query(query, &[&(user_id.0 as i64), &ticket_id, &POINTS_SPENT, &(checkout.points as i64), &created])
+      .await
+      .map_err(|err| format!("order_to_ticket points insert customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+      let spent = rows.first().map(|row| row.get::<usize, i32>(0)).unwrap_or_default();
+
+      let query = "UPDATE tickets SET points = $1::INTEGER WHERE ticket_id = $2::INTEGER";
+      trans
+      .execute(query, &[&spent, &ticket_id])
+      .await
+      .map_err(|err| format!("order_to_ticket points update customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+      points = spent as usize;
+   }
+
+   // Take the ordered items from the stock, the whole order fails if someone else has taken them first.
+   // Only the bundle itself is counted, its chosen components are a recipe and do not touch the stock
+   let query = "WITH u AS (UPDATE nodes n SET stock = n.stock - o.amount FROM orders o
+         WHERE o.user_id = $1::BIGINT AND o.owner_node_id = $2::INTEGER AND o.amount > 0 AND n.id = o.node_id AND n.stock >= o.amount
+         RETURNING n.id)
+      SELECT COUNT(*) FROM orders o INNER JOIN nodes n ON n.id = o.node_id
+      WHERE o.user_id = $1::BIGINT AND o.owner_node_id = $2::INTEGER AND o.amount > 0 AND n.stock IS NOT NULL
+      AND o.node_id NOT IN (SELECT id FROM u)";
+
+   let statement = trans
+   .prepare_cached(query)
+   .await
+   .map_err(|err| format!("order_to_ticket stock prepare customer_id={}, owner_node_id={}: {}", user_id, node_id, err))?;
+
+   let short: i64 = trans
+   .query_one(&statement, &[&(user_id.0 as i64), &node_id])
+   .await
+   .map_err(|err| format!("order_to_ticket stock query customer_id={}, owner_node_id={}: {}", user_id, node_id, err))?
+   .get(0);
+   if short > 0 {
+      // The transaction rolls back when dropped
+      return Ok(None);
+   }
+
+   // The chosen components of the bundles went to the ticket items with the orders
+   let query = "DELETE FROM order_components c USING orders o
+      WHERE c.user_id = $1::BIGINT AND o.user_id = c.user_id AND o.owner_node_id = $2::INTEGER AND o.node_id = c.node_id";
+
+   trans
+   .execute(query, &[&(user_id.0 as i64), &node_id])
+   .await
+   .map_err(|err| format!("order_to_ticket components execute customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+
+   // Delete orders, like fn delete_orders()
+   let query = "DELETE FROM orders WHERE (user_id = $1::BIGINT AND owner_node_id = $2::INTEGER) OR amount < 1";
+
+   let statement = trans
+   .prepare_cached(query)
+   .await
+   .map_err(|err| format!("order_to_ticket delete prepare customer_id={}, owner_node_id={}: {}", user_id, node_id, err))?;
+
+   trans
+   .execute(&statement, &[&(user_id.0 as i64), &node_id])
+   .await
+   .map_err(|err| format!("order_to_ticket delete execute customer_id={}, node_id={}: {}", user_id, node_id, err))?;
+
+   // Commit transaction
+   trans.commit()
+   .await
+   .map_err(|err| format!("order_to_ticket transaction commit customer_id={}, node_id={}: {}", user_id, node_id, err))?;
 
    // Create and return ticket
    let res = ticket::Ticket {
       id: ticket_id,
       node_id,
       customer_id: user_id,
-      owners_msg_id,
+      owners_msg_id: (None, None, None),
       cust_msg_id,
       stage: ticket::Stage::OwnersConfirmation,
       cust_status_msg_id: None,
       owners_status_msg_id: (None, None, None),
-      service_msg_id,
+      service_msg_id: None,
+      eta: None,
+      pickup: checkout.pickup,
+      courier_id: None,
+      courier_location_id: None,
+      created: Some(created),
+      number: Some(number),
+      scheduled: checkout.scheduled,
+      payment: ticket::PaymentState::Unpaid,
+      payment_id: None,
+      paid: 0,
+      discount,
+      promo,
+      points,
+      tip: 0,
+      delivery_fee: checkout.delivery_fee,
    };
-   Ok(res)
+   Ok(Some(res))
 }
 
 pub async fn ticket_update_status_messages(ticket: &ticket::Ticket) -> Result<(), String>
@@ -664,6 +1504,19 @@ pub async fn ticket_update_status_messages(ticket: &ticket::Ticket) -> Result<()
    Ok(())
 }
 
+// The order forwarded to owners and logged to the service chat
+pub async fn ticket_update_msg_ids(ticket: &ticket::Ticket) -> Result<(), String>
+{
+   // Convert from MessageId to i32
+   let owners_id = ticket::three_msg_id_to_int(&ticket.owners_msg_id);
+   let service_id = ticket.service_msg_id.map(|id| id.0);
+
+   let text = "UPDATE tickets SET owner1_msg_id = $1::INTEGER, owner2_msg_id = $2::INTEGER, owner3_msg_id = $3::INTEGER, service_msg_id = $4::INTEGER
+   WHERE ticket_id = $5::INTEGER";
+   execute_prepared_one(text, &[&owners_id.0, &owners_id.1, &owners_id.2, &service_id, &ticket.id]).await?;
+   Ok(())
+}
+
 pub async fn ticket_update_stage(id: i32, stage: ticket::Stage) -> Result<(), String>
 {
    let text = "UPDATE tickets SET stage = $1::CHAR WHERE ticket_id = $2::INTEGER";
@@ -671,15 +1524,115 @@ pub async fn ticket_update_stage(id: i32, stage: ticket::Stage) -> Result<(), St
    Ok(())
 }
 
+// Set the estimated time and wait for it again
+pub async fn ticket_update_eta(id: i32, eta: Option<NaiveDateTime>) -> Result<(), String>
+{
+   let text = "UPDATE tickets SET eta = $1::TIMESTAMP, eta_reminded = FALSE WHERE ticket_id = $2::INTEGER";
+   execute_prepared_one(text, &[&eta, &id]).await?;
+   Ok(())
+}
+
+pub async fn ticket_eta_reminded(id: i32) -> Result<(), String>
+{
+   let text = "UPDATE tickets SET eta_reminded = TRUE WHERE ticket_id = $1::INTEGER";
+   execute_prepared_one(text, &[&id]).await?;
+   Ok(())
+}
+
+// Assign the courier if nobody else has done it yet
+pub async fn ticket_assign_courier(id: i32, courier_id: UserId) -> Result<bool, String>
+{
+   let text = "UPDATE tickets SET courier = $1::BIGINT WHERE ticket_id = $2::INTEGER AND courier IS NULL";
+   let courier_id = courier_id.0 as i64;
+   let updated = execute_prepared(text, &[&courier_id, &id]).await?;
+   Ok(updated == 1)
+}
+
+// The ticket the courier is delivering now
+pub async fn ticket_delivering_by(courier_id: UserId) -> Result<Option<ticket::TicketWithOwners>, String>
+{
+   let text = format!("{} WHERE t.stage = 'C' AND t.courier = $1::BIGINT ORDER BY t.ticket_id DESC LIMIT 1", TICKET_SELECT);
+   let courier_id = courier_id.0 as i64;
+   let rows = query_prepared(&text, &[&courier_id]).await?;
+   Ok(rows.first().map(ticket_from_db))
+}
+
+// Store the charge id once, a repeated notification does not change it
+pub async fn ticket_set_paid(id: i32, charge_id: &str, amount: usize) -> Result<bool, String>
+{
+   let text = "UPDATE tickets SET payment_id = $1::VARCHAR, payment_state = 'P', payment_amount = $2::INTEGER WHERE ticket_id = $3::INTEGER AND payment_state = 'U'";
+   let amount = amount as i32;
+   let updated = execute_prepared(text, &[&charge_id, &amount, &id]).await?;
+   Ok(updated == 1)
+}
+
+// Move the payment to the next state if it is still in the expected one
+pub async fn ticket_update_payment(id: i32, from: ticket::PaymentState, to: ticket::PaymentState) -> Result<bool, String>
+{
+   let text = "UPDATE tickets SET payment_state = $1::CHAR WHERE ticket_id = $2::INTEGER AND payment_state = $3::CHAR";
+   let updated = execute_prepared(text, &[&to.as_ref(), &id, &from.as_ref()]).await?;
+   Ok(updated == 1)
+}
+
+// Paid tickets by places, only the places of the owner if specified
+pub async fn payments_report(owner: Option<UserId>, since: NaiveDateTime) -> Result<Vec<Reconciliation>, String>
+{
+   let text = "SELECT n.title,
+         COUNT(*) FILTER (WHERE t.payment_state <> 'U'), COALESCE(SUM(t.payment_amount) FILTER (WHERE t.payment_state <> 'U'), 0),
+         COUNT(*) FILTER (WHERE t.payment_state = 'P' AND t.stage = 'X'), COALESCE(SUM(t.payment_amount) FILTER (WHERE t.payment_state = 'P' AND t.stage = 'X'), 0),
+         COUNT(*) FILTER (WHERE t.payment_state = 'P' AND t.stage < 'X'), COALESCE(SUM(t.payment_amount) FILTER (WHERE t.payment_state = 'P' AND t.stage < 'X'), 0),
+         COUNT(*) FILTER (WHERE t.payment_state = 'R'), COALESCE(SUM(t.payment_amount) FILTER (WHERE t.payment_state = 'R'), 0),
+         COUNT(*) FILTER (WHERE t.payment_state = 'F'), COALESCE(SUM(t.payment_amount) FILTER (WHERE t.payment_state = 'F'), 0),
+         COUNT(*) FILTER (WHERE t.payment_state = 'P' AND t.stage > 'X'), COALESCE(SUM(t.payment_amount) FILTER (WHERE t.payment_state = 'P' AND t.stage > 'X'), 0),
+         COUNT(*) FILTER (WHERE t.tip > 0), COALESCE(SUM(t.tip), 0)
+      FROM tickets t INNER JOIN nodes n ON n.id = t.node_id
+      WHERE (t.payment_state <> 'U' OR t.tip > 0) AND t.created >= $1::TIMESTAMP
+         AND ($2::BIGINT IS NULL OR n.owner1 = $2::BIGINT OR n.owner2 = $2::BIGINT OR n.owner3 = $2::BIGINT)
+      GROUP BY n.id, n.title ORDER BY n.title";
+   let owner = owner.map(|id| id.0 as i64);
+   let rows = query_prepared(text, &[&since, &owner]).await?;
+
+   let pair = |row: &Row, i: usize| (row.get::<usize, i64>(i), row.get::<usize, i64>(i + 1));
+   let res = rows.iter()
+   .map(|row| Reconciliation {
+      title: row.get(0),
+      paid: pair(row, 1),
+      finished: pair(row, 3),
+      active: pair(row, 5),
+      pending: pair(row, 7),
+      refunded: pair(row, 9),
+      unreturned: pair(row, 11),
+      tips: pair(row, 13),
+   })
+   .collect();
+   Ok(res)
+}
+
+// The tip is left only once, returns false if it was already
+pub async fn ticket_set_tip(id: i32, tip: i32) -> Result<bool, String>
+{
+   let text = "UPDATE tickets SET tip = $1::INTEGER WHERE ticket_id = $2::INTEGER AND tip = 0";
+   let res = execute_prepared(text, &[&tip, &id]).await?;
+   Ok(res == 1)
+}
+
+// Message with the courier's location at customer side
+pub async fn ticket_update_courier_location(id: i32, msg_id: Option<MessageId>) -> Result<(), String>
+{
+   let text = "UPDATE tickets SET courier_loc_msg_id = $1::INTEGER WHERE ticket_id = $2::INTEGER";
+   let msg_id = msg_id.map(|id| id.0);
+   execute_prepared_one(text, &[&msg_id, &id]).await
+}
+
 pub async fn ticket_with_owners(ticket_id: i32) -> Result<ticket::TicketWithOwners, String>
 {
    // Load ticket
-   let sql_text = "SELECT t.ticket_id, t.node_id, t.customer, t.cust_msg_id, t.owner1_msg_id, t.owner2_msg_id, t.owner3_msg_id, t.stage, t.cust_status_msg_id, t.owner1_status_msg_id, t.owner2_status_msg_id, t.owner3_status_msg_id, service_msg_id,
-      n.owner1, n.owner2, n.owner3 FROM tickets t INNER JOIN nodes n ON n.id = t.node_id
-      WHERE t.ticket_id = $1::INTEGER";
-   let rows = query_prepared_one(sql_text, &[&ticket_id]).await?;
+   let sql_text = format!("{} WHERE t.ticket_id = $1::INTEGER", TICKET_SELECT);
+   let rows = query_prepared_one(&sql_text, &[&ticket_id]).await?;
 
-   Ok(ticket_from_db(&rows[0]))
+   let mut res = ticket_from_db(&rows[0]);
+   res.items = ticket_items(ticket_id).await?;
+   Ok(res)
 }
 
 pub fn ticket_from_db(row: &Row) -> ticket::TicketWithOwners {
@@ -694,11 +1647,235 @@ pub fn ticket_from_db(row: &Row) -> ticket::TicketWithOwners {
       cust_status_msg_id: ticket::option_to_msg_id(row.get(8)),
       owners_status_msg_id: ticket::three_option_to_msg_id(row.get(9), row.get(10), row.get(11)),
       service_msg_id: ticket::option_to_msg_id(row.get(12)),
+      eta: row.get(16),
+      pickup: row.get(17),
+      courier_id: row.get::<usize, Option<i64>>(18).map(|id| UserId(id as u64)),
+      courier_location_id: ticket::option_to_msg_id(row.get(22)),
+      created: row.get(23),
+      number: row.get(24),
+      scheduled: row.get(25),
+      payment: ticket::PaymentState::from_str(row.get(27)).unwrap_or(ticket::PaymentState::Unpaid),
+      payment_id: row.get(26),
+      paid: row.get::<usize, i32>(28) as usize,
+      discount: row.get::<usize, i32>(29) as usize,
+      promo: row.get(30),
+      points: row.get::<usize, i32>(31) as usize,
+      tip: row.get::<usize, i32>(32) as usize,
+      delivery_fee: row.get::<usize, i32>(33) as usize,
    };
 
    // Create owners part and return item
    let owners: Owners = Owners::from_int(row.get(13), row.get(14), row.get(15));
-   ticket::TicketWithOwners { ticket, owners }
+   let couriers: Owners = Owners::from_int(row.get(19), row.get(20), row.get(21));
+   ticket::TicketWithOwners { ticket, owners, couriers, items: Vec::new() }
+}
+
+// ============================================================================
+// [Ticket items]
+// ============================================================================
+pub async fn ticket_items(ticket_id: i32) -> Result<Vec<ticket::TicketItem>, String> {
+   let sql_text = "SELECT node_id, title, price, amount, proposed, components, id FROM ticket_items WHERE ticket_id = $1::INTEGER ORDER BY title, id";
+   let rows = query_prepared(sql_text, &[&ticket_id]).await?;
+
+   let res = rows.iter()
+   .map(|row| ticket::TicketItem {
+      id: row.get(6),
+      node_id: row.get(0),
+      title: row.get(1),
+      price: row.get::<usize, i32>(2) as usize,
+      amount: row.get::<usize, i32>(3) as usize,
+      proposed: row.get::<usize, Option<i32>>(4).map(|v| v as usize),
+      components: row.get(5),
+   }).collect();
+
+   Ok(res)
+}
+
+pub enum ItemProposal {
+   Dec, // one less
+   Inc, // one more
+   Remove, // exclude from the order
+}
+
+// Owner's suggestion to change the amount of the line
+pub async fn ticket_item_propose(ticket_id: i32, item_id: i32, proposal: ItemProposal) -> Result<(), String> {
+   let new_val = match proposal {
+      ItemProposal::Dec => "GREATEST(COALESCE(proposed, amount) - 1, 0)",
+      ItemProposal::Inc => "COALESCE(proposed, amount) + 1",
+      ItemProposal::Remove => "0",
+   };
+   let text = format!("UPDATE ticket_items SET proposed = {} WHERE ticket_id = $1::INTEGER AND id = $2::INTEGER", new_val);
+   execute_one(&text, &[&ticket_id, &item_id]).await
+}
+
+// Owner's suggestion to replace the line with another item in the same amount, the item is one of ticket_item_candidates()
+pub async fn ticket_item_substitute(ticket_id: i32, old_id: i32, item: &ticket::TicketItem) -> Result<(), String> {
+   let new_id = item.node_id;
+   let mut client = db_client().await?;
+   let trans = client.transaction()
+   .await
+   .map_err(|err| format!("ticket_item_substitute transaction ticket_id={}: {}", ticket_id, err))?;
+
+   // Add the new item with the amount of the old one
+   let text = "INSERT INTO ticket_items (ticket_id, node_id, title, price, amount, proposed)
+      SELECT $1::INTEGER, $3::INTEGER, $4::VARCHAR, $5::INTEGER, 0, COALESCE(proposed, amount)
+      FROM ticket_items WHERE ticket_id = $1::INTEGER AND id = $2::INTEGER";
+   let added = trans.execute(text, &[&ticket_id, &old_id, &new_id, &item.title, &(item.price as i32)])
+   .await
+   .map_err(|err| format!("ticket_item_substitute insert ticket_id={}: {}", ticket_id, err))?;
+   if added != 1 {
+      return Err(format!("ticket_item_substitute ticket_id={}, node_id={} affected {} records instead one", ticket_id, new_id, added));
+   }
+
+   // Exclude the old one
+   let text = "UPDATE ticket_items SET proposed = 0 WHERE ticket_id = $1::INTEGER AND id = $2::INTEGER";
+   trans.execute(text, &[&ticket_id, &old_id])
+   .await
+   .map_err(|err| format!("ticket_item_substitute update ticket_id={}: {}", ticket_id, err))?;
+
+   trans.commit()
+   .await
+   .map_err(|err| format!("ticket_item_substitute commit ticket_id={}: {}", ticket_id, err))
+}
+
+// Candidates for substitution - items of the same place, not yet in the ticket, available at the time with the price like at the checkout
+pub async fn ticket_item_candidates(ticket_id: i32, owner_node_id: i32, time: NaiveDateTime) -> Result<Vec<ticket::TicketItem>, String> {
+   let sql_text = "WITH RECURSIVE cte AS (
+         SELECT id FROM nodes WHERE id = $1::INTEGER
+         UNION SELECT n.id FROM nodes n
+         INNER JOIN cte ON n.parent = cte.id
+      ) SELECT n.id FROM nodes n INNER JOIN cte ON cte.id = n.id
+      WHERE n.enabled AND NOT n.banned AND n.price > 0 AND (n.stock IS NULL OR n.stock > 0)
+      AND n.id NOT IN (SELECT node_id FROM ticket_items WHERE ticket_id = $2::INTEGER)
+      AND n.id NOT IN (SELECT bundle_id FROM bundle_slots)
+      ORDER BY n.title";
+   let rows = query_prepared(sql_text, &[&owner_node_id, &ticket_id]).await?;
+
+   // The opening hours and the price rules are checked for the loaded node as in the cart
+   let mut res = Vec::new();
+   for row in rows {
+      let node = node(LoadNode::EnabledIdNoChildren(row.get(0))).await?;
+      if let Some(node) = node.filter(|node| node.is_open_at(time.time()) && !node.is_sold_out()) {
+         res.push(ticket::TicketItem {
+            id: 0,
+            node_id: node.id,
+            price: node.actual_price(),
+            title: node.title,
+            amount: 0,
+            proposed: None,
+            components: None,
+         });
+         if res.len() == 30 {
+            break;
+         }
+      }
+   }
+
+   Ok(res)
+}
+
+// Forget the owner's suggestions
+pub async fn ticket_items_discard(ticket_id: i32) -> Result<(), String> {
+   let mut client = db_client().await?;
+   let trans = client.transaction()
+   .await
+   .map_err(|err| format!("ticket_items_discard transaction ticket_id={}: {}", ticket_id, err))?;
+
+   let text = "DELETE FROM ticket_items WHERE ticket_id = $1::INTEGER AND amount = 0";
+   trans.execute(text, &[&ticket_id])
+   .await
+   .map_err(|err| format!("ticket_items_discard delete ticket_id={}: {}", ticket_id, err))?;
+
+   let text = "UPDATE ticket_items SET proposed = NULL WHERE ticket_id = $1::INTEGER";
+   trans.execute(text, &[&ticket_id])
+   .await
+   .map_err(|err| format!("ticket_items_discard update ticket_id={}: {}", ticket_id, err))?;
+
+   trans.commit()
+   .await
+   .map_err(|err| format!("ticket_items_discard commit ticket_id={}: {}", ticket_id, err))
+}
+
+// Make the owner's suggestions the actual order, false if there are not enough items in stock
+pub async fn ticket_items_accept(ticket_id: i32, charges: ticket::Charges) -> Result<bool, String> {
+   let mut client = db_client().await?;
+   let trans = client.transaction()
+   .await
+   .map_err(|err| format!("ticket_items_accept transaction ticket_id={}: {}", ticket_id, err))?;
+
+   // Retake the stock for the changed lines: give back what was taken and take the new amount,
+   // nothing is changed if there is not enough like at the checkout
+   let text = "SELECT id, node_id, proposed, stock_taken, stock_date FROM ticket_items
+      WHERE ticket_id = $1::INTEGER AND proposed IS NOT NULL FOR UPDATE";
+   let rows = trans.query(text, &[&ticket_id])
+   .await
+   .map_err(|err| format!("ticket_items_accept select ticket_id={}: {}", ticket_id, err))?;
+
+   for row in rows {
+      let item_id: i32 = row.get(0);
+      let node_id: i32 = row.get(1);
+      let proposed: i32 = row.get(2);
+      let stock_taken: i32 = row.get(3);
+      let stock_date: Option<NaiveDate> = row.get(4);
+
+      let text = "UPDATE nodes SET stock = stock + $2::INTEGER
+         WHERE id = $1::INTEGER AND stock IS NOT NULL AND stock_date IS NOT DISTINCT FROM $3::DATE";
+      trans.execute(text, &[&node_id, &stock_taken, &stock_date])
+      .await
+      .map_err(|err| format!("ticket_items_accept give back ticket_id={}: {}", ticket_id, err))?;
+
+      let text = "SELECT stock, stock_date FROM nodes WHERE id = $1::INTEGER FOR UPDATE";
+      let stock = trans.query(text, &[&node_id])
+      .await
+      .map_err(|err| format!("ticket_items_accept stock ticket_id={}: {}", ticket_id, err))?;
+      let (stock, stock_date) = stock.first()
+      .map(|row| (row.get::<usize, Option<i32>>(0), row.get::<usize, Option<NaiveDate>>(1)))
+      .unwrap_or_default();
+
+      let stock_taken = match stock {
+         // The transaction rolls back when dropped
+         Some(stock) if stock < proposed => return Ok(false),
+         Some(_) => {
+            let text = "UPDATE nodes SET stock = stock - $2::INTEGER WHERE id = $1::INTEGER";
+            trans.execute(text, &[&node_id, &proposed])
+            .await
+            .map_err(|err| format!("ticket_items_accept take ticket_id={}: {}", ticket_id, err))?;
+            proposed
+         }
+         None => 0,
+      };
+
+      let text = "UPDATE ticket_items SET stock_taken = $2::INTEGER, stock_date = $3::DATE WHERE id = $1::INTEGER";
+      trans.execute(text, &[&item_id, &stock_taken, &stock_date])
+      .await
+      .map_err(|err| format!("ticket_items_accept taken ticket_id={}: {}", ticket_id, err))?;
+   }
+
+   let text = "DELETE FROM ticket_items WHERE ticket_id = $1::INTEGER AND COALESCE(proposed, amount) = 0";
+   trans.execute(text, &[&ticket_id])
+   .await
+   .map_err(|err| format!("ticket_items_accept delete ticket_id={}: {}", ticket_id, err))?;
+
+   let text = "UPDATE ticket_items SET amount = proposed, proposed = NULL WHERE ticket_id = $1::INTEGER AND proposed IS NOT NULL";
+   trans.execute(text, &[&ticket_id])
+   .await
+   .map_err(|err| format!("ticket_items_accept update ticket_id={}: {}", ticket_id, err))?;
+
+   // The charges for the agreed items, the points not needed anymore go back to the balance
+   let text = "UPDATE tickets SET discount = $2::INTEGER, points = $3::INTEGER, delivery_fee = $4::INTEGER WHERE ticket_id = $1::INTEGER";
+   trans.execute(text, &[&ticket_id, &(charges.discount as i32), &(charges.points as i32), &(charges.delivery_fee as i32)])
+   .await
+   .map_err(|err| format!("ticket_items_accept charges ticket_id={}: {}", ticket_id, err))?;
+
+   let text = "UPDATE points SET amount = -$2::INTEGER WHERE ticket_id = $1::INTEGER AND kind = $3::CHAR";
+   trans.execute(text, &[&ticket_id, &(charges.points as i32), &POINTS_SPENT])
+   .await
+   .map_err(|err| format!("ticket_items_accept points ticket_id={}: {}", ticket_id, err))?;
+
+   trans.commit()
+   .await
+   .map_err(|err| format!("ticket_items_accept commit ticket_id={}: {}", ticket_id, err))?;
+   Ok(true)
 }
 
 // ============================================================================
@@ -787,6 +1964,173 @@ pub async fn create_tables() -> Result<(), String> {
    Ok(())
 }
 
+// Bring the tables of the previous versions up to date
+pub async fn update_tables() -> Result<(), String> {
+   let client = db_client().await?;
+
+   client.batch_execute("CREATE TABLE IF NOT EXISTS ticket_items (
+         PRIMARY KEY (id),
+         id             SERIAL,
+         ticket_id      INTEGER        NOT NULL,
+         node_id        INTEGER        NOT NULL,
+         title          VARCHAR        NOT NULL,
+         price          INTEGER        NOT NULL,
+         amount         INTEGER        NOT NULL,
+         proposed       INTEGER);
+
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS eta TIMESTAMP;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS eta_reminded BOOLEAN NOT NULL DEFAULT FALSE;
+
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS courier1 BIGINT NOT NULL DEFAULT 0;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS courier2 BIGINT NOT NULL DEFAULT 0;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS courier3 BIGINT NOT NULL DEFAULT 0;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS pickup BOOLEAN NOT NULL DEFAULT FALSE;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS courier BIGINT;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS courier_loc_msg_id INTEGER;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS created TIMESTAMP;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS number INTEGER;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS scheduled TIMESTAMP;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS payment_id VARCHAR;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS payment_state CHAR NOT NULL DEFAULT 'U';
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS payment_amount INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE orders ADD COLUMN IF NOT EXISTS price INTEGER;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS min_order INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS delivery_fee INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS free_delivery INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS zone_lat DOUBLE PRECISION;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS zone_lon DOUBLE PRECISION;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS zone_radius INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS km_fee INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE users ADD COLUMN IF NOT EXISTS latitude DOUBLE PRECISION;
+      ALTER TABLE users ADD COLUMN IF NOT EXISTS longitude DOUBLE PRECISION;
+      ALTER TABLE users ADD COLUMN IF NOT EXISTS live BOOLEAN NOT NULL DEFAULT FALSE;
+      ALTER TABLE users ADD COLUMN IF NOT EXISTS address_id INTEGER;
+      ALTER TABLE users ADD COLUMN IF NOT EXISTS phone VARCHAR(20);
+      ALTER TABLE users ADD COLUMN IF NOT EXISTS referrer BIGINT;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS phone_required BOOLEAN NOT NULL DEFAULT FALSE;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS stock INTEGER;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS daily_stock INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS stock_date DATE;
+      ALTER TABLE nodes ADD COLUMN IF NOT EXISTS points_rate INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS points INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS tip INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS delivery_fee INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE orders ADD COLUMN IF NOT EXISTS note VARCHAR;
+      ALTER TABLE ticket_items ADD COLUMN IF NOT EXISTS note VARCHAR;
+      ALTER TABLE ticket_items ADD COLUMN IF NOT EXISTS components VARCHAR;
+      ALTER TABLE ticket_items ADD COLUMN IF NOT EXISTS stock_taken INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE ticket_items ADD COLUMN IF NOT EXISTS stock_date DATE;
+
+      DO $$ BEGIN
+         IF NOT EXISTS (SELECT 1 FROM information_schema.columns WHERE table_name = 'ticket_items' AND column_name = 'id') THEN
+            ALTER TABLE ticket_items DROP CONSTRAINT ticket_items_pkey;
+            ALTER TABLE ticket_items ADD COLUMN id SERIAL PRIMARY KEY;
+         END IF;
+      END $$;
+      CREATE INDEX IF NOT EXISTS ticket_items_ticket_id ON ticket_items (ticket_id);
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS comment VARCHAR;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS promo_id INTEGER;
+      ALTER TABLE tickets ADD COLUMN IF NOT EXISTS discount INTEGER NOT NULL DEFAULT 0;
+
+      CREATE TABLE IF NOT EXISTS reviews (
+         PRIMARY KEY (ticket_id),
+         ticket_id      INTEGER        NOT NULL,
+         node_id        INTEGER        NOT NULL,
+         rating         INTEGER        NOT NULL,
+         review         VARCHAR,
+         created        TIMESTAMP      NOT NULL);
+
+      CREATE TABLE IF NOT EXISTS order_comments (
+         PRIMARY KEY (user_id, owner_node_id),
+         user_id        BIGINT         NOT NULL,
+         owner_node_id  INTEGER        NOT NULL,
+         comment        VARCHAR        NOT NULL);
+
+      CREATE TABLE IF NOT EXISTS promo_codes (
+         PRIMARY KEY (id),
+         id             SERIAL,
+         node_id        INTEGER        NOT NULL,
+         code           VARCHAR(20)    NOT NULL,
+         percent        INTEGER        NOT NULL DEFAULT 0,
+         amount         INTEGER        NOT NULL DEFAULT 0,
+         valid_from     DATE,
+         valid_to       DATE,
+         per_user       INTEGER        NOT NULL DEFAULT 0,
+         total          INTEGER        NOT NULL DEFAULT 0,
+         UNIQUE (code));
+
+      CREATE TABLE IF NOT EXISTS order_promos (
+         PRIMARY KEY (user_id, owner_node_id),
+         user_id        BIGINT         NOT NULL,
+         owner_node_id  INTEGER        NOT NULL,
+         promo_id       INTEGER        NOT NULL);
+
+      CREATE TABLE IF NOT EXISTS price_rules (
+         PRIMARY KEY (id),
+         id             SERIAL,
+         node_id        INTEGER        NOT NULL,
+         weekdays       INTEGER        NOT NULL,
+         start          TIME           NOT NULL,
+         finish         TIME           NOT NULL,
+         percent        INTEGER        NOT NULL);
+
+      CREATE TABLE IF NOT EXISTS bundles (
+         PRIMARY KEY (node_id),
+         node_id        INTEGER        NOT NULL,
+         percent        INTEGER        NOT NULL DEFAULT 0);
+
+      CREATE TABLE IF NOT EXISTS bundle_slots (
+         PRIMARY KEY (id),
+         id             SERIAL,
+         bundle_id      INTEGER        NOT NULL,
+         source_id      INTEGER        NOT NULL,
+         UNIQUE (bundle_id, source_id));
+
+      CREATE TABLE IF NOT EXISTS order_points (
+         PRIMARY KEY (user_id, owner_node_id),
+         user_id        BIGINT         NOT NULL,
+         owner_node_id  INTEGER        NOT NULL);
+
+      CREATE TABLE IF NOT EXISTS points (
+         PRIMARY KEY (id),
+         id             SERIAL,
+         user_id        BIGINT         NOT NULL,
+         ticket_id      INTEGER        NOT NULL,
+         kind           CHAR           NOT NULL,
+         amount         INTEGER        NOT NULL,
+         created        TIMESTAMP      NOT NULL,
+         UNIQUE (ticket_id, kind));
+
+      CREATE TABLE IF NOT EXISTS order_components (
+         PRIMARY KEY (user_id, node_id, slot_id),
+         user_id        BIGINT         NOT NULL,
+         node_id        INTEGER        NOT NULL,
+         slot_id        INTEGER        NOT NULL,
+         component_id   INTEGER        NOT NULL);
+
+      CREATE TABLE IF NOT EXISTS addresses (
+         PRIMARY KEY (id),
+         id             SERIAL,
+         user_id        BIGINT         NOT NULL,
+         label          VARCHAR(100)   NOT NULL,
+         address        VARCHAR(100)   NOT NULL,
+         latitude       DOUBLE PRECISION,
+         longitude      DOUBLE PRECISION,
+         live           BOOLEAN        NOT NULL DEFAULT FALSE,
+         UNIQUE (user_id, label));
+
+      CREATE TABLE IF NOT EXISTS ticket_numbers (
+         PRIMARY KEY (node_id, date),
+         node_id        INTEGER        NOT NULL,
+         date           DATE           NOT NULL,
+         number         INTEGER        NOT NULL);
+   ")
+   .await
+   .map_err(|e| format!("{}", e))?;
+
+   Ok(())
+}
+
 
 // Обёртка, возвращает пул клиентов
 async fn db_client() -> Result<Client, String> {
@@ -86,6 +86,18 @@ pub struct Vars {
 
    // Link for open node from /start http://t.me/{bot name} ?start=
    link: String,
+
+   // Minutes before the scheduled time to start the order
+   schedule_lead: i64,
+
+   // Loyalty points to the referrer for the first finished order of the invited user, zero to switch off
+   referral_points: usize,
+
+   // Payments: "telegram", "fake" or empty to switch off
+   payment_provider: String,
+   payment_token: String,
+   payment_currency: String,
+   payment_exponent: Option<u32>, // digits of the minor unit, by the currency if not set
 }
 
 impl Vars {
@@ -218,6 +230,40 @@ impl Vars {
             }
          },
 
+         // Minutes before the scheduled time
+         schedule_lead: {
+            match env::var("SCHEDULE_LEAD") {
+               Ok(s) => match s.parse::<i64>() {
+                     Ok(n) => n,
+                     Err(e) => {
+                        internal_log(chat.clone(), &format!("Something wrong with SCHEDULE_LEAD: {}", e)).await;
+                        30
+                     }
+               }
+               Err(_) => 30 // if the variable is not set, that's ok
+            }
+         },
+
+         // Reward for the invited user
+         referral_points: {
+            match env::var("REFERRAL_POINTS") {
+               Ok(s) => match s.parse::<usize>() {
+                     Ok(n) => n,
+                     Err(e) => {
+                        internal_log(chat.clone(), &format!("Something wrong with REFERRAL_POINTS: {}", e)).await;
+                        0
+                     }
+               }
+               Err(_) => 0 // if the variable is not set, that's ok
+            }
+         },
+
+         // Payments are optional
+         payment_provider: env::var("PAYMENT_PROVIDER").unwrap_or_default(),
+         payment_token: env::var("PAYMENT_TOKEN").unwrap_or_default(),
+         payment_currency: env::var("PAYMENT_CURRENCY").unwrap_or(String::from("USD")),
+         payment_exponent: env::var("PAYMENT_EXPONENT").ok().and_then(|s| s.parse().ok()),
+
          link,
          chat,
       }
@@ -265,6 +311,36 @@ pub fn price_with_unit(price: usize) -> String {
    format!("{}{}", price,  VARS.get().unwrap().price_unit)
 }
 
+// How many minutes before the scheduled time to start the order
+pub fn schedule_lead() -> i64 {
+   VARS.get().unwrap().schedule_lead
+}
+
+// Loyalty points for the first order of the invited user
+pub fn referral_points() -> usize {
+   VARS.get().unwrap().referral_points
+}
+
+// Kind of payment provider, empty if payments are off
+pub fn payment_provider() -> String {
+   VARS.get().unwrap().payment_provider.clone()
+}
+
+// Provider token from BotFather
+pub fn payment_token() -> String {
+   VARS.get().unwrap().payment_token.clone()
+}
+
+// ISO 4217 code of the prices
+pub fn payment_currency() -> String {
+   VARS.get().unwrap().payment_currency.clone()
+}
+
+// Number of digits after the decimal point of the currency, if set
+pub fn payment_exponent() -> Option<u32> {
+   VARS.get().unwrap().payment_exponent
+}
+
 // Advert link
 pub fn link() -> String {
    VARS.get().unwrap().link.clone()
@@ -16,6 +16,9 @@ use crate::states::*;
 use crate::database as db;
 use crate::node::*;
 use crate::environment as env;
+use crate::geo::Geo;
+use crate::promo::Promo;
+use crate::pricing::PriceRule;
 use crate::loc::*;
 
 
@@ -55,10 +58,39 @@ enum EditCmd {
    Owner2,
    #[strum(to_string = "owner3")]
    Owner3,
+   #[strum(to_string = "courier1")]
+   Courier1,
+   #[strum(to_string = "courier2")]
+   Courier2,
+   #[strum(to_string = "courier3")]
+   Courier3,
    #[strum(to_string = "time")] // really in db there open and close fields
    Time,
    #[strum(to_string = "price")]
    Price,
+   #[strum(to_string = "min_order")]
+   MinOrder,
+   #[strum(to_string = "delivery_fee")]
+   DeliveryFee,
+   #[strum(to_string = "free_delivery")]
+   FreeDelivery,
+   #[strum(to_string = "zone")] // really in db there zone_lat and zone_lon fields
+   Zone,
+   #[strum(to_string = "zone_radius")]
+   ZoneRadius,
+   #[strum(to_string = "km_fee")]
+   KmFee,
+   #[strum(to_string = "phone_required")]
+   Phone,
+   #[strum(to_string = "stock")]
+   Stock,
+   #[strum(to_string = "daily_stock")]
+   DailyStock,
+   #[strum(to_string = "points_rate")]
+   PointsRate,
+   Promo, // separate table
+   Prices, // separate table
+   Bundle, // separate table
 }
 
 impl Command {
@@ -78,8 +110,24 @@ impl Command {
       else if s == loc(Key::GearEditOwner1, tag, &[]) { Self::Edit(EditCmd::Owner1) }
       else if s == loc(Key::GearEditOwner2, tag, &[]) { Self::Edit(EditCmd::Owner2) }
       else if s == loc(Key::GearEditOwner3, tag, &[]) { Self::Edit(EditCmd::Owner3) }
+      else if s == loc(Key::GearEditCourier1, tag, &[]) { Self::Edit(EditCmd::Courier1) }
+      else if s == loc(Key::GearEditCourier2, tag, &[]) { Self::Edit(EditCmd::Courier2) }
+      else if s == loc(Key::GearEditCourier3, tag, &[]) { Self::Edit(EditCmd::Courier3) }
       else if s == loc(Key::GearEditTime, tag, &[]) { Self::Edit(EditCmd::Time) }
       else if s == loc(Key::GearEditPrice, tag, &[]) { Self::Edit(EditCmd::Price) }
+      else if s == loc(Key::GearEditMinOrder, tag, &[]) { Self::Edit(EditCmd::MinOrder) }
+      else if s == loc(Key::GearEditDeliveryFee, tag, &[]) { Self::Edit(EditCmd::DeliveryFee) }
+      else if s == loc(Key::GearEditFreeDelivery, tag, &[]) { Self::Edit(EditCmd::FreeDelivery) }
+      else if s == loc(Key::GearEditZone, tag, &[]) { Self::Edit(EditCmd::Zone) }
+      else if s == loc(Key::GearEditZoneRadius, tag, &[]) { Self::Edit(EditCmd::ZoneRadius) }
+      else if s == loc(Key::GearEditKmFee, tag, &[]) { Self::Edit(EditCmd::KmFee) }
+      else if s == loc(Key::GearEditPhone, tag, &[]) { Self::Edit(EditCmd::Phone) }
+      else if s == loc(Key::GearEditStock, tag, &[]) { Self::Edit(EditCmd::Stock) }
+      else if s == loc(Key::GearEditDailyStock, tag, &[]) { Self::Edit(EditCmd::DailyStock) }
+      else if s == loc(Key::GearEditPointsRate, tag, &[]) { Self::Edit(EditCmd::PointsRate) }
+      else if s == loc(Key::GearEditPromo, tag, &[]) { Self::Edit(EditCmd::Promo) }
+      else if s == loc(Key::GearEditPrices, tag, &[]) { Self::Edit(EditCmd::Prices) }
+      else if s == loc(Key::GearEditBundle, tag, &[]) { Self::Edit(EditCmd::Bundle) }
       else {
          // Looking for the commands with arguments
          if s.get(..4).unwrap_or_default() == GOTO {
@@ -274,8 +322,24 @@ pub async fn update(bot: Bot, msg: Message, dialogue: MyDialogue, state: GearSta
             EditCmd::Owner1 => UpdateKind::User(node.owners.0),
             EditCmd::Owner2 => UpdateKind::User(node.owners.1),
             EditCmd::Owner3 => UpdateKind::User(node.owners.2),
+            EditCmd::Courier1 => UpdateKind::User(node.couriers.0),
+            EditCmd::Courier2 => UpdateKind::User(node.couriers.1),
+            EditCmd::Courier3 => UpdateKind::User(node.couriers.2),
             EditCmd::Time => UpdateKind::Time(node.time.0, node.time.1),
             EditCmd::Price => UpdateKind::Money(node.price),
+            EditCmd::MinOrder => UpdateKind::Money(node.delivery.min_order),
+            EditCmd::DeliveryFee => UpdateKind::Money(node.delivery.fee),
+            EditCmd::FreeDelivery => UpdateKind::Money(node.delivery.free_from),
+            EditCmd::Zone => UpdateKind::Location(node.delivery.zone),
+            EditCmd::ZoneRadius => UpdateKind::Money(node.delivery.radius),
+            EditCmd::KmFee => UpdateKind::Money(node.delivery.km_fee),
+            EditCmd::Phone => UpdateKind::Flag(node.phone_required),
+            EditCmd::Stock => UpdateKind::Stock(node.stock),
+            EditCmd::DailyStock => UpdateKind::Money(node.daily_stock),
+            EditCmd::PointsRate => UpdateKind::Money(node.points_rate),
+            EditCmd::Promo => return enter_promo(bot, msg, dialogue, state).await,
+            EditCmd::Prices => return enter_prices(bot, msg, dialogue, state).await,
+            EditCmd::Bundle => return enter_bundle(bot, msg, dialogue, state).await,
          };
 
          // Appropriate database field name
@@ -303,7 +367,7 @@ pub async fn update(bot: Bot, msg: Message, dialogue: MyDialogue, state: GearSta
 }
 
 
-async fn view(bot: Bot, msg: Message, state: &GearState) -> HandlerResult {
+pub async fn view(bot: Bot, msg: Message, state: &GearState) -> HandlerResult {
    let tag = state.prev_state.tag;
 
    // Collect path from the beginning
@@ -324,6 +388,38 @@ async fn view(bot: Bot, msg: Message, state: &GearState) -> HandlerResult {
       title = format!("{}\n{}: {}", title, EditCmd::Price.as_ref(), env::price_with_unit(price));
    }
 
+   // Add own delivery terms, unset ones are inherited from parents
+   let terms = [
+      (Key::GearEditMinOrder, node.delivery.min_order),
+      (Key::GearEditDeliveryFee, node.delivery.fee),
+      (Key::GearEditFreeDelivery, node.delivery.free_from),
+      (Key::GearEditKmFee, node.delivery.km_fee),
+   ];
+   title = terms.into_iter()
+   .filter(|(_, value)| *value > 0)
+   .fold(title, |acc, (key, value)| format!("{}\n{}: {}", acc, loc(key, tag, &[]), env::price_with_unit(value)));
+
+   if node.phone_required {
+      title = format!("{}\n{}: {}", title, loc(Key::GearEditPhone, tag, &[]), from_flag(true, tag));
+   }
+
+   if let Some(stock) = node.stock {
+      title = format!("{}\n{}: {}", title, loc(Key::GearEditStock, tag, &[]), stock);
+   }
+   if node.daily_stock > 0 {
+      title = format!("{}\n{}: {}", title, loc(Key::GearEditDailyStock, tag, &[]), node.daily_stock);
+   }
+   if node.points_rate > 0 {
+      title = format!("{}\n{}: {}", title, loc(Key::GearEditPointsRate, tag, &[]), node.points_rate);
+   }
+
+   if let Some(zone) = node.delivery.zone {
+      title = format!("{}\n{}: {}, {}: {}", title,
+         loc(Key::GearEditZone, tag, &[]), zone,
+         loc(Key::GearEditZoneRadius, tag, &[]), node.delivery.radius
+      );
+   }
+
    // Add other info
    let tf = loc(Key::CommonTimeFormat, tag, &[]);
    title = format!("{}\n{}: {}, {}: {}\n{}: {}-{}\n{}: {}",
@@ -370,6 +466,12 @@ fn markup(state: &GearState, tag: LocaleTag) -> ReplyMarkup {
    let mut row3 = vec![
       loc(Key::GearExit, tag, &[]),
    ];
+   let row4 = vec![
+      loc(Key::GearEditCourier1, tag, &[]),
+      loc(Key::GearEditCourier2, tag, &[]),
+      loc(Key::GearEditCourier3, tag, &[]),
+      loc(Key::GearEditBundle, tag, &[]),
+   ];
 
    // Condition-dependent menu items
    if state.stack.len() > 1 {
@@ -378,7 +480,31 @@ fn markup(state: &GearState, tag: LocaleTag) -> ReplyMarkup {
       row3.push(loc(Key::GearReturn, tag, &[]));
    }
 
-   let mut keyboard = vec![row1, row2, row3];
+   let row5 = vec![
+      loc(Key::GearEditMinOrder, tag, &[]),
+      loc(Key::GearEditDeliveryFee, tag, &[]),
+      loc(Key::GearEditFreeDelivery, tag, &[]),
+      loc(Key::GearEditPhone, tag, &[]),
+      loc(Key::GearEditPointsRate, tag, &[]),
+   ];
+   let row6 = vec![
+      loc(Key::GearEditZone, tag, &[]),
+      loc(Key::GearEditZoneRadius, tag, &[]),
+      loc(Key::GearEditKmFee, tag, &[]),
+      loc(Key::GearEditPromo, tag, &[]),
+      loc(Key::GearEditPrices, tag, &[]),
+   ];
+
+   let mut keyboard = vec![row1, row2, row4, row5, row6];
+
+   // Stock is counted for the items with price
+   if state.stack.len() > 1 {
+      keyboard.push(vec![
+         loc(Key::GearEditStock, tag, &[]),
+         loc(Key::GearEditDailyStock, tag, &[]),
+      ]);
+   }
+   keyboard.push(row3);
 
    if state.prev_state.is_admin {
       let row_admin = vec![
@@ -486,7 +612,7 @@ async fn send_advert(bot: Bot, msg: Message, state: GearState) -> HandlerResult
 // ============================================================================
 #[derive(Clone)]
 pub struct GearStateEditing {
-   prev_state: GearState,
+   pub prev_state: GearState,
    update: UpdateNode,
 }
 
@@ -543,6 +669,28 @@ pub async fn update_edit(bot: Bot, msg: Message, dialogue: MyDialogue, state: Ge
                   return Ok(text)
                }
             }
+            UpdateKind::Stock(_) => {
+               if input == "-" {
+                  UpdateKind::Stock(None)
+               } else if let Ok(int) = input.parse::<usize>() {
+                  UpdateKind::Stock(Some(int))
+               } else {
+                  // "Error, unable to convert '{}' to number, value not changed"
+                  let text = loc(Key::GearUpdateEdit1, tag, &[&input]);
+                  return Ok(text)
+               }
+            }
+            UpdateKind::Location(_) => {
+               if input == "-" {
+                  UpdateKind::Location(None)
+               } else if let Some(geo) = Geo::parse(&input) {
+                  UpdateKind::Location(Some(geo))
+               } else {
+                  // "Error, unable to recognize '{}' as a point on the map, value not changed"
+                  let text = loc(Key::GearUpdateEdit3, tag, &[&input]);
+                  return Ok(text)
+               }
+            }
          };
 
          // Peek current node
@@ -589,6 +737,10 @@ pub async fn update_edit(bot: Bot, msg: Message, dialogue: MyDialogue, state: Ge
             loc(Key::CommonCancel, tag, &[]) // "/"
          }
       }
+      UpdateKind::Location(_) if msg.location().is_some() => {
+         let l = msg.location().unwrap();
+         Geo::new(l.latitude, l.longitude).to_string()
+      }
       _ => msg.text().unwrap_or(&loc(Key::CommonCancel, tag, &[])).to_string(),
    };
 
@@ -676,7 +828,244 @@ async fn enter_edit(bot: Bot, msg: Message, state: &GearStateEditing) -> Handler
          let text = loc(Key::GearEnterEdit3, tag, &[&old_val]);
          do_enter(bot, chat_id, text, cancel_markup(tag)).await?
       }
+      UpdateKind::Stock(old_val) => {
+         let old_val = old_val.map(|s| s.to_string()).unwrap_or(String::from("-"));
+         // "Current value '{}', enter the number of items left, '-' to stop counting or / to cancel"
+         let text = loc(Key::GearEnterEdit7, tag, &[&old_val]);
+         do_enter(bot, chat_id, text, cancel_markup(tag)).await?
+      }
+      UpdateKind::Location(old_val) => {
+         let old_val = old_val.map(|g| g.to_string()).unwrap_or(String::from("-"));
+         // "Current value '{}', send the center of the delivery zone with the paperclip 📎 or as coordinates like '55.75, 37.61', '-' to remove or / to cancel"
+         let text = loc(Key::GearEnterEdit6, tag, &[&old_val]);
+         do_enter(bot, chat_id, text, cancel_markup(tag)).await?
+      }
    }
 
    Ok(())
 }
+
+// ============================================================================
+// [Promo codes editing mode]
+// ============================================================================
+#[derive(Clone)]
+pub struct GearStatePromo {
+   pub prev_state: GearState,
+}
+
+async fn enter_promo(bot: Bot, msg: Message, dialogue: MyDialogue, state: GearState) -> HandlerResult {
+   let tag = state.prev_state.tag;
+   let node = state.stack.last().unwrap();
+   let promos = db::promos(node.id).await?;
+
+   let list = if promos.is_empty() {
+      // " none"
+      loc(Key::GearEnterPromo2, tag, &[])
+   } else {
+      promos.iter()
+      .fold(String::default(), |acc, p| format!("{}\n{}", acc, p.describe(tag)))
+   };
+
+   // "Promo codes:{}\n\nEnter a new code like 'SUMMER 10% 01.06.2026-31.08.2026 1/100'..."
+   let text = loc(Key::GearEnterPromo1, tag, &[&list]);
+   bot.send_message(msg.chat.id, text)
+   .reply_markup(cancel_markup(tag))
+   .await?;
+
+   dialogue.update(GearStatePromo { prev_state: state }).await?;
+   Ok(())
+}
+
+pub async fn update_promo(bot: Bot, msg: Message, dialogue: MyDialogue, state: GearStatePromo) -> HandlerResult {
+   let tag = state.prev_state.prev_state.tag;
+   let node_id = state.prev_state.stack.last().unwrap().id;
+   let input = msg.text().unwrap_or_default().trim();
+
+   let text = if input.is_empty() || input == loc(Key::CommonCancel, tag, &[]) {
+      // "Cancel, value not changed"
+      loc(Key::CommonEditCancel, tag, &[])
+   } else if let Some(code) = input.strip_suffix(" -") {
+      let code = Promo::normalize(code);
+      if db::promo_delete(node_id, &code).await? {
+         // "Promo code {} removed"
+         loc(Key::GearUpdatePromo3, tag, &[&code])
+      } else {
+         // "Promo code {} not found, nothing changed"
+         loc(Key::GearUpdatePromo4, tag, &[&code])
+      }
+   } else {
+      match Promo::parse(input, node_id, tag) {
+         Some(promo) => {
+            if db::promo_save(&promo).await? {
+               // "New value saved"
+               loc(Key::CommonEditConfirm, tag, &[])
+            } else {
+               // "The code {} is already used by another place, nothing changed"
+               loc(Key::GearUpdatePromo2, tag, &[&promo.code])
+            }
+         }
+         // "Error, unable to recognize '{}' as a promo code, nothing changed"
+         None => loc(Key::GearUpdatePromo1, tag, &[&input]),
+      }
+   };
+
+   bot.send_message(msg.chat.id, text).await?;
+
+   // Back to the node
+   view(bot, msg, &state.prev_state).await?;
+   dialogue.update(state.prev_state).await?;
+   Ok(())
+}
+
+// ============================================================================
+// [Price rules editing mode]
+// ============================================================================
+#[derive(Clone)]
+pub struct GearStatePrices {
+   pub prev_state: GearState,
+}
+
+async fn enter_prices(bot: Bot, msg: Message, dialogue: MyDialogue, state: GearState) -> HandlerResult {
+   let tag = state.prev_state.tag;
+   let node = state.stack.last().unwrap();
+   let rules = db::price_rules(node.id).await?;
+
+   let list = if rules.is_empty() {
+      // " none"
+      loc(Key::GearEnterPromo2, tag, &[])
+   } else {
+      rules.iter()
+      .fold(String::default(), |acc, r| format!("{}\n{}", acc, r.describe(tag)))
+   };
+
+   // "Price discounts for this node and its children:{}\n\nEnter a new rule like '14:00-17:00 20% 12345'..."
+   let text = loc(Key::GearEnterPrices1, tag, &[&list]);
+   bot.send_message(msg.chat.id, text)
+   .reply_markup(cancel_markup(tag))
+   .await?;
+
+   dialogue.update(GearStatePrices { prev_state: state }).await?;
+   Ok(())
+}
+
+pub async fn update_prices(bot: Bot, msg: Message, dialogue: MyDialogue, state: GearStatePrices) -> HandlerResult {
+   let tag = state.prev_state.prev_state.tag;
+   let node_id = state.prev_state.stack.last().unwrap().id;
+   let input = msg.text().unwrap_or_default().trim();
+
+   let text = if input.is_empty() || input == loc(Key::CommonCancel, tag, &[]) {
+      // "Cancel, value not changed"
+      loc(Key::CommonEditCancel, tag, &[])
+   } else if let Some(id) = input.strip_suffix(" -") {
+      let id = id.trim().parse().unwrap_or_default();
+      if db::price_rule_delete(node_id, id).await? {
+         // "Rule {} removed"
+         loc(Key::GearUpdatePrices2, tag, &[&id])
+      } else {
+         // "Rule {} not found, nothing changed"
+         loc(Key::GearUpdatePrices3, tag, &[&id])
+      }
+   } else {
+      match PriceRule::parse(input, node_id, tag) {
+         Some(rule) => {
+            db::price_rule_insert(&rule).await?;
+            // "New value saved"
+            loc(Key::CommonEditConfirm, tag, &[])
+         }
+         // "Error, unable to recognize '{}' as a price rule, nothing changed"
+         None => loc(Key::GearUpdatePrices1, tag, &[&input]),
+      }
+   };
+
+   bot.send_message(msg.chat.id, text).await?;
+
+   // Back to the node
+   view(bot, msg, &state.prev_state).await?;
+   dialogue.update(state.prev_state).await?;
+   Ok(())
+}
+
+// ============================================================================
+// [Bundle editing mode]
+// ============================================================================
+#[derive(Clone)]
+pub struct GearStateBundle {
+   pub prev_state: GearState,
+}
+
+async fn enter_bundle(bot: Bot, msg: Message, dialogue: MyDialogue, state: GearState) -> HandlerResult {
+   let tag = state.prev_state.tag;
+   let node = state.stack.last().unwrap();
+
+   let list = match db::bundle(node.id, state.prev_state.user_id.0).await? {
+      Some(bundle) => {
+         let list = bundle.slots.iter()
+         .fold(String::default(), |acc, slot| {
+            let choices = slot.source.children.iter()
+            .map(|item| item.title.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+            format!("{}\n{} {}: {}", acc, slot.source.id, slot.source.title, choices)
+         });
+
+         if bundle.percent > 0 {
+            // "\nDiscount {}% from the sum of the chosen items"
+            list + &loc(Key::GearEnterBundle2, tag, &[&bundle.percent])
+         } else {
+            list
+         }
+      }
+      // " none"
+      None => loc(Key::GearEnterPromo2, tag, &[]),
+   };
+
+   // "Set of items with choice:{}\n\nEnter the id of the node whose children are the choices to add a slot..."
+   let text = loc(Key::GearEnterBundle1, tag, &[&list]);
+   bot.send_message(msg.chat.id, text)
+   .reply_markup(cancel_markup(tag))
+   .await?;
+
+   dialogue.update(GearStateBundle { prev_state: state }).await?;
+   Ok(())
+}
+
+pub async fn update_bundle(bot: Bot, msg: Message, dialogue: MyDialogue, state: GearStateBundle) -> HandlerResult {
+   let tag = state.prev_state.prev_state.tag;
+   let node_id = state.prev_state.stack.last().unwrap().id;
+   let input = msg.text().unwrap_or_default().trim();
+
+   let text = if input.is_empty() || input == loc(Key::CommonCancel, tag, &[]) {
+      // "Cancel, value not changed"
+      loc(Key::CommonEditCancel, tag, &[])
+   } else if let Some(percent) = input.strip_suffix('%').and_then(|p| p.trim().parse().ok()).filter(|p| *p < 100) {
+      db::bundle_update_percent(node_id, percent).await?;
+      // "New value saved"
+      loc(Key::CommonEditConfirm, tag, &[])
+   } else if let Some(source_id) = input.strip_suffix(" -").and_then(|id| id.trim().parse::<i32>().ok()) {
+      if db::bundle_slot_delete(node_id, source_id).await? {
+         // "Slot with the node {} removed"
+         loc(Key::GearUpdateBundle2, tag, &[&source_id])
+      } else {
+         // "Slot with the node {} not found in this place or already exists, nothing changed"
+         loc(Key::GearUpdateBundle3, tag, &[&source_id])
+      }
+   } else if let Ok(source_id) = input.parse::<i32>() {
+      if db::bundle_slot_insert(node_id, source_id).await? {
+         // "New value saved"
+         loc(Key::CommonEditConfirm, tag, &[])
+      } else {
+         // "Slot with the node {} not found in this place or already exists, nothing changed"
+         loc(Key::GearUpdateBundle3, tag, &[&source_id])
+      }
+   } else {
+      // "Error, unable to recognize '{}', nothing changed"
+      loc(Key::GearUpdateBundle1, tag, &[&input])
+   };
+
+   bot.send_message(msg.chat.id, text).await?;
+
+   // Back to the node
+   view(bot, msg, &state.prev_state).await?;
+   dialogue.update(state.prev_state).await?;
+   Ok(())
+}
@@ -19,17 +19,26 @@ pub enum Command {
    #[strum(to_string = "/start")]
    Start,
    #[strum(to_string = "/start ")]
-   StartFrom(i32),
+   StartFrom(i32, Option<u64>), // node to open and the user who shared the link
    #[strum(to_string = "/msg")]
    Message(ChatId),
    #[strum(to_string = "/get")]
    Goto(i32),
+   #[strum(to_string = "#")]
+   FindTicket, // order by the daily number like "#12-017"
+   #[strum(to_string = "/pay")]
+   Payments, // reconciliation of the online payments
+   #[strum(to_string = "/ref")]
+   Referrals, // link to invite friends or the report for admins
    Unknown,
 }
 
 impl Command {
    fn parse(s: &str) -> Self {
       if s == Self::Start.as_ref() { Command::Start }
+      else if s == Self::Payments.as_ref() { Command::Payments }
+      else if s == Self::Referrals.as_ref() { Command::Referrals }
+      else if s.starts_with(Self::FindTicket.as_ref()) { Command::FindTicket }
       else {
          // Looking for the commands with arguments
          let l_part = s.get(..4).unwrap_or_default();
@@ -43,9 +52,13 @@ impl Command {
          } else {
             // More long command
             let l_part = s.get(..7).unwrap_or_default();
-            if l_part == Self::StartFrom(0).as_ref() {
+            if l_part == Self::StartFrom(0, None).as_ref() {
+               // Payload like "12" or "12_345" with the referrer, see referral::link()
                let r_part = s.get(7..).unwrap_or_default();
-               Command::StartFrom(r_part.parse().unwrap_or_default())
+               match r_part.split_once('_') {
+                  Some((node_id, referrer)) => Command::StartFrom(node_id.parse().unwrap_or_default(), referrer.parse().ok()),
+                  None => Command::StartFrom(r_part.parse().unwrap_or_default(), None),
+               }
             } else {
                Command::Unknown
             }
@@ -54,6 +67,14 @@ impl Command {
    }
 }
 
+// The user who shared the link to the bot
+pub fn referrer(s: &str) -> Option<u64> {
+   match Command::parse(s) {
+      Command::StartFrom(_, referrer) => referrer,
+      _ => None,
+   }
+}
+
 #[derive(Clone)]
 pub struct MessageState {
    pub prev_state: MainState,
@@ -69,19 +90,26 @@ pub async fn update(bot: Bot, msg: Message, dialogue: MyDialogue, state: MainSta
    let input = msg.text().unwrap_or_default();
    let cmd = Command::parse(input);
    match cmd {
-      Command::Start => {
+      Command::Start
+      | Command::StartFrom(0, Some(_)) => {
          // "Welcome. Please click on 'All' to display the full list, 'Open' for those currently working (if the panel with buttons is hidden, expand it), or send a text to search."
          let text = loc(Key::GeneralUpdate1, tag, &[]);
          bot.send_message(chat_id, text)
-         .reply_markup(main_menu_markup(tag))
+         .reply_markup(main_menu_markup(state.is_staff, tag))
          .await?;
       }
       
       Command::Message(receiver) => return enter_input(bot, msg, dialogue, state, receiver).await,
       
       Command::Goto(node_id)
-      | Command::StartFrom(node_id) => return crate::navigation::enter(bot, msg, state, WorkTime::AllFrom(node_id)).await,
+      | Command::StartFrom(node_id, _) => return crate::navigation::enter(bot, msg, state, WorkTime::AllFrom(node_id)).await,
       
+      Command::FindTicket => crate::registration::find_ticket(&bot, state.user_id, input, tag).await?,
+
+      Command::Payments => crate::payment::report(&bot, state.user_id, tag).await?,
+
+      Command::Referrals => crate::referral::report(&bot, state.user_id, tag).await?,
+
       Command::Unknown => {
          let text = if input.is_empty() {
             // Text message please
@@ -112,7 +140,7 @@ pub async fn update(bot: Bot, msg: Message, dialogue: MyDialogue, state: MainSta
          };
 
          bot.send_message(chat_id, text)
-         .reply_markup(main_menu_markup(tag))
+         .reply_markup(main_menu_markup(state.is_staff, tag))
          .parse_mode(ParseMode::Html)
          .await?;
       },
@@ -167,7 +195,7 @@ pub async fn update_input(bot: Bot, msg: Message, dialogue: MyDialogue, state: M
 
    // Report result and return to main menu
    bot.send_message(chat_id, info)
-   .reply_markup(main_menu_markup(tag))
+   .reply_markup(main_menu_markup(state.prev_state.is_staff, tag))
    .await?;
 
    // Return to previous state
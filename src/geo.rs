@@ -0,0 +1,103 @@
+/* ===============================================================================
+Restaurant menu bot.
+Geographic point and distances. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use std::fmt;
+
+const EARTH_RADIUS: f64 = 6_371_000.0; // meters
+
+#[derive(Debug, Clone, Copy)]
+pub struct Geo {
+   pub latitude: f64,
+   pub longitude: f64,
+}
+
+impl Geo {
+   pub fn new(latitude: f64, longitude: f64) -> Self {
+      Self { latitude, longitude }
+   }
+
+   pub fn from_opt(latitude: Option<f64>, longitude: Option<f64>) -> Option<Self> {
+      match (latitude, longitude) {
+         (Some(latitude), Some(longitude)) => Some(Self::new(latitude, longitude)),
+         _ => None,
+      }
+   }
+
+   // Text like "55.75, 37.61" or None
+   pub fn parse(s: &str) -> Option<Self> {
+      let mut parts = s.split(|c: char| c == ',' || c.is_whitespace())
+      .filter(|p| !p.is_empty())
+      .map(|p| p.parse::<f64>());
+
+      match (parts.next(), parts.next(), parts.next()) {
+         (Some(Ok(latitude)), Some(Ok(longitude)), None)
+         if latitude.abs() <= 90.0 && longitude.abs() <= 180.0 => Some(Self::new(latitude, longitude)),
+         _ => None,
+      }
+   }
+
+   // Great-circle distance in meters by haversine formula
+   pub fn distance(&self, other: &Geo) -> f64 {
+      let lat1 = self.latitude.to_radians();
+      let lat2 = other.latitude.to_radians();
+      let dlat = lat2 - lat1;
+      let dlon = (other.longitude - self.longitude).to_radians();
+
+      let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+      2.0 * EARTH_RADIUS * a.sqrt().asin()
+   }
+}
+
+impl fmt::Display for Geo {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "{:.5}, {:.5}", self.latitude, self.longitude)
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn parse_separators() {
+      for s in ["55.75, 37.61", "55.75,37.61", "55.75 37.61", "  55.75 ,  37.61  "] {
+         let geo = Geo::parse(s).unwrap();
+         assert_eq!(geo.latitude, 55.75);
+         assert_eq!(geo.longitude, 37.61);
+      }
+      assert!(Geo::parse("-33.87, 151.21").is_some());
+   }
+
+   #[test]
+   fn parse_rejects() {
+      assert!(Geo::parse("").is_none());
+      assert!(Geo::parse("55.75").is_none());
+      assert!(Geo::parse("55.75, 37.61, 1").is_none());
+      assert!(Geo::parse("55.75, east").is_none());
+      assert!(Geo::parse("90.1, 37.61").is_none());
+      assert!(Geo::parse("55.75, -180.5").is_none());
+      assert!(Geo::parse("NaN, 37.61").is_none());
+      assert!(Geo::parse("55.75, inf").is_none());
+   }
+
+   #[test]
+   fn distance_known() {
+      // Moscow - Saint Petersburg, about 634 km
+      let msk = Geo::new(55.7558, 37.6173);
+      let spb = Geo::new(59.9343, 30.3351);
+      let d = msk.distance(&spb);
+      assert!((d - 634_000.0).abs() < 5_000.0, "{}", d);
+      assert!((d - spb.distance(&msk)).abs() < 1e-6);
+      assert_eq!(msk.distance(&msk), 0.0);
+
+      // A degree of the meridian is about 111.2 km
+      let d = Geo::new(0.0, 0.0).distance(&Geo::new(1.0, 0.0));
+      assert!((d - 111_195.0).abs() < 10.0, "{}", d);
+   }
+}
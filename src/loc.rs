@@ -19,6 +19,8 @@ pub static LOC: OnceCell<Locale> = OnceCell::new();
 #[derive(AsRefStr)]
 pub enum Key {
    CommonTimeFormat,
+   CommonDateTimeFormat,
+   CommonDateFormat,
    CommonCancel,
    CommonEditCancel,
    CommonEditConfirm,
@@ -33,11 +35,23 @@ pub enum Key {
    CartView1,
    CartView2,
    CartView3,
+   CartView4,
    CartUpdate,
    CartMakeOwnerText1,
    CartMakeOwnerText2,
    CartMakeOwnerText4,
+   CartMakeOwnerText5,
+   CartMakeOwnerText6,
+   CartMakeOwnerText7,
+   CartMakeOwnerText8,
+   CartMakeOwnerText9,
+   CartMakeOwnerText10,
+   CartMakeOwnerText11,
+   CartMakeOwnerText12,
+   CartMakeOwnerText13,
    CartOrderMarkup,
+   CartOrderMarkupLater,
+   CartOrderMarkupAddress,
    CartEnterEdit1,
    CartEnterEdit2,
    CartEnterEdit3,
@@ -45,16 +59,45 @@ pub enum Key {
    CartEnterEdit5,
    CartEnterEdit6,
    CartEnterEdit7,
+   CartEnterEdit8,
+   CartEnterEdit9,
+   CartEnterEdit10,
+   CartEnterEdit11,
+   CartEnterEdit12,
    CartUpdateEdit,
+   CartUpdateEditAddress,
+   CartUpdateEditPhone,
+   CartUpdateEditPromo1,
+   CartUpdateEditPromo2,
+   CartUpdateEditPromo3,
    CartAddressMarkup,
+   CartAddressLabel1,
+   CartAddressLabel2,
+   CartContactMarkup,
 
    CallbackCancel,
    CallbackNext,
    CallbackConfirm,
    CallbackAdded,
    CallbackRemoved,
+   CallbackChosen,
+   CallbackChoose,
+   CallbackSoldOut,
    CallbackAll,
    CallbackOpen,
+   CallbackEdit,
+   CallbackDiscard,
+   CallbackPropose,
+   CallbackAccept,
+   CallbackChat1,
+   CallbackChat2,
+   CallbackChat3,
+   CallbackTake,
+   CallbackReview,
+   CallbackTipOther,
+   CallbackPay,
+   CallbackRefunded,
+   CallbackRefresh,
 
    CustomerDeliveryCourier,
    CustomerDeliveryPickup,
@@ -62,6 +105,16 @@ pub enum Key {
    CustomerDeliveryDesk2,
    CustomerDeliveryDesk3,
    CustomerDeliveryDesk4,
+   CustomerDeliveryDesk5,
+   CustomerContactDesc,
+
+   ChatEnter1,
+   ChatEnter2,
+   ChatEnter3,
+   ChatUpdate1,
+   ChatUpdate2,
+   ChatUpdate3,
+   ChatUpdate4,
 
    GearAdd,
    GearDelete,
@@ -76,8 +129,24 @@ pub enum Key {
    GearEditOwner1,
    GearEditOwner2,
    GearEditOwner3,
+   GearEditCourier1,
+   GearEditCourier2,
+   GearEditCourier3,
    GearEditTime,
    GearEditPrice,
+   GearEditMinOrder,
+   GearEditDeliveryFee,
+   GearEditFreeDelivery,
+   GearEditZone,
+   GearEditZoneRadius,
+   GearEditKmFee,
+   GearEditPointsRate,
+   GearEditPhone,
+   GearEditPromo,
+   GearEditPrices,
+   GearEditBundle,
+   GearEditStock,
+   GearEditDailyStock,
    GearEnter,
    GearUpdateGoto,
    GearUpdateDelete1,
@@ -90,11 +159,29 @@ pub enum Key {
    GearSendAdvert,
    GearUpdateEdit1,
    GearUpdateEdit2,
+   GearUpdateEdit3,
    GearEnterEdit1,
    GearEnterEdit2,
    GearEnterEdit3,
    GearEnterEdit4,
+   GearEnterPromo1,
+   GearEnterPromo2,
+   GearUpdatePromo1,
+   GearUpdatePromo2,
+   GearUpdatePromo3,
+   GearUpdatePromo4,
+   GearEnterPrices1,
+   GearUpdatePrices1,
+   GearUpdatePrices2,
+   GearUpdatePrices3,
+   GearEnterBundle1,
+   GearEnterBundle2,
+   GearUpdateBundle1,
+   GearUpdateBundle2,
+   GearUpdateBundle3,
    GearEnterEdit5,
+   GearEnterEdit6,
+   GearEnterEdit7,
 
    GeneralUpdate1,
    GeneralUpdate2,
@@ -115,13 +202,52 @@ pub enum Key {
    NavigationView3,
    NavigationNodeText1,
    NavigationNodeText2,
+   NavigationNodeText3,
+   NavigationNodeText4,
+   NavigationNodeText5,
+   NavigationNodeText6,
+   NavigationNodeText7,
+   PaymentInvoice1,
+   PaymentInvoice2,
+   PaymentInvoice3,
+   PaymentInvoice4,
+   PaymentInvoice5,
+   PaymentFake1,
+   PaymentFake2,
+   PaymentPreCheckout,
+   PaymentComplete1,
+   PaymentComplete2,
+   PaymentRefund1,
+   PaymentRefund2,
+   PaymentRefunded1,
+   PaymentRefunded2,
+   PaymentReport1,
+   PaymentReport2,
+   PaymentReport3,
+   PaymentReport4,
+   PaymentReport5,
+   ReferralReport1,
+   ReferralReport2,
+   ReferralReport3,
+   ReferralReport4,
+   ReferralReport5,
+   ReferralReward,
    NavigationMarkup1,
    NavigationMarkup2,
    NavigationMarkup3,
+   NavigationMarkup5,
    NavigationMarkup4,
 
    NodeDefName,
 
+   QueueCheckOwner,
+   QueueText1,
+   QueueText2,
+   QueueText3,
+   QueueText4,
+   QueueText5,
+   QueueText6,
+
    RegUpdateStatus,
    RegMakeTicket1,
    RegMakeTicket2,
@@ -130,12 +256,70 @@ pub enum Key {
    RegMakeTicket5,
    RegMakeTicket6,
    RegMakeTicket7,
+   RegMakeTicket8,
+   RegMakeTicket9,
+   RegMakeTicket10,
+   RegMakeTicket11,
+   RegMakeTicket12,
+   RegMakeTicket13,
+   RegMakeTicket14,
+   RegMakeTicket15,
    RegConfirmTicket,
+   RegConfirmTicket2,
+   RegCheckOrders1,
+   RegCheckOrders2,
+   RegCheckOrders3,
+   RegCheckOrders4,
+   RegEditTicket1,
+   RegEditTicket2,
+   RegEditTicket3,
+   RegEditTicket4,
+   RegEditTicket5,
+   RegEditTicket6,
+   RegEditTicket7,
+   RegEditTicket8,
+   RegEditTicket9,
+   RegAcceptChanges1,
+   RegAcceptChanges2,
+   RegAcceptChanges3,
+   RegAcceptChanges4,
+   RegSetEta,
+   RegRemindEta,
+   RegOfferDelivery,
+   RegTakeDelivery1,
+   RegTakeDelivery2,
+   RegTakeDelivery3,
+   RegTakeDelivery4,
+   RegTakeDelivery5,
+   RegTakeDelivery6,
+   ReviewAsk,
+   ReviewRate1,
+   ReviewRate2,
+   ReviewRate3,
+   ReviewEnter,
+   ReviewUpdate1,
+   ReviewUpdate2,
+   ReviewUpdate3,
+   TipAsk,
+   TipChoose1,
+   TipChoose2,
+   TipChoose3,
+   TipChoose4,
+   TipEnter,
+   TipUpdate1,
+   TipUpdate2,
+   TipUpdate3,
+   RegFindTicket,
+   RegSelectTime1,
+   RegSelectTime2,
+   RegStartScheduled,
+   RegNextAddress,
 
    StatesMainMenuGear,
    StatesMainMenuCart,
    StatesMainMenuAll,
    StatesMainMenuOpen,
+   StatesMainMenuOrders,
    StatesCallback,
    StatesMainMenu,
    StatesBotRestarted,
@@ -157,7 +341,20 @@ pub enum Key {
    TicketCustomer6,
    TicketOwner7,
    TicketCustomer7,
-   TicketMessage,
+   TicketOwner8,
+   TicketCustomer8,
+   TicketOwner9,
+   TicketCustomer9,
+   TicketScheduled,
+   TicketPaid,
+   TicketRefundPending,
+   TicketRefunded,
+   TicketItemsText1,
+   TicketItemsText2,
+   TicketItemsText3,
+   TicketEta1,
+   TicketEta2,
+   TicketEta3,
 }
 
 pub type LocaleTag = u32;
@@ -36,6 +36,18 @@ mod general;
 mod registration;
 mod search;
 mod loc;
+mod scheduler;
+mod chat;
+mod courier;
+mod queue;
+mod geo;
+mod review;
+mod payment;
+mod promo;
+mod pricing;
+mod bundle;
+mod referral;
+mod tip;
 
 // ============================================================================
 // [Run!]
@@ -124,12 +136,21 @@ async fn run() {
       }
    }
 
+   // Add tables and fields of the new versions
+   match database::update_tables().await {
+      Ok(_) => log::info!("tables updated"),
+      Err(e) => log::error!("main::run() update tables: {}", e),
+   }
+
    // Data for localization
    let loc = crate::loc::Locale::new("en");
    if loc::LOC.set(loc).is_err() {
       log::error!("main::run() loc set error")
    }
 
+   // Background tasks like reminders
+   tokio::spawn(scheduler::run(bot.clone()));
+
    let teloxide_token = env::var("TELOXIDE_TOKEN").expect("TELOXIDE_TOKEN env variable missing");
 
    // Heroku auto defines a port value
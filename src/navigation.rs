@@ -20,6 +20,8 @@ use crate::environment as env;
 use crate::states::*;
 use crate::database as db;
 use crate::node::*;
+use crate::review::Rating;
+use crate::bundle::Bundle;
 use crate::loc::*;
 
 pub async fn enter(bot: Bot, msg: Message, state: MainState, mode: WorkTime) -> HandlerResult {
@@ -70,8 +72,10 @@ pub async fn enter(bot: Bot, msg: Message, state: MainState, mode: WorkTime) ->
 
             // All is ok, collect and display info
             let user_id = state.user_id; // user needs to sync with cart
-            let markup = markup(&node, mode, user_id, tag).await?;
-            let text = node_text(&node, tag);
+            let bundle = db::bundle(node.id, user_id.0).await?;
+            let markup = markup(&node, bundle.as_ref(), mode, user_id, tag).await?;
+            let rating = db::node_rating(node.id).await?;
+            let text = node_text(&node, bundle.as_ref(), rating, tag);
 
             bot.send_photo(chat_id, InputFile::file_id(picture))
             .caption(text)
@@ -113,10 +117,12 @@ pub async fn view(bot: &Bot, q: CallbackQuery, node_id: i32, mode: WorkTime, tag
 
    // Collect info
    let node = node.unwrap();
-   let markup = markup(&node, mode, user_id, tag)
+   let bundle = db::bundle(node.id, user_id.0).await?;
+   let markup = markup(&node, bundle.as_ref(), mode, user_id, tag)
    .await?;
 
-   let text = node_text(&node, tag);
+   let rating = db::node_rating(node.id).await?;
+   let text = node_text(&node, bundle.as_ref(), rating, tag);
 
    // Message to modify
    let message = q.message;
@@ -157,10 +163,16 @@ pub async fn view(bot: &Bot, q: CallbackQuery, node_id: i32, mode: WorkTime, tag
    }
 }
 
-fn node_text(node: &Node, tag: LocaleTag) -> String {
+fn node_text(node: &Node, bundle: Option<&Bundle>, rating: Option<Rating>, tag: LocaleTag) -> String {
 
    let mut res = format!("<b>{}</b>", node.title);
 
+   // Average of the customers' ratings
+   if let Some(rating) = rating {
+      // "{} ⭐{} ({})"
+      res = loc(Key::NavigationNodeText3, tag, &[&res, &format!("{:.1}", rating.average), &rating.count]);
+   }
+
    // Do not display description from 1 symbol
    if node.descr.len() > 1 {
       res = res + "\n" + node.descr.as_str();
@@ -178,15 +190,43 @@ fn node_text(node: &Node, tag: LocaleTag) -> String {
       res = loc(Key::NavigationNodeText1, tag, args);
    };
 
-   if node.price != 0 {
-      // "{}\nPrice: {}"
-      res = loc(Key::NavigationNodeText2, tag, &[&res, &env::price_with_unit(node.price)])
+   // Components of the bundle with the choice of the customer
+   if let Some(bundle) = bundle {
+      for slot in &bundle.slots {
+         res = match &slot.chosen {
+            // "{}\n▫ {}: {}"
+            Some(item) => loc(Key::NavigationNodeText4, tag, &[&res, &slot.source.title, &item.title]),
+            // "{}\n▫ {}: not chosen"
+            None => loc(Key::NavigationNodeText5, tag, &[&res, &slot.source.title]),
+         };
+      }
+   }
+
+   match bundle {
+      Some(bundle) if bundle.percent > 0 => {
+         res = if bundle.is_complete() {
+            // "{}\nPrice: {}"
+            loc(Key::NavigationNodeText2, tag, &[&res, &env::price_with_unit(bundle.price(node))])
+         } else {
+            // "{}\nDiscount {}% from the sum of the chosen items"
+            loc(Key::NavigationNodeText6, tag, &[&res, &bundle.percent])
+         }
+      }
+      _ => if node.price != 0 {
+         // "{}\nPrice: {}"
+         res = loc(Key::NavigationNodeText2, tag, &[&res, &node.price_text()])
+      }
+   }
+
+   if let Some(stock) = node.stock.filter(|stock| *stock > 0) {
+      // "{}\nLeft: {}"
+      res = loc(Key::NavigationNodeText7, tag, &[&res, &stock]);
    }
 
    res
 }
 
-async fn markup(node: &Node, mode: WorkTime, user_id: UserId, tag: LocaleTag) -> Result<InlineKeyboardMarkup, String> {
+async fn markup(node: &Node, bundle: Option<&Bundle>, mode: WorkTime, user_id: UserId, tag: LocaleTag) -> Result<InlineKeyboardMarkup, String> {
 
    // Prepare command
    let pas = match mode {
@@ -210,8 +250,8 @@ async fn markup(node: &Node, mode: WorkTime, user_id: UserId, tag: LocaleTag) ->
    .into_iter()
    .partition(|n| n.text.chars().count() > 21);
 
-   // If price not null add button for cart with amount
-   if node.price != 0 {
+   // If price not null or the price comes from the bundle add button for cart with amount
+   if node.price != 0 || bundle.is_some() {
       // Display only title or title with amount
       let amount = db::orders_amount(user_id.0 as i64, node.id).await?;
       // "+🛒 ({})", "+🛒"
@@ -226,10 +266,12 @@ async fn markup(node: &Node, mode: WorkTime, user_id: UserId, tag: LocaleTag) ->
          WorkTime::Now => Command::IncAmountNow(0),
       };
       let cmd = String::from(cmd.as_ref());
-      let button_inc = InlineKeyboardButton::callback(
-         caption,
-         format!("{}{}", cmd, node.id)
-      );
+      let button_inc = if node.is_sold_out() {
+         // "Sold out", the button only refreshes the node
+         InlineKeyboardButton::callback(loc(Key::NavigationMarkup5, tag, &[]), format!("{}{}", pas, node.id))
+      } else {
+         InlineKeyboardButton::callback(caption, format!("{}{}", cmd, node.id))
+      };
       short.push(button_inc);
 
       // Add decrease button
@@ -256,9 +298,30 @@ async fn markup(node: &Node, mode: WorkTime, user_id: UserId, tag: LocaleTag) ->
       }
    }
 
+   // Choices for each slot of the bundle by three in row, with the price when it makes the price of the bundle
+   let choose = match mode {
+      WorkTime::All | WorkTime::AllFrom(_) => Command::BundleChoose(0, 0),
+      WorkTime::Now => Command::BundleChooseNow(0, 0),
+   };
+   let mut markup = InlineKeyboardMarkup::default();
+   for slot in bundle.iter().flat_map(|bundle| bundle.slots.iter()) {
+      let buttons: Vec<InlineKeyboardButton> = slot.source.children
+      .iter()
+      .map(|item| {
+         let title = if bundle.is_some_and(|bundle| bundle.percent > 0) { item.title_with_price() } else { item.title.clone() };
+         let title = if slot.chosen.as_ref().is_some_and(|chosen| chosen.id == item.id) { format!("✅ {}", title) } else { title };
+         InlineKeyboardButton::callback(title, format!("{}{}_{}", choose.as_ref(), node.id, item.id))
+      })
+      .collect();
+
+      for row in buttons.chunks(3) {
+         markup = markup.append_row(row.to_vec());
+      }
+   }
+
    // Long buttons by one in row
    let markup = long.into_iter()
-   .fold(InlineKeyboardMarkup::default(), |acc, item| acc.append_row(vec![item]));
+   .fold(markup, |acc, item| acc.append_row(vec![item]));
 
    // Short by two
    let mut markup = IteratorExt::array_chunks::<[_; 2]>(short.into_iter())
@@ -12,6 +12,7 @@ use std::hash::{Hash, Hasher};
 use teloxide::types::UserId;
 
 use crate::environment as env;
+use crate::geo::Geo;
 use crate::loc::*;
 
 #[derive(Clone)]
@@ -36,6 +37,10 @@ impl Owners {
    pub fn has_valid_owner(&self) -> bool {
       self.0.0 > Owners::VALID_USER_ID || self.1.0 > Owners::VALID_USER_ID || self.2.0 > Owners::VALID_USER_ID
    }
+
+   pub fn contains(&self, user_id: UserId) -> bool {
+      user_id.0 > Owners::VALID_USER_ID && (self.0 == user_id || self.1 == user_id || self.2 == user_id)
+   }
 }
 
 #[derive(Clone)]
@@ -51,6 +56,51 @@ pub struct Node {
    pub owners: Owners,
    pub time: (NaiveTime, NaiveTime),
    pub price: usize,
+   pub couriers: Owners, // up to three couriers of the place, like owners
+   pub delivery: DeliveryTerms, // own values or inherited after db::node_delivery_terms()
+   pub phone_required: bool, // customer must share the phone number before ordering
+   pub discount: usize, // percent by the price rules active at loading, see db::node_apply_price_rules()
+   pub stock: Option<usize>, // items left, None if not counted
+   pub daily_stock: usize, // the stock is refilled to this amount every day, zero for manual only
+   pub points_rate: usize, // percent of the order cost to the customer as loyalty points
+}
+
+// Conditions of delivery by courier, zero means not set
+#[derive(Clone, Default)]
+pub struct DeliveryTerms {
+   pub min_order: usize,
+   pub fee: usize,
+   pub free_from: usize, // order cost for free delivery
+   pub zone: Option<Geo>, // center of the delivery zone
+   pub radius: usize, // meters from the center, zero for any distance
+   pub km_fee: usize, // additional fee for each kilometer from the center
+}
+
+impl DeliveryTerms {
+   // Fee to the customer's point, if known
+   pub fn fee_for(&self, cost: usize, to: Option<&Geo>) -> usize {
+      if self.free_from > 0 && cost >= self.free_from {
+         return 0;
+      }
+
+      let km = match (&self.zone, to) {
+         (Some(zone), Some(to)) => (zone.distance(to) / 1000.0).ceil() as usize,
+         _ => 0,
+      };
+      self.fee + km * self.km_fee
+   }
+
+   // Delivery without known point of the customer is up to the place
+   pub fn is_in_zone(&self, to: Option<&Geo>) -> bool {
+      match (&self.zone, to) {
+         (Some(zone), Some(to)) if self.radius > 0 => zone.distance(to) <= self.radius as f64,
+         _ => true,
+      }
+   }
+
+   pub fn is_enough(&self, cost: usize) -> bool {
+      cost >= self.min_order
+   }
 }
 
 // Picture type
@@ -88,6 +138,8 @@ pub enum UpdateKind {
    User(UserId),
    Time(NaiveTime, NaiveTime),
    Money(usize),
+   Location(Option<Geo>),
+   Stock(Option<usize>),
 }
 
 #[derive(Debug, Clone)]
@@ -124,6 +176,13 @@ impl Node {
          owners: Owners::from_int(0, 0, 0),
          time: (t, t),
          price: 0,
+         couriers: Owners::default(),
+         delivery: DeliveryTerms::default(),
+         phone_required: false,
+         discount: 0,
+         stock: None,
+         daily_stock: 0,
+         points_rate: 0,
       }
    }
 
@@ -162,32 +221,134 @@ impl Node {
          else { Err(String::from("node::update type int mismatch")) }
       }
 
+      fn check_location(kind: &UpdateKind) -> Result<Option<Geo>, String> {
+         if let UpdateKind::Location(res) = kind { Ok(*res) }
+         else { Err(String::from("node::update type location mismatch")) }
+      }
+
+      fn check_stock(kind: &UpdateKind) -> Result<Option<usize>, String> {
+         if let UpdateKind::Stock(res) = kind { Ok(*res) }
+         else { Err(String::from("node::update type stock mismatch")) }
+      }
+
       match info.field.as_str() {
          "title" => self.title = check_str(&info.kind)?,
          "descr" => self.descr = check_str(&info.kind)?,
          "picture" => self.picture = check_picture(&info.kind)?,
          "enabled" => self.enabled = check_bool(&info.kind)?,
+         "phone_required" => self.phone_required = check_bool(&info.kind)?,
          "banned" => self.banned = check_bool(&info.kind)?,
          "owner1" => self.owners.0 = check_owner(&info.kind)?,
          "owner2" => self.owners.1 = check_owner(&info.kind)?,
          "owner3" => self.owners.2 = check_owner(&info.kind)?,
+         "courier1" => self.couriers.0 = check_owner(&info.kind)?,
+         "courier2" => self.couriers.1 = check_owner(&info.kind)?,
+         "courier3" => self.couriers.2 = check_owner(&info.kind)?,
          "time" => self.time = check_time(&info.kind)?,
          "price" => self.price = check_money(&info.kind)?,
+         "min_order" => self.delivery.min_order = check_money(&info.kind)?,
+         "delivery_fee" => self.delivery.fee = check_money(&info.kind)?,
+         "free_delivery" => self.delivery.free_from = check_money(&info.kind)?,
+         "zone" => self.delivery.zone = check_location(&info.kind)?,
+         "zone_radius" => self.delivery.radius = check_money(&info.kind)?,
+         "km_fee" => self.delivery.km_fee = check_money(&info.kind)?,
+         "stock" => self.stock = check_stock(&info.kind)?,
+         "daily_stock" => self.daily_stock = check_money(&info.kind)?,
+         "points_rate" => self.points_rate = check_money(&info.kind)?,
          _ => return Err(format!("node::update unknown field {}", info.field)),
       }
       Ok(())
    }
 
+   // Working at the given time, including around the clock and after midnight
+   pub fn is_open_at(&self, time: NaiveTime) -> bool {
+      let (open, close) = self.time;
+      if open == close { true }
+      else if open < close { open <= time && time < close }
+      else { open <= time || time < close }
+   }
+
+   pub fn is_sold_out(&self) -> bool {
+      self.stock == Some(0)
+   }
+
    pub fn is_time_set(&self) -> bool {
       let zero = NaiveTime::from_hms_opt(0, 0, 0).unwrap_or_default();
       self.time.0 != zero || self.time.1 != zero
    }
 
    pub fn title_with_price(&self) -> String {
-      let price = if self.price > 0 { String::from(" ") + &env::price_with_unit(self.price) }
+      let price = if self.price > 0 { String::from(" ") + &self.price_text() }
       else { String::default() };
    
       format!("{}{}", self.title, price)
    }
+
+   // Price for the customer with the discount of the happy hours
+   pub fn actual_price(&self) -> usize {
+      self.price - self.price * self.discount / 100
+   }
+
+   pub fn price_text(&self) -> String {
+      let price = env::price_with_unit(self.actual_price());
+      if self.discount > 0 { format!("{} (-{}%)", price, self.discount) }
+      else { price }
+   }
 }
 
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn fee_without_terms_is_zero() {
+      let terms = DeliveryTerms::default();
+      assert_eq!(terms.fee_for(100_000, None), 0);
+   }
+
+   #[test]
+   fn fee_until_free_delivery() {
+      let terms = DeliveryTerms { fee: 20_000, free_from: 200_000, ..Default::default() };
+      assert_eq!(terms.fee_for(199_999, None), 20_000);
+      assert_eq!(terms.fee_for(200_000, None), 0);
+   }
+
+   // About 1.1 km to the north of the center
+   fn zone() -> (Geo, Geo) {
+      (Geo::new(10.93, 108.28), Geo::new(10.94, 108.28))
+   }
+
+   #[test]
+   fn fee_by_kilometers() {
+      let (center, to) = zone();
+      let terms = DeliveryTerms { fee: 10_000, zone: Some(center), km_fee: 5_000, ..Default::default() };
+      assert_eq!(terms.fee_for(0, Some(&to)), 20_000);
+      assert_eq!(terms.fee_for(0, None), 10_000);
+   }
+
+   #[test]
+   fn zone_by_radius() {
+      let (center, to) = zone();
+      let near = DeliveryTerms { zone: Some(center), radius: 1_000, ..Default::default() };
+      let far = DeliveryTerms { zone: Some(center), radius: 2_000, ..Default::default() };
+      assert!(!near.is_in_zone(Some(&to)));
+      assert!(far.is_in_zone(Some(&to)));
+   }
+
+   #[test]
+   fn zone_unknown_point_or_no_radius() {
+      let (center, to) = zone();
+      let terms = DeliveryTerms { zone: Some(center), radius: 1_000, ..Default::default() };
+      assert!(terms.is_in_zone(None));
+      let terms = DeliveryTerms { zone: Some(center), ..Default::default() };
+      assert!(terms.is_in_zone(Some(&to)));
+   }
+
+   #[test]
+   fn minimum_order() {
+      let terms = DeliveryTerms { min_order: 50_000, ..Default::default() };
+      assert!(!terms.is_enough(49_999));
+      assert!(terms.is_enough(50_000));
+   }
+}
@@ -7,23 +7,36 @@ http://www.gnu.org/licenses/gpl-3.0.html
 Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
 =============================================================================== */
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::node::*;
+use crate::customer::Customer;
+use crate::promo::Promo;
 
 pub struct NodeWithAmount {
    pub amount: usize,
    pub node: Node,
+   pub note: Option<String>, // customer's wish like "no onions"
+   pub price: usize, // with the happy hours and the chosen components of the bundle
+   pub components: Option<String>, // chosen items of the bundle
 }
 
 impl NodeWithAmount {
     pub fn cost(&self) -> usize {
-       self.amount * self.node.price
+       self.amount * self.price
     }
 }
 
 pub type Order = Vec<NodeWithAmount>;
 
+// Cart line as the customer saw it, to compare with the current menu
+pub struct OrderLine {
+   pub node_id: i32,
+   pub title: String,
+   pub price: Option<usize>, // unknown for the lines of the previous versions
+   pub amount: usize,
+}
+
 pub struct CartInfo {
    pub orders_num: usize,
    pub items_num: usize,
@@ -32,16 +45,56 @@ pub struct CartInfo {
 
 pub struct Orders {
    pub data: HashMap<Node, Order>,
+   pub comments: HashMap<i32, String>, // comment to the whole order by owner node id
+   pub promos: HashMap<i32, Promo>, // promo code for the order by owner node id
+   pub points: HashSet<i32>, // orders by owner node id where the customer spends loyalty points
+   pub points_balance: usize,
 }
 
 impl Orders {
    pub fn new() -> Self {
       Self {
          data: HashMap::new(),
+         comments: HashMap::new(),
+         promos: HashMap::new(),
+         points: HashSet::new(),
+         points_balance: 0,
       }
    }
 
-   pub fn cart_info(&self) -> CartInfo {
+   // Discount by the promo code for the order to the owner
+   pub fn discount(&self, owner_id: i32, cost: usize) -> usize {
+      self.promos.get(&owner_id)
+      .map(|promo| promo.discount(cost))
+      .unwrap_or_default()
+   }
+
+   // Cost of the items in the order to the owner
+   pub fn cost(&self, owner_id: i32) -> usize {
+      self.data.iter()
+      .find(|(owner, _)| owner.id == owner_id)
+      .map(|(_, order)| order.iter().map(|item| item.cost()).sum())
+      .unwrap_or_default()
+   }
+
+   // Loyalty points to spend on the order to the owner, one balance for all orders with points
+   pub fn points(&self, owner_id: i32) -> usize {
+      if !self.points.contains(&owner_id) {
+         return 0;
+      }
+
+      let costs = self.points.iter()
+      .map(|id| {
+         let cost = self.cost(*id);
+         (*id, cost - self.discount(*id, cost))
+      })
+      .collect();
+      split_points(self.points_balance, costs, owner_id)
+   }
+
+   // With discounts and delivery fees when the customer chose a courier
+   pub fn cart_info(&self, customer: &Customer) -> CartInfo {
+      let geo = customer.geo.as_ref();
       let mut res = CartInfo {
          orders_num: 0,
          items_num: 0,
@@ -54,11 +107,54 @@ impl Orders {
             (acc.0 + 1, acc.1 + v.amount, acc.2 + v.cost())
          });
 
+         let t = t - self.discount(owner.0.id, t);
+         let t = t - self.points(owner.0.id);
          res.orders_num += o;
          res.items_num += i;
          res.total_cost += t;
+         let terms = &owner.0.delivery;
+         if customer.is_courier() && terms.is_in_zone(geo) {
+            res.total_cost += terms.fee_for(t, geo);
+         }
       };
 
       res
    }
-}
\ No newline at end of file
+}
+
+// Share of the balance for the order to the owner, the orders get it one by one by owner id so that the cart
+// shows the same as the checkouts will spend, the costs are after the discounts
+pub fn split_points(balance: usize, mut costs: Vec<(i32, usize)>, owner_id: i32) -> usize {
+   costs.sort_by_key(|(id, _)| *id);
+   let mut left = balance;
+   for (id, cost) in costs {
+      let spent = left.min(cost);
+      if id == owner_id {
+         return spent;
+      }
+      left -= spent;
+   }
+   0
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn points_single_order() {
+      assert_eq!(split_points(100, vec![(1, 300)], 1), 100);
+      assert_eq!(split_points(500, vec![(1, 300)], 1), 300);
+      assert_eq!(split_points(0, vec![(1, 300)], 1), 0);
+   }
+
+   #[test]
+   fn points_split_between_orders() {
+      let costs = vec![(7, 300), (3, 250)];
+      assert_eq!(split_points(400, costs.clone(), 3), 250);
+      assert_eq!(split_points(400, costs.clone(), 7), 150);
+      assert_eq!(split_points(100, costs.clone(), 3), 100);
+      assert_eq!(split_points(100, costs.clone(), 7), 0);
+      assert_eq!(split_points(100, costs, 5), 0);
+   }
+}
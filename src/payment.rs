@@ -0,0 +1,488 @@
+/* ===============================================================================
+Restaurant menu bot.
+Online payment of the ticket. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use teloxide::{prelude::*,
+   types::{CallbackQuery, Currency, InlineKeyboardButton, InlineKeyboardMarkup, LabeledPrice, ParseMode, PreCheckoutQuery, SuccessfulPayment, },
+   utils::html,
+};
+use chrono::Datelike;
+
+use crate::states::*;
+use crate::database as db;
+use crate::environment as env;
+use crate::callback;
+use crate::registration;
+use crate::review;
+use crate::node;
+use crate::ticket::*;
+use crate::loc::*;
+
+pub trait Provider: Send + Sync {
+   // Token for Telegram invoice, without it the customer pays with the button in the chat
+   fn token(&self) -> Option<String>;
+
+   // ISO 4217 code
+   fn currency(&self) -> String;
+
+   // Digits after the decimal point, prices of the bot are in whole units
+   fn exponent(&self) -> u32;
+
+   // Price in the smallest units of the currency
+   fn amount(&self, price: usize) -> Result<u32, String> {
+      10usize.checked_pow(self.exponent())
+      .and_then(|scale| price.checked_mul(scale))
+      .and_then(|amount| u32::try_from(amount).ok())
+      .ok_or(format!("payment::amount {} {} is out of range", price, self.currency()))
+   }
+
+   // The same currency as in the invoice
+   fn is_currency(&self, currency: Currency) -> bool {
+      currency_code(currency) == self.currency()
+   }
+}
+
+// ISO 4217 code by its serialized name, Debug output is not guaranteed
+pub fn currency_code(currency: Currency) -> String {
+   match serde_json::to_value(currency) {
+      Ok(serde_json::Value::String(code)) => code,
+      _ => String::default(),
+   }
+}
+
+// Currencies without a minor unit, the rest have cents
+const ZERO_EXPONENT: [&str; 11] = ["BIF", "CLP", "DJF", "GNF", "ISK", "JPY", "KRW", "PYG", "UGX", "VND", "XAF"];
+
+pub fn exponent_of(currency: &str) -> u32 {
+   if ZERO_EXPONENT.contains(&currency) { 0 } else { 2 }
+}
+
+// Back from the smallest units to the prices of the bot, the fraction of the whole unit is dropped
+pub fn price_of(amount: u32, exponent: u32) -> Result<usize, String> {
+   10u32.checked_pow(exponent)
+   .map(|scale| (amount / scale) as usize)
+   .ok_or(format!("payment::price_of {} with exponent {} is out of range", amount, exponent))
+}
+
+// Telegram Payments with the token from BotFather
+pub struct TelegramProvider {
+   token: String,
+   currency: String,
+   exponent: u32,
+}
+
+impl Provider for TelegramProvider {
+   fn token(&self) -> Option<String> {
+      Some(self.token.clone())
+   }
+
+   fn currency(&self) -> String {
+      self.currency.clone()
+   }
+
+   fn exponent(&self) -> u32 {
+      self.exponent
+   }
+}
+
+// Payment without money for local testing
+pub struct FakeProvider;
+
+impl Provider for FakeProvider {
+   fn token(&self) -> Option<String> {
+      None
+   }
+
+   fn currency(&self) -> String {
+      String::from("XTS") // code reserved for testing
+   }
+
+   fn exponent(&self) -> u32 {
+      0
+   }
+}
+
+// Counts and amounts of the paid tickets of the place
+pub struct Reconciliation {
+   pub title: String,
+   pub paid: (i64, i64),
+   pub finished: (i64, i64),
+   pub active: (i64, i64),
+   pub pending: (i64, i64),
+   pub refunded: (i64, i64),
+   pub unreturned: (i64, i64), // canceled but the refund was not requested
+   pub tips: (i64, i64), // for all tickets, not only paid online
+}
+
+// Provider from the settings or None if payments are off
+pub fn provider() -> Option<Box<dyn Provider>> {
+   match env::payment_provider().as_str() {
+      "telegram" => {
+         let currency = env::payment_currency();
+         Some(Box::new(TelegramProvider {
+            token: env::payment_token(),
+            exponent: env::payment_exponent().unwrap_or_else(|| exponent_of(&currency)),
+            currency,
+         }))
+      }
+      "fake" => Some(Box::new(FakeProvider)),
+      _ => None,
+   }
+}
+
+// The ticket is still waiting for the payment, but not while the customer decides on the changes to it
+fn is_payable(t: &TicketWithOwners) -> bool {
+   t.ticket.stage.is_active() && t.ticket.stage != Stage::CustomerApproval && t.ticket.payment == PaymentState::Unpaid
+}
+
+// Items and delivery to pay
+fn bill(t: &TicketWithOwners, tag: LocaleTag) -> Vec<(String, usize)> {
+   // Prices cannot be negative, so the discount goes as one line for all items
+   let cost = t.cost();
+   let mut res: Vec<(String, usize)> = if cost != t.items_cost() {
+      let label = match &t.ticket.promo {
+         // "Items with promo code {}"
+         Some(promo) => loc(Key::PaymentInvoice4, tag, &[promo]),
+         // "Items with loyalty points"
+         None => loc(Key::PaymentInvoice5, tag, &[]),
+      };
+      vec![(label, cost)]
+   } else {
+      t.items.iter()
+      .filter(|item| item.amount > 0)
+      .map(|item| (format!("{} x{}", item.title, item.amount), item.cost()))
+      .collect()
+   };
+
+   // The fee as the customer saw it at the checkout, the terms may have changed since
+   let fee = t.ticket.delivery_fee;
+   if fee > 0 {
+      // "Delivery"
+      res.push((loc(Key::PaymentInvoice3, tag, &[]), fee));
+   }
+   res
+}
+
+// Send the customer the invoice for the new ticket if payments are on
+pub async fn offer(bot: &Bot, t: &TicketWithOwners, tag: LocaleTag) -> Result<(), String> {
+   let provider = provider();
+   if provider.is_none() {
+      return Ok(());
+   }
+   let provider = provider.unwrap();
+
+   let bill = bill(t, tag);
+   let total: usize = bill.iter().map(|(_, price)| price).sum();
+   let code = t.ticket.code();
+   let customer_id = t.ticket.customer_id;
+
+   match provider.token() {
+      Some(token) => {
+         let prices = bill.into_iter()
+         .map(|(label, price)| provider.amount(price).map(|amount| LabeledPrice::new(label, amount)))
+         .collect::<Result<Vec<_>, _>>()?;
+
+         // "Order {}", "Payment for the order {}, total {}"
+         let title = loc(Key::PaymentInvoice1, tag, &[&code]);
+         let description = loc(Key::PaymentInvoice2, tag, &[&code, &env::price_with_unit(total)]);
+         bot.send_invoice(customer_id, title, description, t.ticket.id.to_string(), token, provider.currency(), prices)
+         .await
+         .map_err(|err| format!("payment::offer invoice customer_id={}: {}", customer_id, err))?;
+      }
+      None => {
+         let args = format!("{}{}", callback::Command::TicketPay(0).as_ref(), t.ticket.id);
+         let button = InlineKeyboardButton::callback(loc(Key::CallbackPay, tag, &[]), args); // "💳 Pay"
+
+         // "Order {}: {} to pay. This is a test payment, no money will be charged"
+         let text = loc(Key::PaymentFake1, tag, &[&code, &env::price_with_unit(total)]);
+         bot.send_message(customer_id, text)
+         .reply_markup(InlineKeyboardMarkup::default().append_row(vec![button]))
+         .await
+         .map_err(|err| format!("payment::offer customer_id={}: {}", customer_id, err))?;
+      }
+   }
+   Ok(())
+}
+
+// Telegram asks to confirm the order before charging
+pub async fn pre_checkout(bot: Bot, q: PreCheckoutQuery) -> HandlerResult {
+   let tag = tag(q.from.language_code.as_deref());
+   let ticket_id = q.invoice_payload.parse().unwrap_or_default();
+   let t = db::ticket_with_owners(ticket_id).await;
+
+   // The order could change or be canceled after the invoice was sent
+   let actual = match (&t, provider()) {
+      (Ok(t), Some(provider)) if is_payable(t) && q.from.id == t.ticket.customer_id => {
+         let total: usize = bill(t, tag).iter().map(|(_, price)| price).sum();
+         q.total_amount == provider.amount(total)? && provider.is_currency(q.currency)
+      }
+      _ => false,
+   };
+
+   if actual {
+      bot.answer_pre_checkout_query(q.id, true).await?;
+   } else {
+      // "The order has changed or is no longer active, please use the new invoice"
+      bot.answer_pre_checkout_query(q.id, false)
+      .error_message(loc(Key::PaymentPreCheckout, tag, &[]))
+      .await?;
+
+      if let Ok(t) = t {
+         if is_payable(&t) { offer(&bot, &t, tag).await? }
+      }
+   }
+   Ok(())
+}
+
+pub async fn successful(bot: Bot, msg: Message, payment: SuccessfulPayment) -> HandlerResult {
+   let tag = tag(msg.from.as_ref().and_then(|u| u.language_code.as_deref()));
+   let ticket_id = payment.invoice_payload.parse().unwrap_or_default();
+
+   // Store what was actually charged, the provider may be switched off since the invoice
+   let exponent = match provider() {
+      Some(provider) if provider.is_currency(payment.currency) => provider.exponent(),
+      _ => exponent_of(&currency_code(payment.currency)),
+   };
+   let paid = price_of(payment.total_amount, exponent)?;
+   complete(&bot, ticket_id, &payment.provider_payment_charge_id, paid, tag).await?;
+   Ok(())
+}
+
+// The customer confirms the test payment
+pub async fn fake_pay(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if q.from.id != t.ticket.customer_id {
+      // "Only the customer can pay for the order"
+      return Ok(loc(Key::PaymentFake2, tag, &[]));
+   }
+
+   if !is_payable(&t) {
+      // "Unsuccessfully"
+      return Ok(loc(Key::RegMakeTicket2, tag, &[]));
+   }
+
+   let charge_id = format!("fake-{}-{}", ticket_id, env::current_date_time().format("%Y%m%d%H%M%S"));
+   let paid = bill(&t, tag).iter().map(|(_, price)| price).sum();
+   complete(bot, ticket_id, &charge_id, paid, tag).await?;
+
+   // Remove the button
+   if let Some(msg) = &q.message {
+      bot.edit_message_reply_markup(q.from.id, msg.id())
+      .await
+      .map_err(|err| format!("payment::fake_pay user_id={}: {}", q.from.id, err))?;
+   }
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+// Store the charged amount and notify both sides
+async fn complete(bot: &Bot, ticket_id: i32, charge_id: &str, paid: usize, tag: LocaleTag) -> Result<(), String> {
+   let mut t = db::ticket_with_owners(ticket_id).await?;
+
+   // Repeated notification of the same payment
+   if !db::ticket_set_paid(ticket_id, charge_id, paid).await? {
+      return Ok(());
+   }
+   t.ticket.payment = PaymentState::Paid;
+   t.ticket.payment_id = Some(charge_id.to_string());
+   t.ticket.paid = paid;
+
+   let total = env::price_with_unit(paid);
+   let code = t.ticket.code();
+
+   // "Order {}: payment {} received, thank you!"
+   let text = loc(Key::PaymentComplete1, tag, &[&code, &total]);
+   bot.send_message(t.ticket.customer_id, text)
+   .await
+   .map_err(|err| format!("payment::complete customer_id={}: {}", t.ticket.customer_id, err))?;
+
+   // "Order {}: paid {}"
+   let text = loc(Key::PaymentComplete2, tag, &[&code, &total]);
+   review::send_to_owners(bot, &t.owners, &text).await;
+
+   // The order was canceled while the customer was paying
+   if !t.ticket.stage.is_active() {
+      request_refund(bot, &mut t, tag).await?;
+   }
+
+   // Show the mark in the status messages
+   registration::update_statuses(bot, t, tag).await
+}
+
+// The paid ticket is canceled, the place must return the money
+pub async fn request_refund(bot: &Bot, t: &mut TicketWithOwners, tag: LocaleTag) -> Result<(), String> {
+   if t.ticket.payment != PaymentState::Paid
+   || !db::ticket_update_payment(t.ticket.id, PaymentState::Paid, PaymentState::RefundPending).await? {
+      return Ok(());
+   }
+   t.ticket.payment = PaymentState::RefundPending;
+
+   let code = t.ticket.code();
+   let total = env::price_with_unit(t.ticket.paid);
+   let charge_id = t.ticket.payment_id.clone().unwrap_or_default();
+
+   // "Order {}: canceled after the payment of {}, charge {}. Please return the money to the customer and mark it with the button"
+   let text = loc(Key::PaymentRefund1, tag, &[&code, &total, &charge_id]);
+   let args = format!("{}{}", callback::Command::TicketRefunded(0).as_ref(), t.ticket.id);
+   let button = InlineKeyboardButton::callback(loc(Key::CallbackRefunded, tag, &[]), args); // "💸 Refunded"
+   let markup = InlineKeyboardMarkup::default().append_row(vec![button]);
+
+   let owners = [t.owners.0, t.owners.1, t.owners.2];
+   for owner in owners.into_iter().filter(|id| id.0 > node::Owners::VALID_USER_ID) {
+      if let Err(err) = bot.send_message(owner, &text).reply_markup(markup.clone()).await {
+         env::log(&format!("payment::request_refund owner={}: {}", owner, err)).await;
+      }
+   }
+
+   // Admins follow the service chat
+   env::log_reply(&text, t.ticket.service_msg_id).await;
+
+   // "Order {}: the payment of {} will be returned to you"
+   let text = loc(Key::PaymentRefund2, tag, &[&code, &total]);
+   bot.send_message(t.ticket.customer_id, text)
+   .await
+   .map_err(|err| format!("payment::request_refund customer_id={}: {}", t.ticket.customer_id, err))?;
+   Ok(())
+}
+
+// The place returned the money, the button from the request
+pub async fn refunded(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   if let Some(err) = mark_refunded(bot, &q, ticket_id, tag).await? {
+      return Ok(err);
+   }
+
+   // Remove the button
+   if let Some(msg) = &q.message {
+      bot.edit_message_reply_markup(q.from.id, msg.id())
+      .await
+      .map_err(|err| format!("payment::refunded user_id={}: {}", q.from.id, err))?;
+   }
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+// Error text if the refund cannot be marked or None
+pub async fn mark_refunded(bot: &Bot, q: &CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<Option<String>, String> {
+   let mut t = db::ticket_with_owners(ticket_id).await?;
+   if !t.owners.contains(q.from.id) && !env::is_admin_id(q.from.id) {
+      // "Only the place or the administrator can mark the refund"
+      return Ok(Some(loc(Key::PaymentRefunded1, tag, &[])));
+   }
+
+   if !db::ticket_update_payment(ticket_id, PaymentState::RefundPending, PaymentState::Refunded).await? {
+      // "Unsuccessfully"
+      return Ok(Some(loc(Key::RegMakeTicket2, tag, &[])));
+   }
+   t.ticket.payment = PaymentState::Refunded;
+
+   // "Order {}: the payment of {} has been returned"
+   let text = loc(Key::PaymentRefunded2, tag, &[&t.ticket.code(), &env::price_with_unit(t.ticket.paid)]);
+   bot.send_message(t.ticket.customer_id, &text)
+   .await
+   .map_err(|err| format!("payment::mark_refunded customer_id={}: {}", t.ticket.customer_id, err))?;
+   env::log_reply(&text, t.ticket.service_msg_id).await;
+
+   registration::update_statuses(bot, t, tag).await?;
+   Ok(None)
+}
+
+// Paid, finished and refunded tickets since the start of the month, all places for admin
+pub async fn report(bot: &Bot, user_id: UserId, tag: LocaleTag) -> Result<(), String> {
+   let today = env::current_date_time().date();
+   let since = today.with_day(1).unwrap_or(today);
+   let owner = if env::is_admin_id(user_id) { None } else { Some(user_id) };
+   let lines = db::payments_report(owner, since.and_hms_opt(0, 0, 0).unwrap_or_default()).await?;
+
+   let fmt = loc(Key::CommonDateFormat, tag, &[]);
+   let since = since.format(&fmt);
+   let sum = |pair: (i64, i64)| env::price_with_unit(pair.1 as usize);
+
+   let text = if lines.is_empty() {
+      // "No paid orders or tips since {}"
+      loc(Key::PaymentReport1, tag, &[&since])
+   } else {
+      // "Payments since {}"
+      let init = loc(Key::PaymentReport2, tag, &[&since]);
+      lines.iter()
+      .fold(init, |acc, r| {
+         // "{}\n\n<b>{}</b>\nPaid: {} for {}\nFinished: {} for {}\nIn progress: {} for {}\nRefund pending: {} for {}\nRefunded: {} for {}"
+         let acc = loc(Key::PaymentReport3, tag, &[&acc, &html::escape(&r.title),
+            &r.paid.0, &sum(r.paid), &r.finished.0, &sum(r.finished), &r.active.0, &sum(r.active),
+            &r.pending.0, &sum(r.pending), &r.refunded.0, &sum(r.refunded)
+         ]);
+
+         let acc = if r.unreturned.0 > 0 {
+            // "{}\n⚠ Canceled without refund: {} for {}"
+            loc(Key::PaymentReport4, tag, &[&acc, &r.unreturned.0, &sum(r.unreturned)])
+         } else {
+            acc
+         };
+
+         if r.tips.0 > 0 {
+            // "{}\n💝 Tips: {} for {}"
+            loc(Key::PaymentReport5, tag, &[&acc, &r.tips.0, &sum(r.tips)])
+         } else {
+            acc
+         }
+      })
+   };
+
+   bot.send_message(user_id, text)
+   .parse_mode(ParseMode::Html)
+   .await
+   .map_err(|err| format!("payment::report user_id={}: {}", user_id, err))?;
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn telegram(currency: &str) -> TelegramProvider {
+      TelegramProvider {
+         token: String::default(),
+         currency: currency.to_string(),
+         exponent: exponent_of(currency),
+      }
+   }
+
+   #[test]
+   fn amount_without_minor_unit() {
+      assert_eq!(telegram("VND").amount(50_000), Ok(50_000));
+      assert_eq!(FakeProvider.amount(50_000), Ok(50_000));
+   }
+
+   #[test]
+   fn amount_in_cents() {
+      assert_eq!(telegram("USD").amount(12), Ok(1_200));
+   }
+
+   #[test]
+   fn amount_out_of_range() {
+      assert!(telegram("USD").amount(50_000_000).is_err());
+      assert!(telegram("VND").amount(u32::MAX as usize + 1).is_err());
+   }
+
+   #[test]
+   fn price_back_from_amount() {
+      assert_eq!(price_of(1_250, 2), Ok(12));
+      assert_eq!(price_of(50_000, 0), Ok(50_000));
+      assert_eq!(price_of(telegram("USD").amount(12).unwrap(), 2), Ok(12));
+      assert!(price_of(1, 10).is_err());
+   }
+
+   #[test]
+   fn currency_by_code() {
+      assert_eq!(currency_code(Currency::VND), "VND");
+      assert!(telegram("USD").is_currency(Currency::USD));
+      assert!(!telegram("USD").is_currency(Currency::EUR));
+      assert!(FakeProvider.is_currency(Currency::XTS));
+   }
+}
@@ -0,0 +1,143 @@
+/* ===============================================================================
+Restaurant menu bot.
+Time-based price rules (happy hours). 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use chrono::{Datelike, NaiveDateTime, NaiveTime};
+
+use crate::loc::*;
+
+#[derive(Clone)]
+pub struct PriceRule {
+   pub id: i32,
+   pub node_id: i32, // the node and all its children
+   pub weekdays: i32, // bit mask, Monday is the lowest bit
+   pub start: NaiveTime,
+   pub finish: NaiveTime,
+   pub percent: usize, // discount
+}
+
+impl PriceRule {
+   pub const ALL_DAYS: i32 = 0b111_1111;
+
+   // Text like "14:00-17:00 20% 12345", weekdays from 1 for Monday are optional
+   pub fn parse(s: &str, node_id: i32, tag: LocaleTag) -> Option<Self> {
+      let mut parts = s.split_whitespace();
+
+      let fmt = loc(Key::CommonTimeFormat, tag, &[]);
+      let (start, finish) = parts.next()?.split_once('-')?;
+      let start = NaiveTime::parse_from_str(start, &fmt).ok()?;
+      let finish = NaiveTime::parse_from_str(finish, &fmt).ok()?;
+
+      let percent = parts.next()?.strip_suffix('%')?
+      .parse().ok()
+      .filter(|p| (1..=100).contains(p))?;
+
+      let weekdays = match parts.next() {
+         Some(days) => days.chars()
+            .try_fold(0, |acc, c| match c.to_digit(10) {
+               Some(d @ 1..=7) => Some(acc | 1 << (d - 1)),
+               _ => None,
+            })?,
+         None => Self::ALL_DAYS,
+      };
+
+      if parts.next().is_some() {
+         return None;
+      }
+      Some(Self { id: 0, node_id, weekdays, start, finish, percent })
+   }
+
+   // Bit of the day for the mask
+   pub fn weekday_bit(time: NaiveDateTime) -> i32 {
+      1 << time.weekday().num_days_from_monday()
+   }
+
+   // The interval may pass midnight like "22:00-02:00", the same start and finish is all day
+   pub fn is_active_at(&self, time: NaiveDateTime) -> bool {
+      let t = time.time();
+      let in_time = if self.start < self.finish {
+         t >= self.start && t < self.finish
+      } else {
+         t >= self.start || t < self.finish
+      };
+      in_time && self.weekdays & Self::weekday_bit(time) != 0
+   }
+
+   // The biggest percent among the rules working at the time, they do not add up
+   pub fn discount_at(rules: &[PriceRule], time: NaiveDateTime) -> usize {
+      rules.iter()
+      .filter(|rule| rule.is_active_at(time))
+      .map(|rule| rule.percent)
+      .max()
+      .unwrap_or_default()
+   }
+
+   // Line for the list in settings
+   pub fn describe(&self, tag: LocaleTag) -> String {
+      let fmt = loc(Key::CommonTimeFormat, tag, &[]);
+      let days = (0..7)
+      .filter(|d| self.weekdays & 1 << d != 0)
+      .map(|d| (d + 1).to_string())
+      .collect::<String>();
+
+      format!("{}) {}-{} {}% {}", self.id, self.start.format(&fmt), self.finish.format(&fmt), self.percent, days)
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use chrono::NaiveDate;
+
+   fn rule(weekdays: i32, start: u32, finish: u32, percent: usize) -> PriceRule {
+      PriceRule {
+         id: 0,
+         node_id: 0,
+         weekdays,
+         start: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+         finish: NaiveTime::from_hms_opt(finish, 0, 0).unwrap(),
+         percent,
+      }
+   }
+
+   // 19 Oct 2026 is Monday
+   fn monday(hour: u32, min: u32) -> NaiveDateTime {
+      NaiveDate::from_ymd_opt(2026, 10, 19).unwrap().and_hms_opt(hour, min, 0).unwrap()
+   }
+
+   #[test]
+   fn active_within_interval() {
+      let r = rule(PriceRule::ALL_DAYS, 14, 17, 20);
+      assert!(!r.is_active_at(monday(13, 59)));
+      assert!(r.is_active_at(monday(14, 0)));
+      assert!(r.is_active_at(monday(16, 59)));
+      assert!(!r.is_active_at(monday(17, 0)));
+   }
+
+   #[test]
+   fn active_over_midnight() {
+      let r = rule(PriceRule::ALL_DAYS, 22, 2, 10);
+      assert!(r.is_active_at(monday(23, 0)));
+      assert!(r.is_active_at(monday(1, 0)));
+      assert!(!r.is_active_at(monday(12, 0)));
+   }
+
+   #[test]
+   fn active_by_weekdays() {
+      assert!(rule(0b000_0001, 14, 17, 20).is_active_at(monday(15, 0)));
+      assert!(!rule(0b110_0000, 14, 17, 20).is_active_at(monday(15, 0)));
+   }
+
+   #[test]
+   fn biggest_discount_wins() {
+      let rules = [rule(PriceRule::ALL_DAYS, 14, 17, 20), rule(PriceRule::ALL_DAYS, 15, 16, 30), rule(0b110_0000, 14, 17, 50)];
+      assert_eq!(PriceRule::discount_at(&rules, monday(14, 30)), 20);
+      assert_eq!(PriceRule::discount_at(&rules, monday(15, 30)), 30);
+      assert_eq!(PriceRule::discount_at(&rules, monday(18, 0)), 0);
+   }
+}
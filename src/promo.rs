@@ -0,0 +1,196 @@
+/* ===============================================================================
+Restaurant menu bot.
+Promo codes with discounts. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use chrono::NaiveDate;
+
+use crate::environment as env;
+use crate::loc::*;
+
+#[derive(Clone, Copy)]
+pub enum Discount {
+   Percent(usize),
+   Fixed(usize), // money
+}
+
+#[derive(Clone)]
+pub struct Promo {
+   pub id: i32,
+   pub node_id: i32, // the place and its children, root node for all places
+   pub code: String,
+   pub discount: Discount,
+   pub valid_from: Option<NaiveDate>,
+   pub valid_to: Option<NaiveDate>,
+   pub per_user: usize, // zero without limit
+   pub total: usize, // zero without limit
+}
+
+// Result of checking the code for the customer's order
+pub enum Check {
+   Valid(Promo),
+   NotFound,
+   Expired,
+   Exhausted,
+}
+
+impl Promo {
+   // Text like "SUMMER 10% 01.06.2026-31.08.2026 1/100", dates and limits are optional
+   pub fn parse(s: &str, node_id: i32, tag: LocaleTag) -> Option<Self> {
+      let fmt = loc(Key::CommonDateFormat, tag, &[]);
+      Self::parse_with_format(s, node_id, &fmt)
+   }
+
+   // The same with the given format of dates
+   pub fn parse_with_format(s: &str, node_id: i32, fmt: &str) -> Option<Self> {
+      let mut parts = s.split_whitespace();
+      let code = Self::normalize(parts.next()?);
+      if code.is_empty() || code.chars().count() > 20 {
+         return None;
+      }
+
+      let value = parts.next()?;
+      let discount = match value.strip_suffix('%') {
+         Some(percent) => Discount::Percent(percent.parse().ok().filter(|p| (1..=100).contains(p))?),
+         None => Discount::Fixed(value.parse().ok().filter(|a| *a > 0)?),
+      };
+
+      let mut res = Self { id: 0, node_id, code, discount, valid_from: None, valid_to: None, per_user: 0, total: 0 };
+      for part in parts {
+         if let Some((per_user, total)) = part.split_once('/') {
+            res.per_user = per_user.parse().ok()?;
+            res.total = total.parse().ok()?;
+         } else {
+            let (from, to) = part.split_once('-')?;
+            let from = NaiveDate::parse_from_str(from, fmt).ok()?;
+            let to = NaiveDate::parse_from_str(to, fmt).ok()?;
+            if from > to {
+               return None;
+            }
+            res.valid_from = Some(from);
+            res.valid_to = Some(to);
+         }
+      }
+      Some(res)
+   }
+
+   // Codes are case insensitive
+   pub fn normalize(code: &str) -> String {
+      code.trim().to_uppercase()
+   }
+
+   // Amount to subtract from the cost of items
+   pub fn discount(&self, cost: usize) -> usize {
+      match self.discount {
+         Discount::Percent(percent) => cost * percent / 100,
+         Discount::Fixed(amount) => amount.min(cost),
+      }
+   }
+
+   pub fn is_valid_now(&self) -> bool {
+      let today = env::current_date_time().date();
+      self.valid_from.is_none_or(|from| from <= today) && self.valid_to.is_none_or(|to| today <= to)
+   }
+
+   // Limits with the number of uses by the customer and by everyone
+   pub fn is_available(&self, used_by_user: usize, used_total: usize) -> bool {
+      (self.per_user == 0 || used_by_user < self.per_user) && (self.total == 0 || used_total < self.total)
+   }
+
+   // Line for the list in settings
+   pub fn describe(&self, tag: LocaleTag) -> String {
+      let value = match self.discount {
+         Discount::Percent(percent) => format!("{}%", percent),
+         Discount::Fixed(amount) => env::price_with_unit(amount),
+      };
+      let mut res = format!("{} {}", self.code, value);
+
+      let fmt = loc(Key::CommonDateFormat, tag, &[]);
+      if let (Some(from), Some(to)) = (self.valid_from, self.valid_to) {
+         res = format!("{} {}-{}", res, from.format(&fmt), to.format(&fmt));
+      }
+
+      if self.per_user > 0 || self.total > 0 {
+         res = format!("{} {}/{}", res, self.per_user, self.total);
+      }
+      res
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const FMT: &str = "%d.%m.%Y";
+
+   fn parse(s: &str) -> Option<Promo> {
+      Promo::parse_with_format(s, 1, FMT)
+   }
+
+   fn fixed(amount: usize) -> Promo {
+      Promo { id: 0, node_id: 1, code: String::from("X"), discount: Discount::Fixed(amount),
+         valid_from: None, valid_to: None, per_user: 0, total: 0 }
+   }
+
+   #[test]
+   fn parse_full() {
+      let p = parse("summer 10% 01.06.2026-31.08.2026 1/100").unwrap();
+      assert_eq!(p.code, "SUMMER");
+      assert!(matches!(p.discount, Discount::Percent(10)));
+      assert_eq!(p.valid_from, NaiveDate::from_ymd_opt(2026, 6, 1));
+      assert_eq!(p.valid_to, NaiveDate::from_ymd_opt(2026, 8, 31));
+      assert_eq!((p.per_user, p.total), (1, 100));
+
+      let p = parse("GIFT 500").unwrap();
+      assert!(matches!(p.discount, Discount::Fixed(500)));
+      assert_eq!((p.valid_from, p.valid_to, p.per_user, p.total), (None, None, 0, 0));
+   }
+
+   #[test]
+   fn parse_discount_bounds() {
+      assert!(matches!(parse("A 1%").unwrap().discount, Discount::Percent(1)));
+      assert!(matches!(parse("A 100%").unwrap().discount, Discount::Percent(100)));
+      assert!(parse("A 0%").is_none());
+      assert!(parse("A 101%").is_none());
+      assert!(parse("A 0").is_none());
+      assert!(parse("A -5").is_none());
+      assert!(parse("A").is_none());
+      assert!(parse("").is_none());
+      assert!(parse("ABCDEFGHIJKLMNOPQRSTU 10%").is_none());
+   }
+
+   #[test]
+   fn parse_dates_and_limits() {
+      assert!(parse("A 10% 01.06.2026-01.06.2026").is_some());
+      assert!(parse("A 10% 31.08.2026-01.06.2026").is_none());
+      assert!(parse("A 10% 01.06.2026").is_none());
+      assert!(parse("A 10% 2026-06-01-2026-08-31").is_none());
+      assert!(parse("A 10% 0/5").is_some());
+      assert!(parse("A 10% 1/x").is_none());
+      assert!(parse("A 10% -1/5").is_none());
+      assert!(parse("A 10% junk").is_none());
+   }
+
+   #[test]
+   fn discount_amount() {
+      assert_eq!(fixed(300).discount(1_000), 300);
+      assert_eq!(fixed(300).discount(200), 200);
+      let p = parse("A 15%").unwrap();
+      assert_eq!(p.discount(1_000), 150);
+      assert_eq!(p.discount(99), 14); // 14.85 rounds down
+      assert_eq!(parse("A 100%").unwrap().discount(99), 99);
+   }
+
+   #[test]
+   fn availability_limits() {
+      let p = parse("A 10% 1/3").unwrap();
+      assert!(p.is_available(0, 2));
+      assert!(!p.is_available(1, 0));
+      assert!(!p.is_available(0, 3));
+      assert!(fixed(1).is_available(100, 1_000));
+   }
+}
@@ -0,0 +1,188 @@
+/* ===============================================================================
+Restaurant menu bot.
+Queue of active orders for owners. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use teloxide::{prelude::*,
+   types::{CallbackQuery, ParseMode, InlineKeyboardButton, InlineKeyboardMarkup, },
+};
+
+use crate::states::*;
+use crate::database as db;
+use crate::environment as env;
+use crate::callback;
+use crate::registration;
+use crate::ticket::*;
+use crate::loc::*;
+
+// The order of groups in the list follows the progress of ticket
+const STAGES: [Stage; 6] = [
+   Stage::OwnersConfirmation,
+   Stage::CustomerApproval,
+   Stage::Scheduled,
+   Stage::Cooking,
+   Stage::Delivery,
+   Stage::CustomerConfirmation,
+];
+
+pub async fn enter(bot: Bot, msg: Message, state: MainState) -> HandlerResult {
+   let tag = state.tag;
+   let tickets = db::tickets_of_owner(state.user_id).await?;
+
+   bot.send_message(msg.chat.id, text(&tickets, tag))
+   .reply_markup(markup(&tickets, tag))
+   .parse_mode(ParseMode::Html)
+   .await?;
+
+   Ok(())
+}
+
+// Redraw the list in place
+pub async fn refresh(bot: &Bot, q: &CallbackQuery, tag: LocaleTag) -> Result<String, String> {
+   let user_id = q.from.id;
+   let message_id = q.message.as_ref()
+   .map(|m| m.id())
+   .ok_or(format!("queue::refresh no message for user_id={}", user_id))?;
+
+   let tickets = db::tickets_of_owner(user_id).await?;
+   bot.edit_message_text(user_id, message_id, text(&tickets, tag))
+   .reply_markup(markup(&tickets, tag))
+   .parse_mode(ParseMode::Html)
+   .await
+   .map_err(|err| format!("queue::refresh user_id={}: {}", user_id, err))?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn next(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   if let Some(err) = check_owner(&q, ticket_id, tag).await? {
+      return Ok(err);
+   }
+
+   registration::next_ticket(bot, ticket_id, tag).await?;
+   refresh(bot, &q, tag).await
+}
+
+pub async fn cancel(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   if let Some(err) = check_owner(&q, ticket_id, tag).await? {
+      return Ok(err);
+   }
+
+   registration::cancel_ticket(bot, q.clone(), ticket_id, tag).await?;
+   refresh(bot, &q, tag).await
+}
+
+pub async fn refunded(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   if let Some(err) = crate::payment::mark_refunded(bot, &q, ticket_id, tag).await? {
+      return Ok(err);
+   }
+
+   refresh(bot, &q, tag).await
+}
+
+// Error text if the user is not an owner of the ticket or None
+async fn check_owner(q: &CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<Option<String>, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if t.owners.contains(q.from.id) {
+      Ok(None)
+   } else {
+      // "Only the place can manage the order"
+      Ok(Some(loc(Key::QueueCheckOwner, tag, &[])))
+   }
+}
+
+fn text(tickets: &[TicketWithOwners], tag: LocaleTag) -> String {
+   // The time in the title makes each refresh visible
+   let fmt = loc(Key::CommonTimeFormat, tag, &[]);
+   let now = env::current_date_time();
+
+   if tickets.is_empty() {
+      // "There are no active orders, updated at {}"
+      return loc(Key::QueueText1, tag, &[&now.format(&fmt)]);
+   }
+
+   // "Active orders, updated at {}"
+   let init = loc(Key::QueueText2, tag, &[&now.format(&fmt)]);
+
+   let text = STAGES.iter()
+   .fold(init, |acc, stage| {
+      let group: Vec<_> = tickets.iter()
+      .filter(|t| t.ticket.stage == *stage)
+      .collect();
+      if group.is_empty() {
+         return acc;
+      }
+
+      // "{}\n\n<b>{}</b>"
+      let acc = loc(Key::QueueText3, tag, &[&acc, &stage.message_for_owner(tag)]);
+      group.iter()
+      .fold(acc, |acc, t| {
+         let age = t.ticket.created
+         .map(|created| (now - created).num_minutes().to_string())
+         .unwrap_or(String::from("?"));
+         let amount: usize = t.items.iter().map(|item| item.amount).sum();
+         let total = t.total();
+
+         // "{}\n{}: {} min ago, {} pcs., {}"
+         loc(Key::QueueText4, tag, &[&acc, &t.ticket.code(), &age, &amount, &env::price_with_unit(total)])
+      })
+   });
+
+   // Canceled tickets wait only for the refund
+   let refunds: Vec<_> = tickets.iter()
+   .filter(|t| t.ticket.payment == PaymentState::RefundPending)
+   .collect();
+   if refunds.is_empty() {
+      return text;
+   }
+
+   // "{}\n\n<b>💸 Refund pending</b>"
+   let text = loc(Key::QueueText5, tag, &[&text]);
+   refunds.iter()
+   .fold(text, |acc, t| {
+      // "{}\n{}: {} to return"
+      loc(Key::QueueText6, tag, &[&acc, &t.ticket.code(), &env::price_with_unit(t.ticket.paid)])
+   })
+}
+
+fn markup(tickets: &[TicketWithOwners], tag: LocaleTag) -> InlineKeyboardMarkup {
+   let button = |caption: String, cmd: callback::Command, id: i32| {
+      let args = format!("{}{}", cmd.as_ref(), id);
+      InlineKeyboardButton::callback(caption, args)
+   };
+
+   let markup = STAGES.iter()
+   .flat_map(|stage| tickets.iter().filter(move |t| t.ticket.stage == *stage))
+   .fold(InlineKeyboardMarkup::default(), |acc, t| {
+      let id = t.ticket.id;
+      let code = t.ticket.code();
+      let cancel = button(format!("✖ {}", code), callback::Command::QueueCancel(0), id);
+
+      // The owner moves the ticket forward only at these stages
+      match t.ticket.stage {
+         Stage::OwnersConfirmation
+         | Stage::Scheduled
+         | Stage::Cooking
+         | Stage::Delivery => {
+            let next = button(format!("⏩ {}", code), callback::Command::QueueNext(0), id);
+            acc.append_row(vec![next, cancel])
+         }
+         _ => acc.append_row(vec![cancel]),
+      }
+   });
+
+   let markup = tickets.iter()
+   .filter(|t| t.ticket.payment == PaymentState::RefundPending)
+   .fold(markup, |acc, t| {
+      let refunded = button(format!("💸 {}", t.ticket.code()), callback::Command::QueueRefunded(0), t.ticket.id);
+      acc.append_row(vec![refunded])
+   });
+
+   // "⭮"
+   markup.append_row(vec![button(loc(Key::CallbackRefresh, tag, &[]), callback::Command::QueueRefresh, 0)])
+}
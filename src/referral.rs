@@ -0,0 +1,90 @@
+/* ===============================================================================
+Restaurant menu bot.
+Referral links. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use teloxide::{prelude::*, types::ParseMode, utils::html, };
+
+use crate::database as db;
+use crate::environment as env;
+use crate::loc::*;
+
+// Users invited by the referrer and their finished orders
+pub struct Referral {
+   pub referrer_id: u64,
+   pub name: String,
+   pub users: i64,
+   pub customers: i64, // invited users with at least one finished order
+   pub orders: i64,
+   pub amount: i64,
+}
+
+// Link to the bot with the user who shares it, without a node to open
+pub fn link(user_id: UserId) -> String {
+   format!("{}0_{}", env::link(), user_id)
+}
+
+// Personal link for the user or the summary for the admins
+pub async fn report(bot: &Bot, user_id: UserId, tag: LocaleTag) -> Result<(), String> {
+   let text = if env::is_admin_id(user_id) {
+      let lines = db::referrals_report(None).await?;
+      if lines.is_empty() {
+         // "No referrals yet"
+         loc(Key::ReferralReport3, tag, &[])
+      } else {
+         // "Referrals"
+         let init = loc(Key::ReferralReport4, tag, &[]);
+         lines.iter()
+         .fold(init, |acc, r| {
+            // "{}\n\n<b>{}</b> /msg{}\nInvited: {}, ordered: {}\nFinished orders: {} for {}"
+            loc(Key::ReferralReport5, tag, &[&acc, &html::escape(&r.name), &r.referrer_id,
+               &r.users, &r.customers, &r.orders, &env::price_with_unit(r.amount as usize)
+            ])
+         })
+      }
+   } else {
+      let lines = db::referrals_report(Some(user_id.0)).await?;
+      let (users, customers) = lines.first()
+      .map(|r| (r.users, r.customers))
+      .unwrap_or_default();
+
+      // "Your link to invite friends:\n{}\nInvited: {}, of them ordered: {}"
+      let text = loc(Key::ReferralReport1, tag, &[&link(user_id), &users, &customers]);
+      let points = env::referral_points();
+      if points > 0 {
+         // "{}\nFor the first finished order of each friend you get {} loyalty points"
+         loc(Key::ReferralReport2, tag, &[&text, &points])
+      } else {
+         text
+      }
+   };
+
+   bot.send_message(user_id, text)
+   .parse_mode(ParseMode::Html)
+   .await
+   .map_err(|err| format!("referral::report user_id={}: {}", user_id, err))?;
+   Ok(())
+}
+
+// Loyalty points to the referrer for the first finished order of the invited user
+pub async fn reward(bot: &Bot, ticket_id: i32, tag: LocaleTag) -> Result<(), String> {
+   let points = env::referral_points();
+   if points == 0 {
+      return Ok(());
+   }
+
+   if let Some(referrer) = db::points_referral(ticket_id, points).await? {
+      let balance = db::points_balance(referrer).await?;
+
+      // "The friend you invited finished the first order, you got {} loyalty points, the balance is {}"
+      let text = loc(Key::ReferralReward, tag, &[&points, &balance]);
+      if let Err(err) = bot.send_message(UserId(referrer), text).await {
+         env::log(&format!("referral::reward for {}: {}", referrer, err)).await;
+      }
+   }
+   Ok(())
+}
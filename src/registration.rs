@@ -8,14 +8,18 @@ Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
 =============================================================================== */
 
 use teloxide::{prelude::*, payloads::SendMessageSetters,
-   types::{CallbackQuery, ParseMode, Recipient, ChatId, UserId, MessageId, ReplyParameters}
+   types::{CallbackQuery, ParseMode, Recipient, ChatId, UserId, MessageId, ReplyParameters, InlineKeyboardButton, InlineKeyboardMarkup}
 };
 use regex::Regex;
+use chrono::{DateTime, Duration, NaiveDateTime, Timelike};
 use lazy_static::lazy_static;
 
 use crate::database as db;
 use crate::customer::*;
+use crate::geo::Geo;
+use crate::promo::Check;
 use crate::node;
+use crate::courier;
 use crate::ticket::*;
 use crate::environment as env;
 use crate::loc::*;
@@ -35,7 +39,32 @@ enum Role {
 
 pub async fn show_tickets(bot: Bot, user_id: UserId, tag: LocaleTag) -> Result<(), String> {
    let tickets = db::tickets(user_id.0 as i64).await?;
+   show_tickets_list(&bot, user_id, tickets, tag).await
+}
 
+// Owner looks for the ticket by its daily number like "#12-017"
+pub async fn find_ticket(bot: &Bot, user_id: UserId, input: &str, tag: LocaleTag) -> Result<(), String> {
+   let found = match parse_code(input) {
+      Some((node_id, number)) => {
+         db::tickets_by_number(user_id, number).await?
+         .into_iter()
+         .find(|t| t.ticket.matches_node(node_id))
+      }
+      None => None,
+   };
+
+   match found {
+      Some(t) => show_tickets_list(bot, user_id, vec![t], tag).await,
+      None => {
+         // "Order {} not found among the orders of your places"
+         let text = loc(Key::RegFindTicket, tag, &[&input]);
+         send_msg(bot, user_id, &text).await?;
+         Ok(())
+      }
+   }
+}
+
+async fn show_tickets_list(bot: &Bot, user_id: UserId, tickets: Vec<TicketWithOwners>, tag: LocaleTag) -> Result<(), String> {
    for mut t in tickets {
       
       // Detect own role - the owner or client
@@ -52,25 +81,25 @@ pub async fn show_tickets(bot: Bot, user_id: UserId, tag: LocaleTag) -> Result<(
       match role {
          Role::Customer => {
             t.ticket.cust_status_msg_id =
-               update_status(&bot, &mut t, Role::Customer, tag).await?
+               update_status(bot, &mut t, Role::Customer, tag).await?
          }
          Role::Owner1 => {
             t.ticket.owners_status_msg_id.0 =
-               update_status(&bot, &mut t, Role::Owner1, tag)
+               update_status(bot, &mut t, Role::Owner1, tag)
                .await
                .ok()
                .flatten()
          }
          Role::Owner2 => {
             t.ticket.owners_status_msg_id.1 =
-               update_status(&bot, &mut t, Role::Owner2, tag)
+               update_status(bot, &mut t, Role::Owner2, tag)
                .await
                .ok()
                .flatten()
          }
          Role::Owner3 => {
             t.ticket.owners_status_msg_id.2 =
-               update_status(&bot, &mut t, Role::Owner3, tag)
+               update_status(bot, &mut t, Role::Owner3, tag)
                .await
                .ok()
                .flatten()
@@ -103,8 +132,8 @@ async fn update_status(bot: &Bot, t: &mut TicketWithOwners, role: Role, tag: Loc
       Role::Customer => InfoFor::Customer,
       _ => InfoFor::Owner,
    };
-   let text = t.stage_message(info_for, tag);
-   let markup = t.ticket.make_markup(info_for, tag);
+   let text = format!("{}: {}", t.ticket.code(), t.stage_message(info_for, tag));
+   let markup = t.ticket.make_markup(info_for, !t.items.is_empty(), tag);
 
    // Not all owners can exist and, accordingly, there are no message codes
    if order_msg_id.is_none() {
@@ -140,7 +169,7 @@ async fn update_status(bot: &Bot, t: &mut TicketWithOwners, role: Role, tag: Loc
 }
 
 
-pub async fn make_ticket(bot: &Bot, q: CallbackQuery, node_id: i32, tag: LocaleTag) -> Result<String, String> {
+pub async fn make_ticket(bot: &Bot, q: CallbackQuery, node_id: i32, scheduled: Option<NaiveDateTime>, tag: LocaleTag) -> Result<String, String> {
 
    // Load customer info
    let user_id = q.from.id;
@@ -148,7 +177,7 @@ pub async fn make_ticket(bot: &Bot, q: CallbackQuery, node_id: i32, tag: LocaleT
 
    // Load owners node
    let node = db::node(db::LoadNode::EnabledIdNoChildren(node_id)).await?;
-   let owners = if let Some(node) = node { node.owners } else { node::Owners::default() };
+   let (owners, couriers) = if let Some(node) = &node { (node.owners.clone(), node.couriers.clone()) } else { (node::Owners::default(), node::Owners::default()) };
 
    let reply_to_id = if let Some(msg) = &q.message { msg.id() } else { MessageId(0) };
 
@@ -161,6 +190,94 @@ pub async fn make_ticket(bot: &Bot, q: CallbackQuery, node_id: i32, tag: LocaleT
       return Ok(loc(Key::RegMakeTicket2, tag, &[]));
    }
 
+   // The place may want to call the customer
+   let phone_required = node.as_ref().map(|n| n.phone_required).unwrap_or_default();
+   if phone_required && customer.phone.is_none() {
+      // "The place asks for your phone number, please share it in the cart with the 'Contact' button"
+      let text = loc(Key::RegMakeTicket14, tag, &[]);
+      reply_msg(bot, user_id, reply_to_id, &text).await?;
+      // "Unsuccessfully"
+      return Ok(loc(Key::RegMakeTicket2, tag, &[]));
+   }
+
+   // The chosen time must be ahead and within the opening hours
+   if let (Some(time), Some(node)) = (scheduled, &node) {
+      if time <= env::current_date_time() || !node.is_open_at(time.time()) {
+         // "The place does not work at {}, please choose another time"
+         let fmt = loc(Key::CommonDateTimeFormat, tag, &[]);
+         let text = loc(Key::RegMakeTicket8, tag, &[&time.format(&fmt)]);
+         reply_msg(bot, user_id, reply_to_id, &text).await?;
+         // "Unsuccessfully"
+         return Ok(loc(Key::RegMakeTicket2, tag, &[]));
+      }
+   }
+
+   // Without the chosen time the place must be open now
+   let now = env::current_date_time();
+   if let (None, Some(node)) = (scheduled, &node) {
+      if !node.is_open_at(now.time()) {
+         // "The place is closed now, you can order for later"
+         let text = loc(Key::RegMakeTicket9, tag, &[]);
+         reply_msg(bot, user_id, reply_to_id, &text).await?;
+         // "Unsuccessfully"
+         return Ok(loc(Key::RegMakeTicket2, tag, &[]));
+      }
+   }
+
+   // The cart must match the current menu, otherwise the customer confirms the changes
+   let (changes, remaining) = check_orders(user_id, node_id, scheduled.unwrap_or(now), tag).await?;
+   if !changes.is_empty() || remaining == 0 {
+      refresh_order(bot, &q, node_id, tag).await?;
+      // "The menu has changed since you added to the cart:{}\nPlease check the updated order above and confirm it again"
+      let text = loc(Key::RegMakeTicket10, tag, &[&changes]);
+      reply_msg(bot, user_id, reply_to_id, &text).await?;
+      // "Order changed, confirmation required"
+      return Ok(loc(Key::RegMakeTicket11, tag, &[]));
+   }
+
+   // The promo code could expire or be used up since it was entered
+   let orders = db::orders(user_id.0 as i64).await?;
+   if let Some(promo) = orders.promos.get(&node_id) {
+      if !matches!(db::promo_check(&promo.code, node_id, user_id.0).await?, Check::Valid(_)) {
+         db::order_update_promo(user_id.0, node_id, None).await?;
+         refresh_order(bot, &q, node_id, tag).await?;
+         // "Promo code {} is no longer valid and was removed, please check the updated order above and confirm it again"
+         let text = loc(Key::RegMakeTicket15, tag, &[&promo.code]);
+         reply_msg(bot, user_id, reply_to_id, &text).await?;
+         // "Order changed, confirmation required"
+         return Ok(loc(Key::RegMakeTicket11, tag, &[]));
+      }
+   }
+   let cost = orders.cost(node_id);
+   let discount = orders.discount(node_id, cost);
+   let points = orders.points(node_id);
+   let mut delivery_fee = 0;
+
+   // Delivery by courier only within the zone and from the minimum order amount
+   if customer.is_courier() {
+      if let Some((owner, order)) = orders.data.iter().find(|(owner, _)| owner.id == node_id) {
+         if !owner.delivery.is_in_zone(customer.geo.as_ref()) {
+            // "The place does not deliver to your location, please choose pickup"
+            let text = loc(Key::RegMakeTicket13, tag, &[]);
+            reply_msg(bot, user_id, reply_to_id, &text).await?;
+            // "Unsuccessfully"
+            return Ok(loc(Key::RegMakeTicket2, tag, &[]));
+         }
+
+         let cost: usize = order.iter().map(|item| item.cost()).sum();
+         if !owner.delivery.is_enough(cost) {
+            // "The minimum order for delivery is {}, please add more or choose pickup"
+            let text = loc(Key::RegMakeTicket12, tag, &[&env::price_with_unit(owner.delivery.min_order)]);
+            reply_msg(bot, user_id, reply_to_id, &text).await?;
+            // "Unsuccessfully"
+            return Ok(loc(Key::RegMakeTicket2, tag, &[]));
+         }
+
+         // The fee is fixed with the ticket as in the cart::delivery_text()
+         delivery_fee = owner.delivery.fee_for(cost - discount - points, customer.geo.as_ref());
+      }
+   }
+
    // Get source message text and id
    let ref_m = q.message.as_ref()
       .and_then(|f| f.regular_message());
@@ -179,74 +296,209 @@ pub async fn make_ticket(bot: &Bot, q: CallbackQuery, node_id: i32, tag: LocaleT
    let orig_msg_id = ref_m.unwrap().id; // unwrap checked above
 
    // Check delivery address if not pickup
-   if matches!(customer.delivery, Delivery::Courier) {
-
-      match customer.is_location() {
-         true => {
-            // Send to owner a message with the geographic location to make sure it's still available
-            let message_id = customer.location_id().unwrap_or(MessageId(0));
-            let res = forward_msg_to_owners(&bot, user_id, &owners, message_id).await;
-            if let Err(err) = res {
-               // "Location message unavailable, please update address\n<i>{}</i>"
-               let text = loc(Key::RegMakeTicket4, tag, &[&err]);
-               reply_msg(bot, user_id, reply_to_id, &text).await?;
-               // "Unsuccessfully"
-               return Ok(loc(Key::RegMakeTicket2, tag, &[]));
-            }
-         }
+   if matches!(customer.delivery, Delivery::Courier) && customer.geo.is_none() && !customer.has_address() {
+      // "Please enter an address or switch to pickup using the buttons below.\nThis information will be saved for future orders, you can always change it if necessary"
+      let text = loc(Key::RegMakeTicket5, tag, &[]);
+      reply_msg(bot, user_id, reply_to_id, &text).await?;
+      // "Unsuccessfully"
+      return Ok(loc(Key::RegMakeTicket2, tag, &[]));
+   }
 
-         false => {
-            if customer.address.len() < 1 {
-               // "Please enter an address or switch to pickup using the buttons below.\nThis information will be saved for future orders, you can always change it if necessary"
-               let text = loc(Key::RegMakeTicket5, tag, &[]);
-               reply_msg(bot, user_id, reply_to_id, &text).await?;
-               // "Unsuccessfully"
-               return Ok(loc(Key::RegMakeTicket2, tag, &[]));
-            }
-         }
+   // Delete data from orders and create ticket, the items are taken from the stock before anyone is notified
+   let checkout = Checkout {
+      pickup: matches!(customer.delivery, Delivery::Pickup),
+      scheduled,
+      delivery_fee,
+      discount,
+      points,
+   };
+   let mut ticket = match db::ticket_form_orders(node_id, user_id, checkout, orig_msg_id).await? {
+      Some(ticket) => ticket,
+      None => {
+         // Someone else has taken the items since the check above
+         let (changes, _) = check_orders(user_id, node_id, scheduled.unwrap_or(now), tag).await?;
+         refresh_order(bot, &q, node_id, tag).await?;
+         // "The menu has changed since you added to the cart:{}\nPlease check the updated order above and confirm it again"
+         let text = loc(Key::RegMakeTicket10, tag, &[&changes]);
+         reply_msg(bot, user_id, reply_to_id, &text).await?;
+         // "Order changed, confirmation required"
+         return Ok(loc(Key::RegMakeTicket11, tag, &[]));
+      }
+   };
+
+   // Nobody knows about the ticket if the order cannot be sent, so it is canceled
+   match send_ticket(bot, user_id, &customer, &owners, orig_msg_id, &old_text, tag).await {
+      Ok((owners_msg_id, service_msg_id)) => {
+         ticket.owners_msg_id = owners_msg_id;
+         ticket.service_msg_id = service_msg_id;
+         db::ticket_update_msg_ids(&ticket).await?;
       }
+      Err(err) => {
+         db::ticket_update_stage(ticket.id, Stage::CanceledByOwner).await?;
+         db::nodes_restore_stock(ticket.id).await?;
+         db::points_return(ticket.id).await?;
+         return Err(err);
+      }
+   }
+
+   let items = db::ticket_items(ticket.id).await?;
+   let t = TicketWithOwners {
+      ticket,
+      owners,
+      couriers,
+      items,
+   };
+
+   // Invoice for the online payment, the order is valid without it
+   if let Err(err) = crate::payment::offer(bot, &t, tag).await {
+      env::log(&format!("make_ticket offer payment: {}", err)).await;
    }
 
+   // Send messages with status to customer and owners
+   update_statuses(bot, t, tag).await?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+// Show the order to the customer without commands and send it to the owners and the service chat, returns ids of the messages
+async fn send_ticket(bot: &Bot, user_id: UserId, customer: &Customer, owners: &node::Owners, orig_msg_id: MessageId, old_text: &str, tag: LocaleTag) -> Result<(ThreeMsgId, Option<MessageId>), String> {
    // Edit the original message - remove commands from text
    lazy_static! {
-      static ref HASHTAG_REGEX : Regex = Regex::new(r" /del\d+").unwrap();
+      static ref HASHTAG_REGEX : Regex = Regex::new(r" (✏ |💬 |🏷 |⭐ )?/(del|note|comm|promo|points)\d+").unwrap();
    }
-   let order_info = HASHTAG_REGEX.replace_all(&old_text, "").to_string();
+   let order_info = HASHTAG_REGEX.replace_all(old_text, "").to_string();
 
-   let cust_msg_id = bot.edit_message_text(user_id, orig_msg_id, &order_info)
+   bot.edit_message_text(user_id, orig_msg_id, &order_info)
    .await
-   .map_err(|err| format!("make_ticket edit_message user_id={} {}", user_id, err))?.id;
+   .map_err(|err| format!("make_ticket edit_message user_id={} {}", user_id, err))?;
+
+   // Send to owner the point on the map from the saved coordinates, the address is also in the text below
+   if let (Delivery::Courier, Some(geo)) = (&customer.delivery, customer.geo) {
+      if let Err(err) = send_location_to_owners(bot, owners, geo).await {
+         // "Location message unavailable, please update address\n<i>{}</i>"
+         let text = loc(Key::RegMakeTicket4, tag, &[&err]);
+         reply_msg(bot, user_id, orig_msg_id, &text).await?;
+      }
+   }
 
    // Send to owner info about customer "Order from {}:\nContact for communication: {}\nDelivery method: {}"
    let customer_info = loc(Key::RegMakeTicket6, tag, &[
       &customer.name,
-      &customer.contact,
+      &customer.contact_desc(tag),
       &customer.delivery_desc(tag)
    ]);
-   send_msg_to_owners(&bot, &owners, &customer_info).await?;
+   send_msg_to_owners(bot, owners, &customer_info).await?;
 
    // Forward edited message with order and save msg id
-   let owners_msg_id = forward_msg_to_owners(&bot, user_id, &owners, orig_msg_id).await?;
+   let owners_msg_id = forward_msg_to_owners(bot, user_id, owners, orig_msg_id).await?;
 
    // Send the order also to the service chat
    let service_msg_id = env::log(&format!("{}\n---\n{}", customer_info, order_info)).await;
 
-   // Delete data from orders and create ticket with owners
-   let ticket = db::ticket_form_orders(node_id, user_id, owners_msg_id, cust_msg_id, service_msg_id).await?;
+   Ok((owners_msg_id, service_msg_id))
+}
 
-   let t = TicketWithOwners {
-      ticket,
-      owners,
+// Remove unavailable items from the cart and update prices, returns description of changes and number of remaining lines
+async fn check_orders(user_id: UserId, node_id: i32, time: NaiveDateTime, tag: LocaleTag) -> Result<(String, usize), String> {
+   let lines = db::orders_of_owner(user_id.0, node_id).await?;
+
+   let mut changes = String::default();
+   let mut remaining = 0;
+   for line in lines {
+      let item = db::node(db::LoadNode::EnabledIdNoChildren(line.node_id)).await?;
+
+      // Bundles need the complete choice of the components
+      let item = match item {
+         Some(item) if item.is_open_at(time.time()) && !item.is_sold_out() => {
+            db::bundle_price(&item, user_id.0).await?.map(|(price, _)| (price, item.stock))
+         }
+         _ => None,
+      };
+
+      match item {
+         Some((actual, stock)) => {
+            remaining += 1;
+
+            // No more than left in stock
+            if let Some(stock) = stock.filter(|stock| line.amount > *stock) {
+               db::order_update_amount(user_id.0, line.node_id, stock).await?;
+               // "{}\n{}: only {} left"
+               changes = loc(Key::RegCheckOrders4, tag, &[&changes, &line.title, &stock]);
+            }
+
+            match line.price {
+               Some(price) if price != actual => {
+                  db::order_update_price(user_id.0, line.node_id, actual).await?;
+                  // "{}\n{}: price {} → {}"
+                  changes = loc(Key::RegCheckOrders2, tag, &[&changes, &line.title, &env::price_with_unit(price), &env::price_with_unit(actual)]);
+               }
+               // The lines of the previous versions get the price silently to keep it with the ticket
+               None => db::order_update_price(user_id.0, line.node_id, actual).await?,
+               _ => {}
+            }
+         }
+         _ => {
+            db::order_delete_node(user_id.0, line.node_id).await?;
+            // "{}\n{}: no longer available"
+            changes = loc(Key::RegCheckOrders1, tag, &[&changes, &line.title]);
+         }
+      }
+   }
+
+   Ok((changes, remaining))
+}
+
+// Switch the delivery to the next address from the book
+pub async fn next_address(bot: &Bot, q: CallbackQuery, node_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let user_id = q.from.id;
+   let customer = db::user(user_id.0).await?;
+   let book = db::addresses(user_id.0).await?;
+
+   let pos = book.iter().position(|a| Some(a.id) == customer.address_id);
+   let next = match pos {
+      Some(pos) => book.get(pos + 1).or(book.first()),
+      None => book.first(),
    };
 
-   // Send messages with status to customer and owners
-   update_statuses(bot, t, tag).await?;
+   if next.is_none() {
+      // "Unsuccessfully"
+      return Ok(loc(Key::RegMakeTicket2, tag, &[]));
+   }
+   let next = next.unwrap();
 
-   // "Successfully"
-   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+   db::address_select(user_id.0, next.id).await?;
+   refresh_order(bot, &q, node_id, tag).await?;
+
+   // "Delivery to {}"
+   Ok(loc(Key::RegNextAddress, tag, &[&next.label]))
 }
 
-async fn update_statuses(bot: &Bot, mut t: TicketWithOwners, tag: LocaleTag) -> Result<(), String> {
+// Redraw the order message from the current cart
+async fn refresh_order(bot: &Bot, q: &CallbackQuery, node_id: i32, tag: LocaleTag) -> Result<(), String> {
+   let user_id = q.from.id;
+   let message_id = q.message.as_ref()
+   .map(|m| m.id())
+   .ok_or(format!("registration::refresh_order no message for user_id={}", user_id))?;
+
+   let orders = db::orders(user_id.0 as i64).await?;
+   let customer = db::user(user_id.0).await?;
+   let address = crate::cart::checkout_address(user_id.0, &customer).await?;
+   let (text, markup) = match orders.data.iter().find(|(owner, _)| owner.id == node_id) {
+      Some((owner, order)) => (crate::cart::make_owner_text(owner, order, &orders, &customer, tag), crate::cart::order_markup(node_id, address.as_deref(), tag)),
+      // "Nothing left in the order"
+      None => (loc(Key::RegCheckOrders3, tag, &[]), InlineKeyboardMarkup::default()),
+   };
+
+   bot.edit_message_text(user_id, message_id, text)
+   .reply_markup(markup)
+   .parse_mode(ParseMode::Html)
+   .await
+   .map_err(|err| format!("registration::refresh_order user_id={}: {}", user_id, err))?;
+   Ok(())
+}
+
+pub async fn update_statuses(bot: &Bot, mut t: TicketWithOwners, tag: LocaleTag) -> Result<(), String> {
 
    // The status change for customer is mandatory
    t.ticket.cust_status_msg_id = update_status(bot, &mut t, Role::Customer, tag).await?;
@@ -285,12 +537,23 @@ pub async fn cancel_ticket(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: Loc
 
    // Load ticket and update status
    let mut t = db::ticket_with_owners(ticket_id).await?;
+   let was_active = t.ticket.stage.is_active();
    t.ticket.stage = if q.from.id == t.ticket.customer_id {
       Stage::CanceledByCustomer
    } else {
       Stage::CanceledByOwner
    };
    db::ticket_update_stage(t.ticket.id, t.ticket.stage).await?;
+   courier::stop_relay(bot, &t).await?;
+
+   // The items are available again
+   if was_active {
+      db::nodes_restore_stock(t.ticket.id).await?;
+      db::points_return(t.ticket.id).await?;
+   }
+
+   // The money for the paid order must be returned
+   crate::payment::request_refund(bot, &mut t, tag).await?;
 
    let service_msg_id = t.ticket.service_msg_id;
    let stage = t.ticket.stage;
@@ -311,6 +574,15 @@ pub async fn next_ticket(bot: &Bot, ticket_id: i32, tag: LocaleTag) -> Result<St
    if t.ticket.next_stage() {
       // Update status in database if it was really changed
       db::ticket_update_stage(t.ticket.id, t.ticket.stage).await?;
+      db::ticket_update_eta(t.ticket.id, t.ticket.eta).await?;
+
+      // Look for someone to deliver the order
+      if matches!(t.ticket.stage, Stage::Delivery) && !t.ticket.pickup {
+         offer_delivery(bot, &t, tag).await;
+      }
+
+      // The customer no longer needs to follow the courier
+      courier::stop_relay(bot, &t).await?;
    }
 
    update_statuses(bot, t, tag).await?;
@@ -326,16 +598,411 @@ pub async fn confirm_ticket(bot: &Bot, ticket_id: i32, tag: LocaleTag) -> Result
    db::ticket_update_stage(t.ticket.id, t.ticket.stage).await?;
 
    let service_msg_id = t.ticket.service_msg_id;
+   let ticket = t.ticket.clone();
+   let cost = t.cost();
    update_statuses(bot, t, tag).await?;
 
+   // Reward the customer with loyalty points by the rate of the place
+   let earned = db::points_award(ticket.id, ticket.customer_id.0, cost).await?;
+   if earned > 0 {
+      let balance = db::points_balance(ticket.customer_id.0).await?;
+      // "Order {}: {} loyalty points earned, the balance is {}"
+      let text = loc(Key::RegConfirmTicket2, tag, &[&ticket.code(), &earned, &balance]);
+      if let Err(err) = bot.send_message(ticket.customer_id, text).await {
+         env::log(&format!("confirm_ticket points for {}: {}", ticket.customer_id, err)).await;
+      }
+   }
+   crate::referral::reward(bot, ticket.id, tag).await?;
+
    // Send the order also to the service chat "Order completed successfully"
    let status = loc(Key::RegConfirmTicket, tag, &[]);
    env::log_reply(&status, service_msg_id).await;
 
+   // Offer the customer to thank the staff and ask for a rating, one does not depend on the other
+   if let Err(err) = crate::tip::ask(bot, &ticket, cost, tag).await {
+      env::log(&format!("confirm_ticket tip: {}", err)).await;
+   }
+   crate::review::ask(bot, &ticket, tag).await?;
+
    // "Successfully"
    Ok(loc(Key::RegMakeTicket7, tag, &[]))
 }
 
+// The slot goes to the callback as the absolute time in minutes, the button may be pressed on another day
+pub fn slot_to_minutes(time: NaiveDateTime) -> Option<i32> {
+   i32::try_from(time.and_utc().timestamp() / 60).ok()
+}
+
+pub fn slot_from_minutes(minutes: i32) -> Option<NaiveDateTime> {
+   DateTime::from_timestamp(minutes as i64 * 60, 0).map(|time| time.naive_utc())
+}
+
+// Order for the chosen slot if it has not passed yet
+pub async fn make_ticket_at(bot: &Bot, q: CallbackQuery, node_id: i32, minutes: i32, tag: LocaleTag) -> Result<String, String> {
+   match slot_from_minutes(minutes).filter(|time| *time > env::current_date_time()) {
+      Some(time) => make_ticket(bot, q, node_id, Some(time), tag).await,
+      // "There is no time available for ordering"
+      None => Ok(loc(Key::RegSelectTime1, tag, &[])),
+   }
+}
+
+// Show the customer the time within opening hours to order for later
+pub async fn select_time(bot: &Bot, q: CallbackQuery, node_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let node = db::node(db::LoadNode::EnabledIdNoChildren(node_id)).await?;
+   let node = match node {
+      Some(node) => node,
+      // "Unsuccessfully"
+      None => return Ok(loc(Key::RegMakeTicket2, tag, &[])),
+   };
+
+   // The nearest half hours within a day, but no earlier than the place needs to prepare
+   let now = env::current_date_time();
+   let midnight = now.date().and_hms_opt(0, 0, 0).unwrap_or_default();
+   let minutes = (now.time().num_seconds_from_midnight() / 60) as i64;
+   let first = ((minutes + env::schedule_lead()) / 30 + 1) * 30;
+   let fmt = loc(Key::CommonTimeFormat, tag, &[]);
+   let buttons: Vec<_> = (0..48)
+   .map(|i| midnight + Duration::minutes(first + i * 30))
+   .filter(|time| node.is_open_at(time.time()))
+   .filter_map(|time| {
+      let args = format!("{}{}_{}", crate::callback::Command::TicketMakeAt(0, 0).as_ref(), node_id, slot_to_minutes(time)?);
+      Some(InlineKeyboardButton::callback(format!("🕒{}", time.format(&fmt)), args))
+   })
+   .take(8)
+   .collect();
+
+   if buttons.is_empty() {
+      // "There is no time available for ordering"
+      return Ok(loc(Key::RegSelectTime1, tag, &[]));
+   }
+
+   // Keep the order text, only add the time below the usual buttons
+   let customer = db::user(q.from.id.0).await?;
+   let address = crate::cart::checkout_address(q.from.id.0, &customer).await?;
+   let markup = buttons.chunks(4)
+   .fold(crate::cart::order_markup(node_id, address.as_deref(), tag), |acc, row| acc.append_row(row.to_vec()));
+
+   let message_id = q.message.as_ref()
+   .map(|m| m.id())
+   .ok_or(format!("registration::select_time no message for user_id={}", q.from.id))?;
+   bot.edit_message_reply_markup(q.from.id, message_id)
+   .reply_markup(markup)
+   .await
+   .map_err(|err| format!("registration::select_time user_id={}: {}", q.from.id, err))?;
+
+   // "Choose the time for your order below"
+   Ok(loc(Key::RegSelectTime2, tag, &[]))
+}
+
+// Order for later goes to work in advance
+pub async fn start_scheduled(bot: &Bot, mut t: TicketWithOwners, tag: LocaleTag) -> Result<(), String> {
+   t.ticket.stage = Stage::Cooking;
+   db::ticket_update_stage(t.ticket.id, t.ticket.stage).await?;
+
+   // "Order {} is due at {}, time to start"
+   let fmt = loc(Key::CommonTimeFormat, tag, &[]);
+   let time = t.ticket.scheduled.map(|time| time.format(&fmt).to_string()).unwrap_or_default();
+   let text = loc(Key::RegStartScheduled, tag, &[&t.ticket.code(), &time]);
+   send_msg_to_owners(bot, &t.owners, &text).await?;
+
+   update_statuses(bot, t, tag).await
+}
+
+// Error text if the user cannot change the ticket or None
+fn check_editable(t: &TicketWithOwners, user_id: UserId, tag: LocaleTag) -> Option<String> {
+   if !t.owners.contains(user_id) {
+      // "Only the place can change the order"
+      Some(loc(Key::RegEditTicket2, tag, &[]))
+   } else if !matches!(t.ticket.stage, Stage::OwnersConfirmation) {
+      // "The order can only be changed before it is accepted"
+      Some(loc(Key::RegEditTicket3, tag, &[]))
+   } else if t.items.is_empty() {
+      // "The order was placed before the items were kept with it and cannot be changed"
+      Some(loc(Key::RegEditTicket8, tag, &[]))
+   } else if t.ticket.payment != PaymentState::Unpaid {
+      // "The order has already been paid and cannot be changed"
+      Some(loc(Key::RegEditTicket9, tag, &[]))
+   } else {
+      None
+   }
+}
+
+// Show to owner the changes with commands instead of the status message
+async fn show_editor(bot: &Bot, q: &CallbackQuery, t: &TicketWithOwners, tag: LocaleTag) -> Result<(), String> {
+   // "Changes to the order {}, use the buttons below to decrease, increase, remove or replace the numbered items:{}"
+   let text = loc(Key::RegEditTicket1, tag, &[&t.ticket.code(), &t.items_text(tag)]);
+   edit_msg(bot, q, &text, t.markup_edit(tag)).await
+}
+
+pub async fn edit_ticket(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if let Some(err) = check_editable(&t, q.from.id, tag) {
+      return Ok(err);
+   }
+
+   show_editor(bot, &q, &t, tag).await?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn propose_item(bot: &Bot, q: CallbackQuery, ticket_id: i32, item_id: i32, proposal: db::ItemProposal, tag: LocaleTag) -> Result<String, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if let Some(err) = check_editable(&t, q.from.id, tag) {
+      return Ok(err);
+   }
+
+   // Store and reload the changes
+   db::ticket_item_propose(ticket_id, item_id, proposal).await?;
+   let t = db::ticket_with_owners(ticket_id).await?;
+   show_editor(bot, &q, &t, tag).await?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn select_substitute(bot: &Bot, q: CallbackQuery, ticket_id: i32, item_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if let Some(err) = check_editable(&t, q.from.id, tag) {
+      return Ok(err);
+   }
+
+   // Available items of the same place
+   let candidates = db::ticket_item_candidates(ticket_id, t.ticket.node_id, t.ticket.work_time()).await?;
+   if candidates.is_empty() {
+      // "No other items available for replacement"
+      return Ok(loc(Key::RegEditTicket5, tag, &[]));
+   }
+
+   // "Choose a replacement for '{}'"
+   let title = t.items.iter()
+   .find(|item| item.id == item_id)
+   .map(|item| item.title.clone())
+   .unwrap_or_default();
+   let text = loc(Key::RegEditTicket4, tag, &[&title]);
+   edit_msg(bot, &q, &text, t.markup_substitute(item_id, &candidates, tag)).await?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn substitute_item(bot: &Bot, q: CallbackQuery, ticket_id: i32, old_id: i32, new_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if let Some(err) = check_editable(&t, q.from.id, tag) {
+      return Ok(err);
+   }
+
+   // The item could become unavailable since the list was shown
+   let candidates = db::ticket_item_candidates(ticket_id, t.ticket.node_id, t.ticket.work_time()).await?;
+   let item = match candidates.iter().find(|item| item.node_id == new_id) {
+      Some(item) => item,
+      // "No other items available for replacement"
+      None => return Ok(loc(Key::RegEditTicket5, tag, &[])),
+   };
+
+   // Store and reload the changes
+   db::ticket_item_substitute(ticket_id, old_id, item).await?;
+   let t = db::ticket_with_owners(ticket_id).await?;
+   show_editor(bot, &q, &t, tag).await?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn discard_changes(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if let Some(err) = check_editable(&t, q.from.id, tag) {
+      return Ok(err);
+   }
+
+   // Restore the original order and the status messages instead of editor
+   db::ticket_items_discard(ticket_id).await?;
+   let t = db::ticket_with_owners(ticket_id).await?;
+   update_statuses(bot, t, tag).await?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn propose_changes(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let mut t = db::ticket_with_owners(ticket_id).await?;
+   if let Some(err) = check_editable(&t, q.from.id, tag) {
+      return Ok(err);
+   }
+
+   if t.items.iter().all(|item| item.proposed_amount() == item.amount) {
+      // "There are no changes to send"
+      return Ok(loc(Key::RegEditTicket6, tag, &[]));
+   }
+
+   if t.items.iter().all(|item| item.proposed_amount() == 0) {
+      // "Nothing left in the order, cancel it instead"
+      return Ok(loc(Key::RegEditTicket7, tag, &[]));
+   }
+
+   // Wait for the customer's decision
+   t.ticket.stage = Stage::CustomerApproval;
+   db::ticket_update_stage(t.ticket.id, t.ticket.stage).await?;
+   update_statuses(bot, t, tag).await?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn accept_changes(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if q.from.id != t.ticket.customer_id || !matches!(t.ticket.stage, Stage::CustomerApproval) || t.ticket.payment != PaymentState::Unpaid {
+      // "The changes are no longer relevant"
+      return Ok(loc(Key::RegAcceptChanges2, tag, &[]));
+   }
+
+   // The discount, points and delivery as at the checkout but for the new cost
+   let promo = db::ticket_promo(ticket_id).await?;
+   let terms = if t.ticket.pickup { None } else { Some(db::node_delivery_terms(t.ticket.node_id).await?) };
+   let customer = db::user(t.ticket.customer_id.0).await?;
+   let charges = t.proposed_charges(promo.as_ref(), terms.as_ref(), customer.geo.as_ref());
+
+   // "The customer accepted the changes to the order {}:{}"
+   let text = loc(Key::RegAcceptChanges1, tag, &[&t.ticket.code(), &t.items_text(tag)]);
+
+   // The stock could run out while the customer was deciding, the place has to change the order again
+   if !db::ticket_items_accept(ticket_id, charges).await? {
+      let mut t = t;
+      t.ticket.stage = Stage::OwnersConfirmation;
+      db::ticket_update_stage(t.ticket.id, t.ticket.stage).await?;
+
+      // "Order {}: the customer accepted the changes, but some items are out of stock now, please change the order again"
+      let text = loc(Key::RegAcceptChanges3, tag, &[&t.ticket.code()]);
+      send_msg_to_owners(bot, &t.owners, &text).await?;
+      update_statuses(bot, t, tag).await?;
+
+      // "Some items are out of stock now, the place will change the order again"
+      return Ok(loc(Key::RegAcceptChanges4, tag, &[]));
+   }
+
+   // The owner has already agreed, so the ticket goes to work
+   let mut t = db::ticket_with_owners(ticket_id).await?;
+   t.ticket.stage = t.ticket.stage_after_confirmation();
+   db::ticket_update_stage(t.ticket.id, t.ticket.stage).await?;
+
+   // Notify owners about the agreed order
+   send_msg_to_owners(bot, &t.owners, &text).await?;
+
+   // The invoice sent at the checkout is for the old amount
+   if let Err(err) = crate::payment::offer(bot, &t, tag).await {
+      env::log(&format!("accept_changes offer payment: {}", err)).await;
+   }
+   update_statuses(bot, t, tag).await?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn set_eta(bot: &Bot, q: CallbackQuery, ticket_id: i32, minutes: i32, tag: LocaleTag) -> Result<String, String> {
+   let mut t = db::ticket_with_owners(ticket_id).await?;
+   if !t.owners.contains(q.from.id) || !matches!(t.ticket.stage, Stage::Cooking | Stage::Delivery) {
+      // "The estimated time can only be set by the place for an order in progress"
+      return Ok(loc(Key::RegSetEta, tag, &[]));
+   }
+
+   // Store the time and show it to everyone
+   let eta = env::current_date_time() + Duration::minutes(minutes as i64);
+   t.ticket.eta = Some(eta);
+   db::ticket_update_eta(t.ticket.id, t.ticket.eta).await?;
+   update_statuses(bot, t, tag).await?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn remind_eta(bot: &Bot, t: TicketWithOwners, tag: LocaleTag) -> Result<(), String> {
+   // "Order {}: the estimated time {} has passed, please move the order to the next stage or specify a new time"
+   let fmt = loc(Key::CommonTimeFormat, tag, &[]);
+   let eta = t.ticket.eta.map(|eta| eta.format(&fmt).to_string()).unwrap_or_default();
+   let text = loc(Key::RegRemindEta, tag, &[&t.ticket.code(), &eta]);
+   send_msg_to_owners(bot, &t.owners, &text).await?;
+
+   // Remind only once, but try again next time if nobody got it
+   db::ticket_eta_reminded(t.ticket.id).await
+}
+
+// Send couriers of the place an offer to take the delivery, ignore fail
+async fn offer_delivery(bot: &Bot, t: &TicketWithOwners, tag: LocaleTag) {
+   // "Order {} is ready, who will deliver it?"
+   let text = loc(Key::RegOfferDelivery, tag, &[&t.ticket.code()]);
+   let couriers = [t.couriers.0, t.couriers.1, t.couriers.2];
+   for courier_id in couriers.into_iter().filter(|id| id.0 > node::Owners::VALID_USER_ID) {
+      let res = bot.send_message(courier_id, &text)
+      .reply_markup(t.ticket.markup_take(tag))
+      .await;
+      if let Err(err) = res {
+         env::log(&format!("registration::offer_delivery courier_id={}: {}", courier_id, err)).await;
+      }
+   }
+}
+
+pub async fn take_delivery(bot: &Bot, q: CallbackQuery, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   let courier_id = q.from.id;
+   if !t.couriers.contains(courier_id) {
+      // "Only the couriers of the place can take the delivery"
+      return Ok(loc(Key::RegTakeDelivery1, tag, &[]));
+   }
+   if !matches!(t.ticket.stage, Stage::Delivery) {
+      // "The delivery is no longer relevant"
+      return Ok(loc(Key::RegTakeDelivery2, tag, &[]));
+   }
+
+   // The first one gets the order
+   if t.ticket.courier_id.is_some() || !db::ticket_assign_courier(ticket_id, courier_id).await? {
+      // "Another courier has already taken the delivery"
+      return Ok(loc(Key::RegTakeDelivery3, tag, &[]));
+   }
+
+   // Give the courier the customer's details, "Order {} is yours.\nCustomer: {}\nContact for communication: {}\nDelivery method: {}"
+   let customer = db::user(t.ticket.customer_id.0).await?;
+   let text = loc(Key::RegTakeDelivery4, tag, &[
+      &t.ticket.code(),
+      &customer.name,
+      &customer.contact_desc(tag),
+      &customer.delivery_desc(tag)
+   ]);
+   let msg = send_msg(bot, courier_id, &text).await?;
+
+   // The point on the map in addition to the text
+   if let Some(geo) = customer.geo {
+      send_location(bot, courier_id, geo).await?;
+   }
+
+   // Courier reports the delivery himself, "Share your live location with the paperclip 📎 so the customer can follow you"
+   let text = format!("{}\n{}", t.ticket.stage.message_for_owner(tag), loc(Key::RegTakeDelivery6, tag, &[]));
+   bot.send_message(courier_id, text)
+   .reply_parameters(ReplyParameters::new(msg.id))
+   .reply_markup(t.ticket.markup_delivered(tag))
+   .await
+   .map_err(|err| format!("registration::take_delivery markup courier_id={}: {}", courier_id, err))?;
+
+   // Tell the customer and owners who is coming, "Order {}: delivered by {}"
+   let text = loc(Key::RegTakeDelivery5, tag, &[&t.ticket.code(), &q.from.full_name()]);
+   send_msg(bot, t.ticket.customer_id, &text).await?;
+   send_msg_to_owners(bot, &t.owners, &text).await?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+async fn edit_msg(bot: &Bot, q: &CallbackQuery, text: &str, markup: InlineKeyboardMarkup) -> Result<(), String> {
+   let message_id = q.message.as_ref()
+   .map(|m| m.id())
+   .ok_or(format!("registration::edit_msg no message for user_id={}", q.from.id))?;
+
+   bot.edit_message_text(q.from.id, message_id, text)
+   .reply_markup(markup)
+   .await
+   .map_err(|err| format!("registration::edit_msg for user_id={} {}", q.from.id, err))?;
+   Ok(())
+}
+
 async fn reply_msg(bot: &Bot, receiver: UserId, reply_to_id: MessageId, text: &str) -> Result<(), String> {
    let mut fut = bot.send_message(receiver, text)
    .parse_mode(ParseMode::Html);
@@ -364,6 +1031,22 @@ async fn forward_msg(bot: &Bot, from: UserId, receiver: UserId, message_id: Mess
    Ok(res)
 }
 
+async fn send_location(bot: &Bot, receiver: UserId, geo: Geo) -> ResultMessage {
+   let res = bot.send_location(receiver, geo.latitude, geo.longitude)
+   .await
+   .map_err(|err| format!("registration::send_location for receiver={} {}", receiver, err))?;
+   Ok(res)
+}
+
+async fn send_location_to_owners(bot: &Bot, owners: &node::Owners, geo: Geo) -> Result3Id {
+   // Try to send to all owners
+   let msg1 = send_location(bot, owners.0, geo).await;
+   let msg2 = send_location(bot, owners.1, geo).await;
+   let msg3 = send_location(bot, owners.2, geo).await;
+
+   unwrap_msg_id(msg1, msg2, msg3)
+}
+
 async fn send_msg_to_owners(bot: &Bot, owners: &node::Owners, text: &str) -> Result3Id {
    // Try to send to all owners
    let msg1 = send_msg(bot, owners.0, text).await;
@@ -396,3 +1079,23 @@ fn unwrap_msg_id(msg1: ResultMessage, msg2: ResultMessage, msg3: ResultMessage)
    );
    Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use chrono::NaiveDate;
+
+   #[test]
+   fn slot_keeps_the_day() {
+      let time = NaiveDate::from_ymd_opt(2026, 10, 20).unwrap().and_hms_opt(0, 30, 0).unwrap();
+      let minutes = slot_to_minutes(time).unwrap();
+      assert_eq!(slot_from_minutes(minutes), Some(time));
+   }
+
+   #[test]
+   fn slot_after_midnight_differs_from_same_time_today() {
+      let today = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap().and_hms_opt(0, 30, 0).unwrap();
+      let tomorrow = today + Duration::days(1);
+      assert_eq!(slot_to_minutes(tomorrow).unwrap() - slot_to_minutes(today).unwrap(), 1440);
+   }
+}
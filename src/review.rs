@@ -0,0 +1,145 @@
+/* ===============================================================================
+Restaurant menu bot.
+Customer's rating and review of the completed ticket. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use teloxide::{prelude::*,
+   types::{CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup, },
+};
+
+use crate::states::*;
+use crate::database as db;
+use crate::environment as env;
+use crate::callback;
+use crate::node;
+use crate::ticket::*;
+use crate::loc::*;
+
+// Average rating of the place
+pub struct Rating {
+   pub average: f64,
+   pub count: i64,
+}
+
+#[derive(Clone)]
+pub struct ReviewState {
+   pub prev_state: Box<State>,
+   pub tag: LocaleTag,
+   pub ticket_id: i32,
+}
+
+// Offer the customer to rate the finished ticket
+pub async fn ask(bot: &Bot, t: &Ticket, tag: LocaleTag) -> Result<(), String> {
+   let stars = (1..=5)
+   .map(|n| {
+      let args = format!("{}{}_{}", callback::Command::TicketRate(0, 0).as_ref(), t.id, n);
+      InlineKeyboardButton::callback(stars(n), args)
+   })
+   .collect::<Vec<_>>();
+
+   // "Order {} is completed, please rate it"
+   let text = loc(Key::ReviewAsk, tag, &[&t.code()]);
+   bot.send_message(t.customer_id, text)
+   .reply_markup(InlineKeyboardMarkup::default().append_row(stars))
+   .await
+   .map_err(|err| format!("review::ask customer_id={}: {}", t.customer_id, err))?;
+   Ok(())
+}
+
+pub async fn rate(bot: &Bot, q: CallbackQuery, ticket_id: i32, rating: i32, tag: LocaleTag) -> Result<String, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if q.from.id != t.ticket.customer_id || t.ticket.stage != Stage::Finished {
+      // "Only the customer can rate the order"
+      return Ok(loc(Key::ReviewRate1, tag, &[]));
+   }
+
+   let rating = rating.clamp(1, 5);
+   db::review_rate(ticket_id, t.ticket.node_id, rating).await?;
+
+   // Replace the stars with the offer to write a review
+   let code = t.ticket.code();
+   if let Some(msg) = &q.message {
+      let args = format!("{}{}", callback::Command::TicketReview(0).as_ref(), ticket_id);
+      let button = InlineKeyboardButton::callback(loc(Key::CallbackReview, tag, &[]), args); // "✍ Write a review"
+
+      // "Order {}: thank you for the rating {}! You can also write a review"
+      let text = loc(Key::ReviewRate2, tag, &[&code, &stars(rating)]);
+      bot.edit_message_text(q.from.id, msg.id(), text)
+      .reply_markup(InlineKeyboardMarkup::default().append_row(vec![button]))
+      .await
+      .map_err(|err| format!("review::rate user_id={}: {}", q.from.id, err))?;
+   }
+
+   // "Order {}: the customer rated {}"
+   let text = loc(Key::ReviewRate3, tag, &[&code, &stars(rating)]);
+   send_to_owners(bot, &t.owners, &text).await;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn enter(bot: &Bot, q: CallbackQuery, dialogue: MyDialogue, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let user_id = q.from.id;
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if user_id != t.ticket.customer_id {
+      // "Only the customer can rate the order"
+      return Ok(loc(Key::ReviewRate1, tag, &[]));
+   }
+
+   // Return to the same place after review
+   let prev_state = resume_state(&dialogue, user_id, tag).await?;
+   let new_state = ReviewState { prev_state, tag, ticket_id };
+   dialogue.update(new_state)
+   .await
+   .map_err(|err| format!("review::enter dialogue user_id={} {}", user_id, err))?;
+
+   // "Order {}: write your review or press / to cancel"
+   let text = loc(Key::ReviewEnter, tag, &[&t.ticket.code()]);
+   bot.send_message(user_id, text)
+   .reply_markup(cancel_markup(tag))
+   .await
+   .map_err(|err| format!("review::enter user_id={} {}", user_id, err))?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn update(bot: Bot, msg: Message, dialogue: MyDialogue, state: ReviewState) -> HandlerResult {
+   let tag = state.tag;
+   let input = msg.text().unwrap_or_default();
+
+   let text = if input.is_empty() || input == loc(Key::CommonCancel, tag, &[]) {
+      // "Review cancelled"
+      loc(Key::ReviewUpdate1, tag, &[])
+   } else {
+      let t = db::ticket_with_owners(state.ticket_id).await?;
+      let rating = db::review_update_text(state.ticket_id, input).await?;
+
+      // "Order {}: review from the customer {}\n{}"
+      let text = loc(Key::ReviewUpdate3, tag, &[&t.ticket.code(), &stars(rating), &input]);
+      send_to_owners(&bot, &t.owners, &text).await;
+
+      // "Thank you for the review!"
+      loc(Key::ReviewUpdate2, tag, &[])
+   };
+
+   resume(bot, msg, dialogue, *state.prev_state, text).await
+}
+
+fn stars(rating: i32) -> String {
+   "⭐".repeat(rating as usize)
+}
+
+// The notification is not a reason to stop, so errors are only logged
+pub async fn send_to_owners(bot: &Bot, owners: &node::Owners, text: &str) {
+   let owners = [owners.0, owners.1, owners.2];
+   for owner in owners.into_iter().filter(|id| id.0 > node::Owners::VALID_USER_ID) {
+      if let Err(err) = bot.send_message(owner, text).await {
+         env::log(&format!("review::send_to_owners owner={}: {}", owner, err)).await;
+      }
+   }
+}
@@ -0,0 +1,66 @@
+/* ===============================================================================
+Restaurant menu bot.
+Periodic background tasks. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use std::time::Duration;
+use teloxide::prelude::*;
+
+use crate::database as db;
+use crate::environment as env;
+use crate::registration;
+use crate::loc::*;
+
+// Interval between checks
+const PERIOD: Duration = Duration::from_secs(60);
+
+pub async fn run(bot: Bot) {
+   let mut interval = tokio::time::interval(PERIOD);
+   loop {
+      interval.tick().await;
+
+      // Report and continue on error
+      if let Err(e) = check(&bot).await {
+         report(&format!("scheduler::run {}", e)).await;
+      }
+   }
+}
+
+// To the log and the service chat, the check goes on
+async fn report(text: &str) {
+   log::error!("{}", text);
+   env::log(text).await;
+}
+
+async fn check(bot: &Bot) -> Result<(), String> {
+   // Messages in default language
+   let tag = tag(None);
+
+   // Orders for later go to work in advance
+   let time = env::current_date_time() + chrono::Duration::minutes(env::schedule_lead());
+   let tickets = db::tickets_scheduled_due(time).await?;
+   for t in tickets {
+      let id = t.ticket.id;
+      if let Err(e) = registration::start_scheduled(bot, t, tag).await {
+         report(&format!("scheduler::start_scheduled ticket_id={} {}", id, e)).await;
+      }
+   }
+
+   // Remind owners about overdue orders
+   let tickets = db::tickets_eta_expired().await?;
+   for t in tickets {
+      let id = t.ticket.id;
+      if let Err(e) = registration::remind_eta(bot, t, tag).await {
+         report(&format!("scheduler::remind_eta ticket_id={} {}", id, e)).await;
+      }
+   }
+
+   // New day, new stock
+   db::nodes_restock(env::current_date_time().date()).await?;
+
+   Ok(())
+}
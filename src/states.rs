@@ -18,6 +18,9 @@ use crate::database as db;
 use crate::gear::*;
 use crate::cart::*;
 use crate::general::MessageState;
+use crate::chat::ChatState;
+use crate::review::ReviewState;
+use crate::tip::TipState;
 use crate::loc::*;
 
 pub type MyDialogue = Dialogue<State, InMemStorage<State>>;
@@ -30,9 +33,15 @@ pub enum State {
    Command(MainState), // await for select menu item from bottom
    Gear(GearState), // in settings menu
    GearSubmode(GearStateEditing), // in settings menu edit field
+   GearPromo(GearStatePromo), // in settings menu edit promo codes of the node
+   GearPrices(GearStatePrices), // in settings menu edit price rules of the node
+   GearBundle(GearStateBundle), // in settings menu edit components of the bundle
    Cart(CartState), // in cart menu
    CartSubmode(CartStateEditing),
    GeneralMessage(MessageState), // general commands, enter text of message to send
+   TicketChat(ChatState), // messages to the other side of the ticket
+   Review(ReviewState), // customer writes a review of the ticket
+   Tip(TipState), // customer enters the tip amount
 }
 
 impl Default for State {
@@ -51,6 +60,7 @@ pub struct MainState {
    pub prev_state: StartState,
    pub user_id: UserId,
    pub is_admin: bool,
+   pub is_staff: bool, // owner or courier of some place
    pub tag: LocaleTag,
 }
 
@@ -61,6 +71,7 @@ enum MainMenu {
    Gear,  // settings menu
    Cart,  // cart menu
    Open,  // show opened items
+   Orders,  // queue of active orders for owners
    Unknown,
 }
 
@@ -70,6 +81,7 @@ impl MainMenu {
       else if s == loc(Key::StatesMainMenuGear, tag, &[]) { Self::Gear }
       else if s == loc(Key::StatesMainMenuCart, tag, &[]) { Self::Cart }
       else if s == loc(Key::StatesMainMenuOpen, tag, &[]) { Self::Open }
+      else if s == loc(Key::StatesMainMenuOrders, tag, &[]) { Self::Orders }
       else { Self::Unknown }
    }
 }
@@ -87,21 +99,43 @@ pub fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'stat
    .branch(
       // Private message handler
       dptree::filter(|msg: Message| { msg.chat.is_private() })
+      .branch(Message::filter_successful_payment().endpoint(crate::payment::successful))
+      .branch(dptree::filter_map_async(crate::courier::delivery_of).endpoint(crate::courier::relay_location))
       .branch(dptree::case![State::Start(state)].endpoint(start))
       .branch(dptree::case![State::Command(state)].endpoint(command))
       .branch(dptree::case![State::Cart(state)].endpoint(crate::cart::update))
       .branch(dptree::case![State::CartSubmode(state)].endpoint(crate::cart::update_edit))
       .branch(dptree::case![State::Gear(state)].endpoint(crate::gear::update))
       .branch(dptree::case![State::GearSubmode(state)].endpoint(crate::gear::update_edit))
+      .branch(dptree::case![State::GearPromo(state)].endpoint(crate::gear::update_promo))
+      .branch(dptree::case![State::GearPrices(state)].endpoint(crate::gear::update_prices))
+      .branch(dptree::case![State::GearBundle(state)].endpoint(crate::gear::update_bundle))
       .branch(dptree::case![State::GeneralMessage(state)].endpoint(crate::general::update_input))
+      .branch(dptree::case![State::TicketChat(state)].endpoint(crate::chat::update))
+      .branch(dptree::case![State::Review(state)].endpoint(crate::review::update))
+      .branch(dptree::case![State::Tip(state)].endpoint(crate::tip::update))
    )
    .branch(dptree::entry().endpoint(chat_message_handler));
 
+   // Updates of the live location come as edits
+   let edited_message_handler = Update::filter_edited_message()
+   .filter(|msg: Message| { msg.chat.is_private() })
+   .branch(dptree::filter_map_async(crate::courier::delivery_of).endpoint(crate::courier::relay_location))
+   .branch(dptree::endpoint(crate::cart::update_live_location));
+
    let callback_query_handler = Update::filter_callback_query().endpoint(callback);
 
-   dialogue::enter::<Update, InMemStorage<State>, State, _>()
-   .branch(message_handler)
-   .branch(callback_query_handler)
+   // Pre-checkout has no chat, so it goes without dialogue
+   let pre_checkout_query_handler = Update::filter_pre_checkout_query().endpoint(crate::payment::pre_checkout);
+
+   dptree::entry()
+   .branch(pre_checkout_query_handler)
+   .branch(
+      dialogue::enter::<Update, InMemStorage<State>, State, _>()
+      .branch(message_handler)
+      .branch(edited_message_handler)
+      .branch(callback_query_handler)
+   )
 }
 
 
@@ -123,10 +157,11 @@ async fn start(bot: Bot, msg: Message, dialogue: MyDialogue, state: StartState)
 
    let user = user.unwrap();
    let user_id = user.id;
-   let new_state = MainState { prev_state: state, user_id, is_admin: false, tag: locale };
+   let new_state = MainState { prev_state: state, user_id, is_admin: false, is_staff: false, tag: locale };
 
    // Insert or update info about user
-   update_last_seen_full(user).await?;
+   let referrer = crate::general::referrer(msg.text().unwrap_or_default());
+   update_last_seen_full(user, referrer).await?;
 
    command(bot, msg, dialogue, new_state)
    .await
@@ -134,18 +169,79 @@ async fn start(bot: Bot, msg: Message, dialogue: MyDialogue, state: StartState)
 
 pub async fn reload(bot: Bot, msg: Message, dialogue: MyDialogue, state: MainState) -> HandlerResult {
    let tag = state.tag;
+   let is_staff = state.is_staff;
 
    dialogue.update(state).await?;
 
    let text =  loc(Key::StatesMainMenu, tag, &[]); // You are in the main menu
    let chat_id = msg.chat.id;
    bot.send_message(chat_id, text)
-   .reply_markup(main_menu_markup(tag))
+   .reply_markup(main_menu_markup(is_staff, tag))
    .await?;
    
    Ok(())
 }
 
+// Where to return after the dialog started with an inline button, the input modes go back to their menu
+pub async fn resume_state(dialogue: &MyDialogue, user_id: UserId, tag: LocaleTag) -> Result<Box<State>, String> {
+   let main = MainState {
+      prev_state: StartState { restarted: false },
+      user_id,
+      is_admin: env::is_admin_id(user_id),
+      is_staff: db::is_staff(user_id).await?,
+      tag,
+   };
+
+   // The dialogs do not nest, the new one returns where the previous would
+   let state = match dialogue.get().await {
+      Ok(Some(State::TicketChat(state))) => *state.prev_state,
+      Ok(Some(State::Review(state))) => *state.prev_state,
+      Ok(Some(State::Tip(state))) => *state.prev_state,
+      Ok(Some(state)) => state,
+      _ => State::Command(main),
+   };
+
+   let state = match state {
+      State::Command(_) | State::Cart(_) | State::Gear(_) => state,
+      State::GearSubmode(state) => State::Gear(state.prev_state),
+      State::GearPromo(state) => State::Gear(state.prev_state),
+      State::GearPrices(state) => State::Gear(state.prev_state),
+      State::GearBundle(state) => State::Gear(state.prev_state),
+      State::CartSubmode(state) => State::Cart(state.prev_state),
+      State::GeneralMessage(state) => State::Command(state.prev_state),
+      _ => State::Command(main),
+   };
+   Ok(Box::new(state))
+}
+
+// Back from the dialog to the state from resume_state() with its menu
+pub async fn resume(bot: Bot, msg: Message, dialogue: MyDialogue, state: State, text: String) -> HandlerResult {
+   match state {
+      State::Cart(state) => {
+         bot.send_message(msg.chat.id, text).await?;
+         dialogue.update(state.to_owned()).await?;
+         crate::cart::view(bot, msg, state).await
+      }
+      State::Gear(state) => {
+         bot.send_message(msg.chat.id, text).await?;
+         dialogue.update(state.to_owned()).await?;
+         crate::gear::view(bot, msg, &state).await
+      }
+      State::Command(state) => {
+         bot.send_message(msg.chat.id, text)
+         .reply_markup(main_menu_markup(state.is_staff, state.tag))
+         .await?;
+         dialogue.update(state).await?;
+         Ok(())
+      }
+      state => {
+         bot.send_message(msg.chat.id, text).await?;
+         dialogue.update(state).await?;
+         Ok(())
+      }
+   }
+}
+
 // #[async_recursion]
 pub async fn command(bot: Bot, msg: Message, dialogue: MyDialogue, state: MainState) -> HandlerResult {
    // Determine the language of the user
@@ -160,6 +256,7 @@ pub async fn command(bot: Bot, msg: Message, dialogue: MyDialogue, state: MainSt
       prev_state: StartState { restarted: false },
       user_id,
       is_admin: env::is_admin_id(user_id), // reload permissions every time
+      is_staff: db::is_staff(user_id).await?,
       tag,
    };
 
@@ -176,6 +273,7 @@ pub async fn command(bot: Bot, msg: Message, dialogue: MyDialogue, state: MainSt
       MainMenu::All => crate::navigation::enter(bot, msg, new_state, WorkTime::All).await?,
       MainMenu::Open => crate::navigation::enter(bot, msg, new_state, WorkTime::Now).await?,
       MainMenu::Gear => crate::gear::enter(bot, msg, dialogue, new_state).await?,
+      MainMenu::Orders => crate::queue::enter(bot, msg, new_state).await?,
 
       MainMenu::Unknown => {
 
@@ -183,7 +281,7 @@ pub async fn command(bot: Bot, msg: Message, dialogue: MyDialogue, state: MainSt
          if state.prev_state.restarted {
             let text =  loc(Key::StatesBotRestarted, tag, &[]); // Sorry, the bot has been restarted
             bot.send_message(chat_id, text)
-            .reply_markup(main_menu_markup(tag))
+            .reply_markup(main_menu_markup(new_state.is_staff, tag))
             .await?;
          } else {
 
@@ -216,14 +314,14 @@ pub async fn chat_message_handler(bot: Bot, msg: Message) -> HandlerResult {
    Ok(())
 }
 
-pub async fn callback(bot: Bot, q: CallbackQuery) -> HandlerResult {
+pub async fn callback(bot: Bot, q: CallbackQuery, dialogue: MyDialogue) -> HandlerResult {
    let user_id = q.from.id;
 
    // Determine the language of the user
    let locale = q.from.language_code.as_deref();
    let tag = tag(locale);
 
-   let res = crate::callback::update(bot.to_owned(), q.to_owned(), tag).await;
+   let res = crate::callback::update(bot.to_owned(), q.to_owned(), dialogue, tag).await;
 
    // Notify user about possible error
    if let Err(e) = res {
@@ -248,18 +346,22 @@ pub async fn callback(bot: Bot, q: CallbackQuery) -> HandlerResult {
 }
 
 
-pub fn main_menu_markup(tag: LocaleTag) -> ReplyMarkup {
-   let commands = vec![
+// The queue of orders only for those who work at a place
+pub fn main_menu_markup(is_staff: bool, tag: LocaleTag) -> ReplyMarkup {
+   let mut commands = vec![
       loc(Key::StatesMainMenuCart, tag, &[]),
       loc(Key::StatesMainMenuAll, tag, &[]),
       loc(Key::StatesMainMenuOpen, tag, &[]),
       loc(Key::StatesMainMenuGear, tag, &[]),
    ];
+   if is_staff {
+      commands.push(loc(Key::StatesMainMenuOrders, tag, &[]));
+   }
    kb_markup(vec![commands])
 }
 
 
-async fn update_last_seen_full(user: &User) -> Result<(), String> {
+async fn update_last_seen_full(user: &User, referrer: Option<u64>) -> Result<(), String> {
    let user_id = user.id.0;
 
    // Collect info about the new user and store in database
@@ -271,7 +373,7 @@ async fn update_last_seen_full(user: &User) -> Result<(), String> {
       format!(" @{}", username)
    } else {String::from("-")};
 
-   db::user_insert(user_id, name, contact).await?;
+   db::user_insert(user_id, name, contact, referrer).await?;
    Ok(())
 }
 
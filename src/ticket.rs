@@ -8,13 +8,16 @@ Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
 =============================================================================== */
 
 use strum::{AsRefStr, EnumString};
-use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, ChatId, MessageId,
+use chrono::{Duration, NaiveDateTime, NaiveTime, Timelike};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, MessageId,
    UserId,
 };
 
 use crate::callback;
 use crate::node;
-use crate::general as gen;
+use crate::geo::Geo;
+use crate::promo::Promo;
+use crate::environment as env;
 use crate::loc::*;
 
 pub type ThreeMsgId = (Option<MessageId>, Option<MessageId>, Option<MessageId>);
@@ -35,13 +38,19 @@ pub fn three_option_to_msg_id(opt1: Option<i32>, opt2: Option<i32>, opt3: Option
    (option_to_msg_id(opt1), option_to_msg_id(opt2), option_to_msg_id(opt3))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 #[derive(AsRefStr, EnumString)]
 pub enum Stage {
    // DB value, info for customer, info for owner
    #[strum(to_string = "A")]
    OwnersConfirmation,
 
+   #[strum(to_string = "E")]
+   CustomerApproval,
+
+   #[strum(to_string = "S")]
+   Scheduled,
+
    #[strum(to_string = "B")]
    Cooking,
 
@@ -62,10 +71,19 @@ pub enum Stage {
 }
 
 impl Stage {
+   // Not yet finished or canceled
+   pub fn is_active(&self) -> bool {
+      !matches!(self, Stage::Finished | Stage::CanceledByCustomer | Stage::CanceledByOwner)
+   }
+
    pub fn message_for_owner(&self, tag: LocaleTag) -> String {
       match self {
          // Waiting for confirmation of acceptance of the order for work
          Stage::OwnersConfirmation => loc(Key::TicketOwner1, tag, &[]),
+         // Proposed changes sent, waiting for the customer's decision
+         Stage::CustomerApproval => loc(Key::TicketOwner8, tag, &[]),
+         // Accepted, waiting for the time chosen by the customer
+         Stage::Scheduled => loc(Key::TicketOwner9, tag, &[]),
          // In progress
          Stage::Cooking => loc(Key::TicketOwner2, tag, &[]),
          // Done, delivery in progress
@@ -85,6 +103,10 @@ impl Stage {
       match self {
          // Confirm the start of order processing
          Stage::OwnersConfirmation => loc(Key::TicketCustomer1, tag, &[]),
+         // The place suggests changing the order
+         Stage::CustomerApproval => loc(Key::TicketCustomer8, tag, &[]),
+         // Accepted, the order will be ready by the chosen time
+         Stage::Scheduled => loc(Key::TicketCustomer9, tag, &[]),
          // In progress. Confirm that the order is ready for pickup
          Stage::Cooking => loc(Key::TicketCustomer2, tag, &[]),
          // In the process of delivery. Confirm delivery of the order to the customer
@@ -101,6 +123,39 @@ impl Stage {
    }
 }
 
+// Online payment of the ticket
+#[derive(Copy, Clone, PartialEq)]
+#[derive(AsRefStr, EnumString)]
+pub enum PaymentState {
+   #[strum(to_string = "U")]
+   Unpaid,
+
+   #[strum(to_string = "P")]
+   Paid,
+
+   // Canceled after the payment, the money must be returned
+   #[strum(to_string = "R")]
+   RefundPending,
+
+   #[strum(to_string = "F")]
+   Refunded,
+}
+
+// Place and number from text like "#12-017" or "#17"
+pub fn parse_code(s: &str) -> Option<(Option<i32>, i32)> {
+   let s = s.trim().trim_start_matches('#');
+   match s.split_once('-') {
+      Some((node_id, number)) => Some((Some(node_id.parse().ok()?), number.parse().ok()?)),
+      None => Some((None, s.parse().ok()?)),
+   }
+}
+
+// Whole minutes until the estimated time, the started minute counts
+pub fn minutes_left(eta: NaiveDateTime, now: NaiveDateTime) -> i64 {
+   let secs = (eta - now).num_seconds().max(0);
+   (secs + 59) / 60
+}
+
 // The message to the customer and owner is different in markup
 #[derive(Copy, Clone)]
 pub enum InfoFor {
@@ -108,6 +163,7 @@ pub enum InfoFor {
    Owner,
 }
 
+#[derive(Clone)]
 pub struct Ticket {
    pub id: i32, // DB primary key
    pub node_id: i32, // Id of node with owners
@@ -117,36 +173,142 @@ pub struct Ticket {
    pub stage: Stage, // execution stage
    pub cust_status_msg_id: Option<MessageId>, // Id of message with execution status at customer side
    pub owners_status_msg_id: ThreeMsgId, // The same for owners but any two can be None
-   pub service_msg_id: Option<MessageId,> // Id of message in service chat
+   pub service_msg_id: Option<MessageId,>, // Id of message in service chat
+   pub eta: Option<NaiveDateTime>, // estimated time of the current stage set by owner
+   pub pickup: bool, // customer will pick up the order himself
+   pub courier_id: Option<UserId>, // who took the delivery
+   pub courier_location_id: Option<MessageId>, // courier's live location relayed to the customer
+   pub created: Option<NaiveDateTime>, // unknown for tickets of the previous versions
+   pub number: Option<i32>, // daily number within the place
+   pub scheduled: Option<NaiveDateTime>, // the time chosen by customer instead of as soon as possible
+   pub payment: PaymentState,
+   pub payment_id: Option<String>, // charge id at the provider side
+   pub paid: usize, // amount of the payment
+   pub discount: usize, // by the promo code
+   pub promo: Option<String>, // code entered by the customer
+   pub points: usize, // loyalty points spent on the order
+   pub tip: usize, // from the customer to the staff after the receipt
+   pub delivery_fee: usize, // as the customer saw it at the checkout
+}
+
+// Terms of the new ticket as the customer saw them in the cart
+#[derive(Clone, Copy)]
+pub struct Checkout {
+   pub pickup: bool,
+   pub scheduled: Option<NaiveDateTime>,
+   pub delivery_fee: usize,
+   pub discount: usize, // by the promo code if it is still with the order
+   pub points: usize, // to spend if there is enough on the balance
 }
 
+// Amounts of the ticket that follow the cost of the items
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Charges {
+   pub discount: usize,
+   pub points: usize,
+   pub delivery_fee: usize,
+}
+
+#[derive(Clone)]
 pub struct TicketWithOwners {
    pub ticket: Ticket,
    pub owners: node::Owners,
+   pub couriers: node::Owners,
+   pub items: Vec<TicketItem>,
+}
+
+// Ordered item with the price at the time of ordering
+#[derive(Clone)]
+pub struct TicketItem {
+   pub id: i32, // line of the ticket, the same item may be in several lines with different components
+   pub node_id: i32,
+   pub title: String,
+   pub price: usize,
+   pub amount: usize,
+   pub proposed: Option<usize>, // the amount suggested by the owner, awaiting customer approval
+   pub components: Option<String>, // chosen items of the bundle
+}
+
+impl TicketItem {
+   pub fn cost(&self) -> usize {
+      self.amount * self.price
+   }
+
+   // Amount taking into account the owner's suggestion
+   pub fn proposed_amount(&self) -> usize {
+      self.proposed.unwrap_or(self.amount)
+   }
+
+   pub fn proposed_cost(&self) -> usize {
+      self.proposed_amount() * self.price
+   }
 }
 
 impl Ticket {
-   pub fn make_markup(&self, info_for: InfoFor, tag: LocaleTag) -> Option<InlineKeyboardMarkup> {
-      match info_for {
+   // Short number to say aloud like "#12-017" with the place to be unique, old tickets have only global id
+   pub fn code(&self) -> String {
+      match self.number {
+         Some(number) => format!("#{}-{:03}", self.node_id, number),
+         None => format!("#{}", self.id),
+      }
+   }
+
+   // Time of cooking for the availability of the items
+   pub fn work_time(&self) -> NaiveDateTime {
+      self.scheduled.unwrap_or_else(env::current_date_time)
+   }
+
+   // Check the place from parse_code(), without it any place fits
+   pub fn matches_node(&self, node_id: Option<i32>) -> bool {
+      node_id.is_none_or(|id| id == self.node_id)
+   }
+
+   // The items can be edited only if they are kept with the ticket, older tickets have none
+   pub fn make_markup(&self, info_for: InfoFor, editable: bool, tag: LocaleTag) -> Option<InlineKeyboardMarkup> {
+      let markup = match info_for {
          InfoFor::Customer => {
             match self.stage {
                Stage::OwnersConfirmation
+               | Stage::Scheduled
                | Stage::Cooking
                | Stage::Delivery => Some(self.markup_cancel(tag)),
+               Stage::CustomerApproval => Some(self.markup_accept(tag)),
                Stage::CustomerConfirmation => Some(self.markup_confirm(tag)),
                _ => None,
             }
          }
          InfoFor::Owner => {
             match self.stage {
-               Stage::OwnersConfirmation
-               | Stage::Cooking
-               | Stage::Delivery => Some(self.markup_next(tag)),
-               Stage::CustomerConfirmation => Some(self.markup_cancel(tag)),
+               Stage::OwnersConfirmation if editable => Some(self.markup_next(tag)
+                  .append_row(vec![self.button(callback::Command::TicketEdit(0), tag)])),
+               Stage::OwnersConfirmation => Some(self.markup_next(tag)),
+               Stage::Scheduled => Some(self.markup_next(tag)),
+               Stage::Cooking
+               | Stage::Delivery => Some(self.markup_eta(self.markup_next(tag), tag)),
+               Stage::CustomerApproval
+               | Stage::CustomerConfirmation => Some(self.markup_cancel(tag)),
                _ => None,
             }
          }
-      }
+      };
+
+      // Chat with the other side while the ticket is in progress
+      markup.map(|m| m.append_row(vec![self.button_chat(info_for, tag)]))
+   }
+
+   fn button_chat(&self, info_for: InfoFor, tag: LocaleTag) -> InlineKeyboardButton {
+      let title = match info_for {
+         InfoFor::Customer => loc(Key::CallbackChat1, tag, &[]), // "✉️ Write to the place"
+         InfoFor::Owner => loc(Key::CallbackChat2, tag, &[]), // "✉️ Write to the customer"
+      };
+      let args = format!("{}{}", callback::Command::TicketChat(0).as_ref(), self.id);
+      InlineKeyboardButton::callback(title, args)
+   }
+
+   // Button for the recipient to answer in the chat
+   pub fn markup_chat_reply(&self, tag: LocaleTag) -> InlineKeyboardMarkup {
+      InlineKeyboardMarkup::default()
+      .append_row(vec![self.button(callback::Command::TicketChat(0), tag)])
    }
 
    fn button(&self, cmd: callback::Command, tag: LocaleTag) -> InlineKeyboardButton {
@@ -155,6 +317,12 @@ impl Ticket {
       InlineKeyboardButton::callback(title, args)
    }
 
+   // Button for command with ticket and item id
+   fn item_button(&self, caption: String, cmd: callback::Command, item_id: i32) -> InlineKeyboardButton {
+      let args = format!("{}{}_{}", cmd.as_ref(), self.id, item_id);
+      InlineKeyboardButton::callback(caption, args)
+   }
+
    // Menu to cancel ticket at middle
    fn markup_cancel(&self, tag: LocaleTag) -> InlineKeyboardMarkup {
       let cmd = callback::Command::TicketCancel(0);
@@ -172,6 +340,15 @@ impl Ticket {
       .append_row(vec![self.button(cancel, tag), self.button(confirm, tag)])
    }
 
+   // Menu for customer to agree with the changes
+   fn markup_accept(&self, tag: LocaleTag) -> InlineKeyboardMarkup {
+      let cancel = callback::Command::TicketCancel(0);
+      let accept = callback::Command::TicketAccept(0);
+
+      InlineKeyboardMarkup::default()
+      .append_row(vec![self.button(cancel, tag), self.button(accept, tag)])
+   }
+
    // Menu for owner to process ticket
    pub fn markup_next(&self, tag: LocaleTag) -> InlineKeyboardMarkup {
       let cancel = callback::Command::TicketCancel(0);
@@ -181,29 +358,348 @@ impl Ticket {
       .append_row(vec![self.button(cancel, tag), self.button(next, tag)])
    }
 
+   // Menu for courier to report the delivery
+   pub fn markup_delivered(&self, tag: LocaleTag) -> InlineKeyboardMarkup {
+      let next = callback::Command::TicketNext(0);
+
+      InlineKeyboardMarkup::default()
+      .append_row(vec![self.button(next, tag)])
+   }
+
+   // Offer for couriers to take the delivery
+   pub fn markup_take(&self, tag: LocaleTag) -> InlineKeyboardMarkup {
+      let take = callback::Command::TicketTake(0);
+
+      InlineKeyboardMarkup::default()
+      .append_row(vec![self.button(take, tag)])
+   }
+
+   // Add to menu buttons for owner to select estimated time in minutes and by clock
+   fn markup_eta(&self, markup: InlineKeyboardMarkup, tag: LocaleTag) -> InlineKeyboardMarkup {
+      let button = |caption: String, minutes: i64| {
+         let args = format!("{}{}_{}", callback::Command::TicketEta(0, 0).as_ref(), self.id, minutes);
+         InlineKeyboardButton::callback(caption, args)
+      };
+
+      let minutes: Vec<_> = [15, 30, 45, 60].iter()
+      .map(|m| button(format!("⏱{}'", m), *m))
+      .collect();
+
+      // The nearest half hours, but not earlier than in 10 minutes
+      let fmt = loc(Key::CommonTimeFormat, tag, &[]);
+      let now = env::current_date_time().time();
+      let now = (now.num_seconds_from_midnight() / 60) as i64;
+      let first = ((now + 10) / 30 + 1) * 30;
+      let clock: Vec<_> = (0..4)
+      .map(|i| {
+         let slot = first + i * 30;
+         let time = NaiveTime::from_num_seconds_from_midnight_opt((slot % 1440) as u32 * 60, 0).unwrap_or_default();
+         button(format!("⏱{}", time.format(&fmt)), slot - now)
+      })
+      .collect();
+
+      markup.append_row(minutes)
+      .append_row(clock)
+   }
+
    // Go to the next stage if it possible
    pub fn next_stage(&mut self) -> bool {
       self.stage = match self.stage {
-         Stage::OwnersConfirmation => Stage::Cooking,
+         Stage::OwnersConfirmation => self.stage_after_confirmation(),
+         Stage::Scheduled => Stage::Cooking,
          Stage::Cooking => Stage::Delivery,
          Stage::Delivery => Stage::CustomerConfirmation,
          _ => return false,
       };
+
+      // The estimated time relates to the previous stage
+      self.eta = None;
       true
    }
+
+   // The order for later waits until the lead time before it
+   pub fn stage_after_confirmation(&self) -> Stage {
+      match self.scheduled {
+         Some(time) if time - Duration::minutes(env::schedule_lead()) > env::current_date_time() => Stage::Scheduled,
+         _ => Stage::Cooking,
+      }
+   }
 }
 
 impl TicketWithOwners {
    pub fn stage_message(&self, info_for: InfoFor, tag: LocaleTag) -> String {
-      let (s, id) = match info_for {
-         InfoFor::Customer => (self.ticket.stage.message_for_customer(tag), self.owners.0),
-         InfoFor::Owner => (self.ticket.stage.message_for_owner(tag), self.ticket.customer_id),
+      let s = match info_for {
+         InfoFor::Customer => self.ticket.stage.message_for_customer(tag),
+         InfoFor::Owner => self.ticket.stage.message_for_owner(tag),
+      };
+
+      // The customer needs to see the proposed changes and both the estimated time
+      let s = match (info_for, self.ticket.stage) {
+         (InfoFor::Customer, Stage::CustomerApproval) => format!("{}{}", s, self.items_text(tag)),
+         (_, Stage::Cooking)
+         | (_, Stage::Delivery) => format!("{}{}", s, self.eta_text(info_for, tag)),
+         _ => s,
+      };
+
+      // The order for later is always visible
+      let s = match self.ticket.scheduled {
+         Some(time) if self.ticket.stage.is_active() => {
+            // "🕒 For {}\n{}"
+            let fmt = loc(Key::CommonDateTimeFormat, tag, &[]);
+            loc(Key::TicketScheduled, tag, &[&time.format(&fmt), &s])
+         }
+         _ => s,
       };
-      let cmd = gen::Command::Message(ChatId(0)).as_ref();
-      loc(Key::TicketMessage, tag, &[
-         &s,
-         &cmd,
-         &id
+
+      match self.ticket.payment {
+         PaymentState::Unpaid => s,
+         PaymentState::Paid => loc(Key::TicketPaid, tag, &[&s]), // "{}\n💳 Paid"
+         PaymentState::RefundPending => loc(Key::TicketRefundPending, tag, &[&s]), // "{}\n💸 Refund pending"
+         PaymentState::Refunded => loc(Key::TicketRefunded, tag, &[&s]), // "{}\n↩ Refunded"
+      }
+   }
+
+   // Cost of the items at the moment
+   pub fn items_cost(&self) -> usize {
+      self.items.iter().map(|item| item.cost()).sum()
+   }
+
+   // The same with the discount by the promo code and the spent loyalty points
+   pub fn cost(&self) -> usize {
+      self.items_cost().saturating_sub(self.ticket.discount + self.ticket.points)
+   }
+
+   // Everything the customer pays for, with the delivery
+   pub fn total(&self) -> usize {
+      self.cost() + self.ticket.delivery_fee
+   }
+
+   // The charges for the proposed items like at the checkout, the spent points can only decrease,
+   // without the promo code (deleted since) its discount is kept within the cost
+   pub fn proposed_charges(&self, promo: Option<&Promo>, terms: Option<&node::DeliveryTerms>, geo: Option<&Geo>) -> Charges {
+      let cost: usize = self.items.iter().map(|item| item.proposed_cost()).sum();
+      let discount = match promo {
+         Some(promo) => promo.discount(cost),
+         None => self.ticket.discount.min(cost),
+      };
+      let points = self.ticket.points.min(cost - discount);
+      let delivery_fee = terms
+      .map(|terms| terms.fee_for(cost - discount - points, geo))
+      .unwrap_or_default();
+      Charges { discount, points, delivery_fee }
+   }
+
+   // Estimated time by the clock, the status message is not redrawn so a countdown would freeze
+   fn eta_text(&self, info_for: InfoFor, tag: LocaleTag) -> String {
+      match self.ticket.eta {
+         Some(eta) => {
+            let fmt = loc(Key::CommonTimeFormat, tag, &[]);
+            let time = eta.format(&fmt);
+            let now = env::current_date_time();
+            if eta > now {
+               // "\nEstimated time: {} (in ~{} min)"
+               loc(Key::TicketEta1, tag, &[&time, &minutes_left(eta, now)])
+            } else {
+               // "\nEstimated time: {}, slightly delayed"
+               loc(Key::TicketEta2, tag, &[&time])
+            }
+         }
+         None => match info_for {
+            // "\nPlease specify the estimated time with the buttons below"
+            InfoFor::Owner => loc(Key::TicketEta3, tag, &[]),
+            InfoFor::Customer => String::default(),
+         }
+      }
+   }
+
+   // List of items with changes and total
+   pub fn items_text(&self, tag: LocaleTag) -> String {
+      let text = self.items.iter()
+      .enumerate()
+      .fold(String::default(), |acc, (i, item)| {
+         let price = env::price_with_unit(item.price);
+         let text = if item.proposed_amount() == item.amount {
+            // "{}\n{}. {}: {} x {} pcs."
+            loc(Key::TicketItemsText1, tag, &[&acc, &(i + 1), &item.title, &price, &item.amount])
+         } else {
+            // "{}\n{}. {}: {} x {} → {} pcs."
+            loc(Key::TicketItemsText2, tag, &[&acc, &(i + 1), &item.title, &price, &item.amount, &item.proposed_amount()])
+         };
+         match &item.components {
+            Some(components) => format!("{}\n({})", text, components),
+            None => text,
+         }
+      });
+
+      let (total, proposed) = self.items.iter()
+      .fold((0, 0), |acc, item| (acc.0 + item.cost(), acc.1 + item.proposed_cost()));
+
+      // "{}\nTotal: {} → {}"
+      loc(Key::TicketItemsText3, tag, &[&text, &env::price_with_unit(total), &env::price_with_unit(proposed)])
+   }
+
+   // Menu for owner to change the items
+   pub fn markup_edit(&self, tag: LocaleTag) -> InlineKeyboardMarkup {
+      let t = &self.ticket;
+
+      let markup = self.items.iter()
+      .enumerate()
+      .filter(|(_, item)| item.proposed_amount() > 0)
+      .fold(InlineKeyboardMarkup::default(), |acc, (i, item)| {
+         let i = i + 1;
+         acc.append_row(vec![
+            t.item_button(format!("➖{}", i), callback::Command::TicketItemDec(0, 0), item.id),
+            t.item_button(format!("➕{}", i), callback::Command::TicketItemInc(0, 0), item.id),
+            t.item_button(format!("✖{}", i), callback::Command::TicketItemRemove(0, 0), item.id),
+            t.item_button(format!("🔄{}", i), callback::Command::TicketItemSubstitute(0, 0), item.id),
+         ])
+      });
+
+      markup.append_row(vec![
+         t.button(callback::Command::TicketDiscard(0), tag),
+         t.button(callback::Command::TicketPropose(0), tag),
       ])
    }
+
+   // Menu for owner to select a replacement for the item
+   pub fn markup_substitute(&self, item_id: i32, candidates: &[TicketItem], tag: LocaleTag) -> InlineKeyboardMarkup {
+      let t = &self.ticket;
+
+      let markup = candidates.iter()
+      .fold(InlineKeyboardMarkup::default(), |acc, item| {
+         let caption = format!("{} {}", item.title, env::price_with_unit(item.price));
+         let args = format!("{}{}_{}_{}", callback::Command::TicketSubstituteWith(0, 0, 0).as_ref(), t.id, item_id, item.node_id);
+         acc.append_row(vec![InlineKeyboardButton::callback(caption, args)])
+      });
+
+      // "⏪Back"
+      markup.append_row(vec![InlineKeyboardButton::callback(
+         loc(Key::NavigationMarkup4, tag, &[]),
+         format!("{}{}", callback::Command::TicketEdit(0).as_ref(), t.id)
+      )])
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::promo::Discount;
+
+   fn ticket(id: i32, node_id: i32, number: Option<i32>) -> Ticket {
+      Ticket {
+         id, node_id,
+         customer_id: UserId(1),
+         cust_msg_id: MessageId(1),
+         owners_msg_id: (None, None, None),
+         stage: Stage::OwnersConfirmation,
+         cust_status_msg_id: None,
+         owners_status_msg_id: (None, None, None),
+         service_msg_id: None,
+         eta: None,
+         pickup: false,
+         courier_id: None,
+         courier_location_id: None,
+         created: None,
+         number,
+         scheduled: None,
+         payment: PaymentState::Unpaid,
+         payment_id: None,
+         paid: 0,
+         discount: 0,
+         promo: None,
+         points: 0,
+         tip: 0,
+         delivery_fee: 0,
+      }
+   }
+
+   // Two lines for 100 each, the owner proposes to leave one piece of the first only
+   fn edited(discount: usize, points: usize) -> TicketWithOwners {
+      let item = |id, amount, proposed| TicketItem {
+         id, node_id: id, title: String::default(), price: 100, amount, proposed, components: None,
+      };
+      let mut ticket = ticket(100, 12, Some(17));
+      ticket.discount = discount;
+      ticket.points = points;
+      let owners = node::Owners(UserId(0), UserId(0), UserId(0));
+      TicketWithOwners { ticket, owners: owners.clone(), couriers: owners, items: vec![item(1, 2, Some(1)), item(2, 1, Some(0))] }
+   }
+
+   fn promo(discount: Discount) -> Promo {
+      Promo { id: 1, node_id: 12, code: String::from("A"), discount, valid_from: None, valid_to: None, per_user: 0, total: 0 }
+   }
+
+   #[test]
+   fn proposed_charges_follow_the_cost() {
+      let t = edited(30, 0);
+      let charges = t.proposed_charges(Some(&promo(Discount::Percent(10))), None, None);
+      assert_eq!(charges, Charges { discount: 10, points: 0, delivery_fee: 0 });
+      let charges = t.proposed_charges(Some(&promo(Discount::Fixed(150))), None, None);
+      assert_eq!(charges.discount, 100);
+   }
+
+   #[test]
+   fn proposed_charges_keep_within_the_cost() {
+      let t = edited(30, 200);
+      assert_eq!(t.proposed_charges(None, None, None), Charges { discount: 30, points: 70, delivery_fee: 0 });
+      let t = edited(150, 0);
+      assert_eq!(t.proposed_charges(None, None, None).discount, 100);
+   }
+
+   #[test]
+   fn proposed_charges_recount_the_fee() {
+      let t = edited(0, 0);
+      let terms = node::DeliveryTerms { fee: 20, free_from: 200, ..Default::default() };
+      assert_eq!(t.proposed_charges(None, Some(&terms), None).delivery_fee, 20);
+   }
+
+   #[test]
+   fn parse_code_with_place() {
+      assert_eq!(parse_code("#12-017"), Some((Some(12), 17)));
+      assert_eq!(parse_code(" 12-5 "), Some((Some(12), 5)));
+   }
+
+   #[test]
+   fn parse_code_without_place() {
+      assert_eq!(parse_code("#17"), Some((None, 17)));
+      assert_eq!(parse_code("017"), Some((None, 17)));
+   }
+
+   #[test]
+   fn parse_code_invalid() {
+      assert_eq!(parse_code("#"), None);
+      assert_eq!(parse_code("#A-017"), None);
+      assert_eq!(parse_code("#12-"), None);
+   }
+
+   #[test]
+   fn code_includes_place() {
+      assert_eq!(ticket(100, 12, Some(17)).code(), "#12-017");
+      assert_eq!(ticket(100, 12, None).code(), "#100");
+   }
+
+   #[test]
+   fn code_round_trip() {
+      let t = ticket(100, 12, Some(17));
+      let (node_id, number) = parse_code(&t.code()).unwrap();
+      assert_eq!(number, 17);
+      assert!(t.matches_node(node_id));
+   }
+
+   #[test]
+   fn matches_node_filters_places() {
+      let t = ticket(100, 12, Some(17));
+      assert!(t.matches_node(None));
+      assert!(t.matches_node(Some(12)));
+      assert!(!t.matches_node(Some(38)));
+   }
+
+   #[test]
+   fn minutes_left_rounds_up() {
+      let now = NaiveDateTime::parse_from_str("2026-10-19 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+      assert_eq!(minutes_left(now + Duration::minutes(25), now), 25);
+      assert_eq!(minutes_left(now + Duration::seconds(61), now), 2);
+      assert_eq!(minutes_left(now + Duration::seconds(1), now), 1);
+      assert_eq!(minutes_left(now - Duration::minutes(5), now), 0);
+   }
 }
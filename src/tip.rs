@@ -0,0 +1,220 @@
+/* ===============================================================================
+Restaurant menu bot.
+Customer's tip to the staff of the completed ticket. 19 Oct 2026.
+----------------------------------------------------------------------------
+Licensed under the terms of the GPL version 3.
+http://www.gnu.org/licenses/gpl-3.0.html
+Copyright (c) 2020-2022 by Artem Khomenko _mag12@yahoo.com.
+=============================================================================== */
+
+use teloxide::{prelude::*,
+   types::{CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup, },
+};
+
+use crate::states::*;
+use crate::database as db;
+use crate::environment as env;
+use crate::callback;
+use crate::review;
+use crate::ticket::*;
+use crate::loc::*;
+
+// Suggested tips from the cost of the ticket
+const PERCENTS: [usize; 3] = [5, 10, 15];
+
+// The largest tip as a multiple of the cost, guards against a typo with extra zeros
+const MAX_RATIO: usize = 2;
+
+#[derive(Clone)]
+pub struct TipState {
+   pub prev_state: Box<State>,
+   pub tag: LocaleTag,
+   pub ticket_id: i32,
+}
+
+// Percent and the amount for the buttons, too small are skipped
+fn suggested(cost: usize) -> Vec<(usize, usize)> {
+   PERCENTS.iter()
+   .map(|percent| (*percent, cost * percent / 100))
+   .filter(|(_, amount)| *amount > 0)
+   .collect()
+}
+
+fn max_tip(cost: usize) -> usize {
+   cost.saturating_mul(MAX_RATIO)
+}
+
+// The amount from the button or the input if it is within the limits
+fn check(amount: usize, cost: usize) -> Option<i32> {
+   if amount == 0 || amount > max_tip(cost) {
+      return None;
+   }
+   i32::try_from(amount).ok()
+}
+
+// Offer the customer to thank the staff of the finished ticket
+pub async fn ask(bot: &Bot, t: &Ticket, cost: usize, tag: LocaleTag) -> Result<(), String> {
+   let mut buttons = suggested(cost).into_iter()
+   .map(|(percent, amount)| {
+      let args = format!("{}{}_{}", callback::Command::TicketTip(0, 0).as_ref(), t.id, amount);
+      InlineKeyboardButton::callback(format!("{}% {}", percent, env::price_with_unit(amount)), args)
+   })
+   .collect::<Vec<_>>();
+
+   let args = format!("{}{}", callback::Command::TicketTipEnter(0).as_ref(), t.id);
+   buttons.push(InlineKeyboardButton::callback(loc(Key::CallbackTipOther, tag, &[]), args)); // "✍ Other amount"
+
+   // "Order {}: would you like to leave a tip for the staff?"
+   let text = loc(Key::TipAsk, tag, &[&t.code()]);
+   bot.send_message(t.customer_id, text)
+   .reply_markup(InlineKeyboardMarkup::default().append_row(buttons))
+   .await
+   .map_err(|err| format!("tip::ask customer_id={}: {}", t.customer_id, err))?;
+   Ok(())
+}
+
+pub async fn choose(bot: &Bot, q: CallbackQuery, ticket_id: i32, amount: i32, tag: LocaleTag) -> Result<String, String> {
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if q.from.id != t.ticket.customer_id || t.ticket.stage != Stage::Finished {
+      // "Only the customer can leave a tip"
+      return Ok(loc(Key::TipChoose1, tag, &[]));
+   }
+   if t.ticket.tip > 0 {
+      // "Order {}: the tip has already been left"
+      return Ok(loc(Key::TipChoose2, tag, &[&t.ticket.code()]));
+   }
+
+   // The callback data comes from the customer
+   let amount = usize::try_from(amount).ok().and_then(|amount| check(amount, t.cost()));
+   let text = match amount {
+      Some(amount) => save(bot, &t, amount, tag).await?,
+      // "Unsuccessfully"
+      None => return Ok(loc(Key::RegMakeTicket2, tag, &[])),
+   };
+
+   // Replace the buttons with the thanks
+   if let Some(msg) = &q.message {
+      bot.edit_message_text(q.from.id, msg.id(), &text)
+      .await
+      .map_err(|err| format!("tip::choose user_id={}: {}", q.from.id, err))?;
+   }
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn enter(bot: &Bot, q: CallbackQuery, dialogue: MyDialogue, ticket_id: i32, tag: LocaleTag) -> Result<String, String> {
+   let user_id = q.from.id;
+   let t = db::ticket_with_owners(ticket_id).await?;
+   if user_id != t.ticket.customer_id || t.ticket.stage != Stage::Finished {
+      // "Only the customer can leave a tip"
+      return Ok(loc(Key::TipChoose1, tag, &[]));
+   }
+   if t.ticket.tip > 0 {
+      // "Order {}: the tip has already been left"
+      return Ok(loc(Key::TipChoose2, tag, &[&t.ticket.code()]));
+   }
+
+   // Return to the same place after the tip
+   let prev_state = resume_state(&dialogue, user_id, tag).await?;
+   let new_state = TipState { prev_state, tag, ticket_id };
+   dialogue.update(new_state)
+   .await
+   .map_err(|err| format!("tip::enter dialogue user_id={} {}", user_id, err))?;
+
+   // "Order {}: enter the tip amount or press / to cancel"
+   let text = loc(Key::TipEnter, tag, &[&t.ticket.code()]);
+   bot.send_message(user_id, text)
+   .reply_markup(cancel_markup(tag))
+   .await
+   .map_err(|err| format!("tip::enter user_id={} {}", user_id, err))?;
+
+   // "Successfully"
+   Ok(loc(Key::RegMakeTicket7, tag, &[]))
+}
+
+pub async fn update(bot: Bot, msg: Message, dialogue: MyDialogue, state: TipState) -> HandlerResult {
+   let tag = state.tag;
+   let input = msg.text().unwrap_or_default();
+
+   let text = if input.is_empty() || input == loc(Key::CommonCancel, tag, &[]) {
+      // "Tip cancelled"
+      loc(Key::TipUpdate1, tag, &[])
+   } else {
+      let t = db::ticket_with_owners(state.ticket_id).await?;
+      let cost = t.cost();
+      match input.parse::<usize>() {
+         Ok(amount) if amount > 0 => match check(amount, cost) {
+            Some(amount) => save(&bot, &t, amount, tag).await?,
+            None => {
+               // Stay in the mode for another try
+               // "The tip can be up to {}. Try again or press / to cancel"
+               let text = loc(Key::TipUpdate3, tag, &[&env::price_with_unit(max_tip(cost))]);
+               bot.send_message(msg.chat.id, text).await?;
+               return Ok(());
+            }
+         }
+         _ => {
+            // Stay in the mode for another try
+            // "Expected a positive number, got {}. Try again or press / to cancel"
+            let text = loc(Key::TipUpdate2, tag, &[&input]);
+            bot.send_message(msg.chat.id, text).await?;
+            return Ok(());
+         }
+      }
+   };
+
+   resume(bot, msg, dialogue, *state.prev_state, text).await
+}
+
+// Store the tip and inform the staff, returns the text for the customer
+async fn save(bot: &Bot, t: &TicketWithOwners, amount: i32, tag: LocaleTag) -> Result<String, String> {
+   let code = t.ticket.code();
+   if !db::ticket_set_tip(t.ticket.id, amount).await? {
+      // "Order {}: the tip has already been left"
+      return Ok(loc(Key::TipChoose2, tag, &[&code]));
+   }
+
+   let amount = env::price_with_unit(amount as usize);
+
+   // "Order {}: the customer left a tip {}"
+   let text = loc(Key::TipChoose4, tag, &[&code, &amount]);
+   review::send_to_owners(bot, &t.owners, &text).await;
+   if let Some(courier) = t.ticket.courier_id {
+      if let Err(err) = bot.send_message(courier, &text).await {
+         env::log(&format!("tip::save courier={}: {}", courier, err)).await;
+      }
+   }
+
+   // "Order {}: thank you for the tip {}!"
+   Ok(loc(Key::TipChoose3, tag, &[&code, &amount]))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn suggested_percents() {
+      assert_eq!(suggested(200_000), vec![(5, 10_000), (10, 20_000), (15, 30_000)]);
+   }
+
+   #[test]
+   fn suggested_skips_zero() {
+      assert_eq!(suggested(10), vec![(10, 1), (15, 1)]);
+      assert!(suggested(0).is_empty());
+   }
+
+   #[test]
+   fn check_limits() {
+      assert_eq!(check(50_000, 100_000), Some(50_000));
+      assert_eq!(check(200_000, 100_000), Some(200_000));
+      assert_eq!(check(200_001, 100_000), None);
+      assert_eq!(check(0, 100_000), None);
+   }
+
+   #[test]
+   fn check_overflow() {
+      assert_eq!(check(i32::MAX as usize + 1, usize::MAX), None);
+   }
+}